@@ -1,23 +1,13 @@
-// Copyright The pipewire-rs Contributors.
-// SPDX-License-Identifier: MIT
-
-//! This file is a rustic interpretation of the the [PipeWire Tutorial 4][tut]
-//!
-//! tut: https://docs.pipewire.org/page_tutorial4.html
-
+use crate::audio_backend::*;
 use crate::synth::*;
-use std::sync::mpsc;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 
-use pipewire as pw;
-use pw::prelude::*;
-use pw::{properties, spa};
-use spa::param::ParamType;
-use spa::pod::builder::{SpaPodBuild, SpaPodBuilder};
-
-pub const DEFAULT_RATE: u32 = 44100;
-pub const DEFAULT_CHANNELS: u32 = 2;
-pub const CHAN_SIZE: usize = std::mem::size_of::<i16>();
+pub const DEFAULT_CHANNELS: u16 = 2;
+pub const SAFETY_LIMITER: bool = true;
+// How often the status is sent while playing
+const STATUS_INTERVAL: Duration = Duration::from_millis(30);
 
 pub enum AudioControl {
     Start,
@@ -25,98 +15,66 @@ pub enum AudioControl {
 }
 
 pub struct AudioStatus {
+    // Seconds of audio played
     pub ticks: f64,
 }
 
+fn load_graph(patch_file: &str) -> anyhow::Result<Graph> {
+    let file_contents = std::fs::read_to_string(patch_file)?;
+    // Older patches were saved as json
+    let mut graph: Graph = ron::from_str(&file_contents)
+        .or_else(|_| serde_json::from_str(&file_contents))
+        .map_err(|error| anyhow::anyhow!("Failed to parse {}: {}", patch_file, error))?;
+    Subgraph::reload_linked(&mut graph);
+    graph.sort();
+    Ok(graph)
+}
+
+/// Plays `patch_file` on the backend named by `SYNTH_AUDIO`, PipeWire by
+/// default, until the control channel is closed
 pub fn audio_system(
+    patch_file: &str,
     control: mpsc::Receiver<AudioControl>,
     status: mpsc::Sender<AudioStatus>,
-) -> Result<(), pw::Error> {
-    pw::init();
-    let mainloop = pw::MainLoop::new()?;
-
-    let file_contents = std::fs::read_to_string("synth.patch").unwrap();
-    let graph: Graph = serde_json::from_str(&file_contents).unwrap();
-
-    let stream = pw::stream::Stream::<Graph>::with_user_data(
-        &mainloop,
-        "audio-src",
-        properties! {
-            *pw::keys::MEDIA_TYPE => "Audio",
-            *pw::keys::MEDIA_ROLE => "Music",
-            *pw::keys::MEDIA_CATEGORY => "Playback",
-        },
-        graph,
-    )
-    .process(|stream, graph| match stream.dequeue_buffer() {
-        None => println!("No buffer received"),
-        Some(mut buffer) => {
-            let datas = buffer.datas_mut();
-            let stride = CHAN_SIZE * DEFAULT_CHANNELS as usize;
-            let data = &mut datas[0];
-            let n_frames = if let Some(slice) = data.data() {
-                let n_frames = slice.len() / stride;
-                // println!("writing {}", n_frames);
-                for i in 0..n_frames {
-                    let val = (graph.step(DEFAULT_RATE as f32) * 16767.0) as i16;
-                    // let val = (f64::sin(*acc) * DEFAULT_VOLUME * 16767.0) as i16;
-                    for c in 0..DEFAULT_CHANNELS {
-                        let start = i * stride + (c as usize * CHAN_SIZE);
-                        let end = start + CHAN_SIZE;
-                        let chan = &mut slice[start..end];
-                        chan.copy_from_slice(&i16::to_le_bytes(val));
-                    }
-                }
-                n_frames
-            } else {
-                0
-            };
-            let chunk = data.chunk_mut();
-            *chunk.offset_mut() = 0;
-            *chunk.stride_mut() = stride as _;
-            *chunk.size_mut() = (stride * n_frames) as _;
+) -> anyhow::Result<()> {
+    let shared_graph: SharedGraph = Arc::new(Mutex::new(load_graph(patch_file)?));
+    let output_control = Arc::new(OutputControl::default());
+    output_control
+        .limiter_enabled
+        .store(SAFETY_LIMITER, Ordering::Relaxed);
+
+    let mut backend = backend_from_env("pipewire")?;
+    if let Err(error) = backend.open_capture(None) {
+        println!("No audio capture stream: {:?}", error);
+    }
+    let requested = StreamConfig::from_env(DEFAULT_CHANNELS);
+    set_parallel(std::env::var("SYNTH_PARALLEL").is_ok());
+    let mut sample_rate = None;
+
+    loop {
+        match control.recv_timeout(STATUS_INTERVAL) {
+            Ok(AudioControl::Start) => {
+                let renderer =
+                    GraphRenderer::new(shared_graph.clone(), output_control.clone(), 1.0);
+                let config =
+                    backend.open(device_from_env().as_deref(), &requested, Box::new(renderer))?;
+                sample_rate = Some(config.sample_rate as f64);
+            }
+            Ok(AudioControl::Stop) => {
+                backend.close();
+                sample_rate = None;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
         }
-    })
-    .create()?;
-
-    let mut buffer_vec = Vec::<u8>::with_capacity(1024);
-    let mut builder = SpaPodBuilder::with_buffer(&mut buffer_vec);
-
-    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-    audio_info.set_format(spa::param::audio::AudioFormat::S16_LE);
-    audio_info.set_rate(DEFAULT_RATE);
-    audio_info.set_channels(DEFAULT_CHANNELS);
-    let obj = audio_info.build_pod(&mut builder, ParamType::EnumFormat);
-    let mut params = [obj];
-
-    stream.connect(
-        spa::Direction::Output,
-        None,
-        pw::stream::StreamFlags::AUTOCONNECT
-            | pw::stream::StreamFlags::MAP_BUFFERS
-            | pw::stream::StreamFlags::RT_PROCESS,
-        &mut params,
-    )?;
-
-    let status_timer = mainloop.add_timer(move |_| {
-        let ticks = stream.get_time();
-        status.send(AudioStatus { ticks }).unwrap();
-    });
-    status_timer.update_timer(
-        Some(Duration::from_millis(30)),
-        Some(Duration::from_millis(30)),
-    );
-
-    for audio_control in control {
-        match audio_control {
-            AudioControl::Start => {
-                mainloop.run();
+        if let Some(sample_rate) = sample_rate {
+            let ticks = output_control.frames() as f64 / sample_rate;
+            if status.send(AudioStatus { ticks }).is_err() {
+                break;
             }
-            _ => {}
         }
     }
-
-    unsafe { pw::deinit() };
+    backend.close();
 
     Ok(())
 }
@@ -0,0 +1,353 @@
+use crate::synth::*;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod file;
+#[cfg(feature = "jack")]
+pub mod jack;
+pub mod null;
+pub mod pipewire;
+pub mod sdl;
+pub use self::file::*;
+#[cfg(feature = "jack")]
+pub use self::jack::*;
+pub use self::null::*;
+pub use self::pipewire::*;
+pub use self::sdl::*;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+pub const DEFAULT_BUFFER_SIZE: u32 = 1024;
+pub const SAMPLE_RATES: [u32; 4] = [44_100, 48_000, 88_200, 96_000];
+// MIDI messages a renderer keeps for the next buffer, more are dropped
+pub const MIDI_QUEUE_SIZE: usize = 512;
+
+/// Format of an output stream. Backends are asked for one and answer with
+/// the one they got, which can differ in every field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    // Frames per callback
+    pub buffer_size: u32,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channels: 1,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Fills the buffers of an output stream. `out` holds interleaved frames with
+/// `config.channels` samples each. Called from the audio thread of the
+/// backend.
+pub trait AudioRender: Send + 'static {
+    fn render(&mut self, out: &mut [f32], config: &StreamConfig);
+
+    /// A raw MIDI message for `frame` of the next buffer. Messages come in
+    /// the order of their frames.
+    fn midi(&mut self, _frame: usize, _message: &[u8]) {}
+
+    /// The stream changed to `config`, called before the next `render` where
+    /// the backend allows allocating
+    fn prepare(&mut self, _config: &StreamConfig) {}
+}
+
+pub trait AudioBackend {
+    fn name(&self) -> &'static str;
+
+    /// Names of the output devices, for `open`
+    fn devices(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Starts an output stream on `device`, or the default device, and
+    /// returns the negotiated format. A stream that is already open is
+    /// closed first.
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        requested: &StreamConfig,
+        render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<StreamConfig>;
+
+    fn close(&mut self);
+
+    /// Format of the open stream
+    fn config(&self) -> Option<StreamConfig>;
+
+    /// Starts feeding `audio_input()` from `device`, if the backend can record
+    fn open_capture(&mut self, _device: Option<&str>) -> anyhow::Result<()> {
+        anyhow::bail!("{} has no audio input", self.name())
+    }
+}
+
+/// Creates a backend from a name like `sdl`, `pipewire`, `jack`, `null` or
+/// `file:out.wav`. The part after the colon is the path of the file sink, or
+/// the client name for JACK.
+pub fn backend_from_name(name: &str) -> anyhow::Result<Box<dyn AudioBackend>> {
+    let (kind, arg) = name.split_once(':').unwrap_or((name, ""));
+    Ok(match kind {
+        "sdl" => Box::new(SdlBackend::new()?),
+        "pipewire" => Box::new(PipewireBackend::default()),
+        #[cfg(feature = "jack")]
+        "jack" if !arg.is_empty() => Box::new(JackBackend::new(arg)),
+        #[cfg(feature = "jack")]
+        "jack" => Box::new(JackBackend::new(JACK_CLIENT_NAME)),
+        #[cfg(not(feature = "jack"))]
+        "jack" => anyhow::bail!("built without the jack feature"),
+        "null" => Box::new(NullBackend::default()),
+        "file" if !arg.is_empty() => Box::new(FileBackend::new(arg)),
+        "file" => anyhow::bail!("file backend needs a path, e.g. file:out.wav"),
+        _ => anyhow::bail!("unknown audio backend {}", kind),
+    })
+}
+
+/// The backend named by `SYNTH_AUDIO`, or `default`. The device can be picked
+/// with `SYNTH_AUDIO_DEVICE`.
+pub fn backend_from_env(default: &str) -> anyhow::Result<Box<dyn AudioBackend>> {
+    let name = std::env::var("SYNTH_AUDIO").unwrap_or_else(|_| default.to_string());
+    backend_from_name(&name)
+}
+
+pub fn device_from_env() -> Option<String> {
+    std::env::var("SYNTH_AUDIO_DEVICE").ok()
+}
+
+impl StreamConfig {
+    /// The default format with `channels`, with the rate and buffer size
+    /// taken from `SYNTH_SAMPLE_RATE` and `SYNTH_BUFFER_SIZE` when set
+    pub fn from_env(channels: u16) -> Self {
+        let var = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            sample_rate: var("SYNTH_SAMPLE_RATE", DEFAULT_SAMPLE_RATE),
+            channels,
+            buffer_size: var("SYNTH_BUFFER_SIZE", DEFAULT_BUFFER_SIZE),
+        }
+    }
+}
+
+/// State shared between a `GraphRenderer` and the thread that controls it,
+/// so the controls don't have to wait for the audio thread.
+pub struct OutputControl {
+    pub limiter_enabled: AtomicBool,
+    limiter_gain: AtomicU32,
+    // Frames rendered since the control was created
+    frames: AtomicU64,
+    // Audition render to play instead of the graph output, picked up by the
+    // next buffer
+    preview: Mutex<Option<Vec<f32>>>,
+}
+
+impl Default for OutputControl {
+    fn default() -> Self {
+        Self {
+            limiter_enabled: AtomicBool::new(true),
+            limiter_gain: AtomicU32::new(1.0f32.to_bits()),
+            frames: AtomicU64::new(0),
+            preview: Mutex::new(None),
+        }
+    }
+}
+
+impl OutputControl {
+    pub fn limiter_gain(&self) -> f32 {
+        f32::from_bits(self.limiter_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Plays `samples` instead of the graph until they run out. An empty
+    /// vector stops the preview that is playing.
+    pub fn play_preview(&self, samples: Vec<f32>) {
+        *self.preview.lock().unwrap() = Some(samples);
+    }
+}
+
+/// Renders a shared graph through the safety limiter, to all channels of the
+/// stream
+pub struct GraphRenderer {
+    shared_graph: SharedGraph,
+    control: Arc<OutputControl>,
+    // Applied before the limiter
+    pub gain: f32,
+    limiter: SafetyLimiter,
+    preview: Vec<f32>,
+    preview_pos: usize,
+    // MIDI notes of the next buffer and the frame they are played at. Only
+    // three byte channel messages are of use to `Notes`. The queue never
+    // grows past MIDI_QUEUE_SIZE, so it is not reallocated on the audio
+    // thread.
+    notes: Notes,
+    midi: Vec<(usize, [u8; 3])>,
+    // Mono output of the graph for one buffer, sized by `prepare`
+    block: Vec<f32>,
+}
+
+impl GraphRenderer {
+    pub fn new(shared_graph: SharedGraph, control: Arc<OutputControl>, gain: f32) -> Self {
+        Self {
+            shared_graph,
+            control,
+            gain,
+            limiter: SafetyLimiter::default(),
+            preview: Vec::new(),
+            preview_pos: 0,
+            notes: Notes::default(),
+            midi: Vec::with_capacity(MIDI_QUEUE_SIZE),
+            block: Vec::new(),
+        }
+    }
+}
+
+impl AudioRender for GraphRenderer {
+    fn render(&mut self, out: &mut [f32], config: &StreamConfig) {
+        // The deadline counts from here, waiting for the graph lock can also
+        // cause an underrun
+        let requested = Instant::now();
+        if let Ok(mut preview) = self.control.preview.try_lock() {
+            if let Some(samples) = preview.take() {
+                self.preview = samples;
+                self.preview_pos = 0;
+            }
+        }
+        self.limiter.enabled = self.control.limiter_enabled.load(Ordering::Relaxed);
+
+        let sample_rate = config.sample_rate as f32;
+        let channels = config.channels.max(1) as usize;
+        let mut graph = self.shared_graph.lock().unwrap();
+        let frames = out.len() / channels;
+        // Graphs are prepared by `prepare` and by the editor before they are
+        // swapped in, never here
+        debug_assert!(
+            graph.prepared().is_some_and(|prepared| {
+                prepared.sample_rate == sample_rate && prepared.max_block >= frames
+            }),
+            "graph is not prepared for the stream"
+        );
+        // Notes from the editor start with the buffer
+        while let Some(message) = note_queue().pop() {
+            self.notes.handle_midi(&mut graph, &message);
+        }
+        // Only a backend that skipped `prepare` gets here with a larger buffer
+        if self.block.len() < frames {
+            self.block.resize(frames, 0.0);
+        }
+        // The graph runs up to the frame of each MIDI event, so that the
+        // note starts right there
+        let mut start = 0;
+        for (at, message) in &self.midi {
+            let at = (*at).min(frames);
+            if at > start {
+                graph.process(&mut self.block[start..at], sample_rate);
+                start = at;
+            }
+            self.notes.handle_midi(&mut graph, message);
+        }
+        graph.process(&mut self.block[start..frames], sample_rate);
+        self.midi.clear();
+        for (frame, sample) in out.chunks_mut(channels).zip(&self.block) {
+            let mut output = self.gain * sample;
+            if let Some(preview) = self.preview.get(self.preview_pos) {
+                output = *preview;
+                self.preview_pos += 1;
+            }
+            let output = self.limiter.process(output, sample_rate);
+            frame.fill(output);
+            audio_output().push(output);
+        }
+        graph.end_profile_block(requested.elapsed(), frames, sample_rate);
+
+        self.control
+            .limiter_gain
+            .store(self.limiter.gain().to_bits(), Ordering::Relaxed);
+        self.control
+            .frames
+            .fetch_add(frames as u64, Ordering::Relaxed);
+    }
+
+    fn midi(&mut self, frame: usize, message: &[u8]) {
+        if let [status, data1, data2] = *message {
+            if self.midi.len() < self.midi.capacity() {
+                self.midi.push((frame, [status, data1, data2]));
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &StreamConfig) {
+        let max_block = config.buffer_size as usize;
+        self.block.resize(max_block, 0.0);
+        self.shared_graph
+            .lock()
+            .unwrap()
+            .prepare(config.sample_rate as f32, max_block);
+    }
+}
+
+// Thread that renders buffers without a sound card, for the null and file
+// sinks
+struct BlockThread {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<anyhow::Result<()>>,
+}
+
+impl BlockThread {
+    /// Renders buffers of `config.buffer_size` frames and hands them to
+    /// `sink` until stopped or `max_frames` were rendered. With `realtime`
+    /// the buffers are paced like a sound card would request them.
+    fn spawn(
+        mut render: Box<dyn AudioRender>,
+        config: StreamConfig,
+        realtime: bool,
+        max_frames: Option<u64>,
+        mut sink: impl FnMut(&[f32]) -> anyhow::Result<()> + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            render.prepare(&config);
+            let started = Instant::now();
+            let mut buffer = vec![0.0; config.buffer_size as usize * config.channels as usize];
+            let mut frames = 0;
+            while !thread_stop.load(Ordering::Relaxed) {
+                let block = match max_frames {
+                    Some(max_frames) if frames >= max_frames => break,
+                    Some(max_frames) => (max_frames - frames).min(config.buffer_size as u64),
+                    None => config.buffer_size as u64,
+                };
+                let out = &mut buffer[..block as usize * config.channels as usize];
+                render.render(out, &config);
+                sink(out)?;
+                frames += block;
+                if realtime {
+                    let due = started
+                        + Duration::from_secs_f64(frames as f64 / config.sample_rate as f64);
+                    thread::sleep(due.saturating_duration_since(Instant::now()));
+                }
+            }
+            Ok(())
+        });
+        Self { stop, thread }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    fn join(self) -> anyhow::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("audio thread panicked")))
+    }
+}
@@ -0,0 +1,177 @@
+use super::*;
+use std::path::PathBuf;
+
+/// Writes the output to a 32 bit float wav file instead of a sound card
+pub struct FileBackend {
+    pub path: PathBuf,
+    // Stops after this long, otherwise runs until closed
+    pub length: Option<Duration>,
+    // Paces the buffers like a sound card would, otherwise renders as fast
+    // as possible
+    pub realtime: bool,
+    config: Option<StreamConfig>,
+    thread: Option<BlockThread>,
+}
+
+impl FileBackend {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            length: None,
+            realtime: true,
+            config: None,
+            thread: None,
+        }
+    }
+
+    /// Renders `length` of audio as fast as possible and closes the file
+    pub fn render(
+        path: impl Into<PathBuf>,
+        length: Duration,
+        config: &StreamConfig,
+        render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<()> {
+        let mut backend = Self::new(path);
+        backend.length = Some(length);
+        backend.realtime = false;
+        backend.open(None, config, render)?;
+        backend.finish()
+    }
+
+    /// Waits until `length` is written and closes the file. Without a length
+    /// this stops right away, like `close`.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        if self.length.is_some() {
+            while !thread.is_finished() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        self.config = None;
+        thread.join()
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn devices(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![self.path.to_string_lossy().to_string()])
+    }
+
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        requested: &StreamConfig,
+        render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<StreamConfig> {
+        self.close();
+        if let Some(device) = device {
+            self.path = device.into();
+        }
+        let config = requested.clone();
+        let spec = hound::WavSpec {
+            channels: config.channels,
+            sample_rate: config.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        // The writer finishes the file when the thread drops it
+        let mut writer = hound::WavWriter::create(&self.path, spec)?;
+        let max_frames = self
+            .length
+            .map(|length| (length.as_secs_f64() * config.sample_rate as f64) as u64);
+        self.thread = Some(BlockThread::spawn(
+            render,
+            config.clone(),
+            self.realtime,
+            max_frames,
+            move |samples| {
+                for sample in samples {
+                    writer.write_sample(*sample)?;
+                }
+                Ok(())
+            },
+        ));
+        self.config = Some(config.clone());
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        self.config = None;
+        if let Some(thread) = self.thread.take() {
+            if let Err(error) = thread.join() {
+                println!("Writing {} failed: {:?}", self.path.display(), error);
+            }
+        }
+    }
+
+    fn config(&self) -> Option<StreamConfig> {
+        self.config.clone()
+    }
+}
+
+impl Drop for FileBackend {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine_patch() -> SharedGraph {
+        let mut graph = Graph::new();
+        let osc = graph.add(Box::new(SineOsc {
+            freq: 440.0,
+            ..SineOsc::default()
+        }));
+        let out = graph.output_node.unwrap();
+        graph.connect(
+            Port {
+                node: osc,
+                port: 0,
+                kind: PortKind::Output,
+            },
+            Port {
+                node: out,
+                port: 0,
+                kind: PortKind::Input,
+            },
+        );
+        graph.publish();
+        Arc::new(Mutex::new(graph))
+    }
+
+    #[test]
+    fn writes_the_rendered_length_at_the_stream_format() {
+        let path = std::env::temp_dir().join(format!("file_sink_{}.wav", std::process::id()));
+        let config = StreamConfig {
+            sample_rate: 48_000,
+            channels: 2,
+            buffer_size: 256,
+        };
+        let render = GraphRenderer::new(sine_patch(), Arc::default(), 1.0);
+        FileBackend::render(&path, Duration::from_millis(100), &config, Box::new(render)).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!(spec.sample_rate, 48_000);
+        assert_eq!(spec.channels, 2);
+        assert_eq!(reader.duration(), 4800);
+        let samples: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.len(), 9600);
+        let peak = samples
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.1 && peak <= 1.0);
+        // Both channels get the same signal
+        assert!(samples.chunks(2).all(|frame| frame[0] == frame[1]));
+    }
+}
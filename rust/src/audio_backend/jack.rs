@@ -0,0 +1,224 @@
+use super::*;
+use ::jack as j;
+use itertools::Itertools;
+
+pub const JACK_CLIENT_NAME: &str = "synth";
+const AUDIO_TYPE: &str = "32 bit float mono audio";
+
+/// The graph as a JACK client with an audio output port per channel and a
+/// MIDI input port. The JACK transport drives `transport()` while the client
+/// runs, so tempo synced nodes follow the DAW. For trying it out without a
+/// sound card, `jackd -d dummy` runs a server on a fake device.
+pub struct JackBackend {
+    pub client_name: String,
+    // Registers an audio input port that feeds `audio_input()`
+    capture: bool,
+    config: Option<StreamConfig>,
+    // The active client, its type names the process closure
+    client: Option<Box<dyn std::any::Any>>,
+}
+
+impl JackBackend {
+    pub fn new(client_name: &str) -> Self {
+        Self {
+            client_name: client_name.to_string(),
+            capture: false,
+            config: None,
+            client: None,
+        }
+    }
+}
+
+// Client names of the physical playback ports, the devices the output ports
+// can be connected to
+fn playback_clients(client: &j::Client) -> Vec<String> {
+    client
+        .ports(
+            None,
+            Some(AUDIO_TYPE),
+            j::PortFlags::IS_INPUT | j::PortFlags::IS_PHYSICAL,
+        )
+        .iter()
+        .filter_map(|port| port.split_once(':').map(|(client, _)| client.to_string()))
+        .dedup()
+        .collect()
+}
+
+// Copies the JACK transport to `transport()`
+fn follow_transport(client: &j::Client, sample_rate: f32) {
+    let Ok(state_position) = client.transport().query() else {
+        return;
+    };
+    let rolling = state_position.state == j::TransportState::Rolling;
+    let position = &state_position.pos;
+    match position.bbt() {
+        Some(bbt) => {
+            // Bars and beats count from 1
+            let beats = (bbt.bar - 1) as f64 * bbt.sig_num as f64
+                + (bbt.beat - 1) as f64
+                + bbt.tick as f64 / bbt.ticks_per_beat;
+            transport().update(rolling, bbt.bpm as f32, beats);
+        }
+        // Without a timebase master there is only the frame, the tempo stays
+        // at what it was
+        None => {
+            let bpm = transport().state().map_or(120.0, |state| state.bpm);
+            let seconds = position.frame() as f64 / sample_rate as f64;
+            transport().update(rolling, bpm, seconds * bpm as f64 / 60.0);
+        }
+    }
+}
+
+// The process callback of the client
+struct JackProcess {
+    render: Box<dyn AudioRender>,
+    config: StreamConfig,
+    out_ports: Vec<j::Port<j::AudioOut>>,
+    midi_in: j::Port<j::MidiIn>,
+    capture_in: Option<j::Port<j::AudioIn>>,
+    // Interleaved frames for `render`, sized in `buffer_size`
+    buffer: Vec<f32>,
+}
+
+impl j::ProcessHandler for JackProcess {
+    fn process(&mut self, client: &j::Client, ps: &j::ProcessScope) -> j::Control {
+        let frames = ps.n_frames() as usize;
+        let channels = self.config.channels as usize;
+        follow_transport(client, self.config.sample_rate as f32);
+        if let Some(capture_in) = &self.capture_in {
+            audio_input().push_slice(capture_in.as_slice(ps));
+        }
+        for event in self.midi_in.iter(ps) {
+            self.render.midi(event.time as usize, event.bytes);
+        }
+        // JACK announces every size change, this is only a guard
+        let Some(buffer) = self.buffer.get_mut(..frames * channels) else {
+            return j::Control::Continue;
+        };
+        self.render.render(buffer, &self.config);
+        for (channel, port) in self.out_ports.iter_mut().enumerate() {
+            let out = port.as_mut_slice(ps);
+            for (sample, frame) in out.iter_mut().zip(buffer.chunks(channels)) {
+                *sample = frame[channel];
+            }
+        }
+        j::Control::Continue
+    }
+
+    // Runs on the process thread but may allocate
+    fn buffer_size(&mut self, _client: &j::Client, size: j::Frames) -> j::Control {
+        self.config.buffer_size = size;
+        self.buffer
+            .resize(size as usize * self.config.channels as usize, 0.0);
+        self.render.prepare(&self.config);
+        j::Control::Continue
+    }
+}
+
+impl AudioBackend for JackBackend {
+    fn name(&self) -> &'static str {
+        "jack"
+    }
+
+    fn devices(&self) -> anyhow::Result<Vec<String>> {
+        let (client, _status) = j::Client::new(
+            &format!("{}_scan", self.client_name),
+            j::ClientOptions::NO_START_SERVER,
+        )?;
+        Ok(playback_clients(&client))
+    }
+
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        requested: &StreamConfig,
+        mut render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<StreamConfig> {
+        self.close();
+        let (client, _status) =
+            j::Client::new(&self.client_name, j::ClientOptions::NO_START_SERVER)?;
+        // JACK decides the rate and the buffer size, the channels are ports
+        let config = StreamConfig {
+            sample_rate: client.sample_rate() as u32,
+            channels: requested.channels.max(1),
+            buffer_size: client.buffer_size(),
+        };
+        let out_ports = (1..=config.channels)
+            .map(|channel| client.register_port(&format!("out_{}", channel), j::AudioOut))
+            .collect::<Result<Vec<_>, _>>()?;
+        let out_names = out_ports
+            .iter()
+            .map(|port| port.name())
+            .collect::<Result<Vec<_>, _>>()?;
+        let midi_in = client.register_port("midi_in", j::MidiIn)?;
+        let capture_in = if self.capture {
+            audio_input().set_sample_rate(config.sample_rate);
+            Some(client.register_port("in_1", j::AudioIn)?)
+        } else {
+            None
+        };
+
+        render.prepare(&config);
+        let process = JackProcess {
+            render,
+            config: config.clone(),
+            out_ports,
+            midi_in,
+            capture_in,
+            buffer: vec![0.0; config.buffer_size as usize * config.channels as usize],
+        };
+        let active = client.activate_async((), process)?;
+
+        // Connect to the physical ports of the device, or of the first one
+        let target = match device {
+            Some(device) => Some(device.to_string()),
+            None => playback_clients(active.as_client()).into_iter().next(),
+        };
+        if let Some(target) = target {
+            let pattern = format!("^{}:", target);
+            let targets = active.as_client().ports(
+                Some(pattern.as_str()),
+                Some(AUDIO_TYPE),
+                j::PortFlags::IS_INPUT,
+            );
+            for (out_name, target_port) in out_names.iter().zip(targets.iter().cycle()) {
+                if let Err(error) = active
+                    .as_client()
+                    .connect_ports_by_name(out_name, target_port)
+                {
+                    println!(
+                        "Failed to connect {} to {}: {:?}",
+                        out_name, target_port, error
+                    );
+                }
+            }
+        }
+
+        self.client = Some(Box::new(active));
+        self.config = Some(config.clone());
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        // Dropping the active client deactivates it
+        self.client = None;
+        self.config = None;
+        transport().release();
+    }
+
+    fn config(&self) -> Option<StreamConfig> {
+        self.config.clone()
+    }
+
+    // Takes effect when the output is opened
+    fn open_capture(&mut self, _device: Option<&str>) -> anyhow::Result<()> {
+        self.capture = true;
+        Ok(())
+    }
+}
+
+impl Drop for JackBackend {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,96 @@
+use super::*;
+
+/// Renders and throws the samples away. Keeps the graph running, meters and
+/// scopes included, on machines without a sound card.
+pub struct NullBackend {
+    // Paces the buffers like a sound card would, otherwise renders as fast
+    // as possible
+    pub realtime: bool,
+    config: Option<StreamConfig>,
+    thread: Option<BlockThread>,
+}
+
+impl Default for NullBackend {
+    fn default() -> Self {
+        Self {
+            realtime: true,
+            config: None,
+            thread: None,
+        }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn name(&self) -> &'static str {
+        "null"
+    }
+
+    fn devices(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec!["null".to_string()])
+    }
+
+    fn open(
+        &mut self,
+        _device: Option<&str>,
+        requested: &StreamConfig,
+        render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<StreamConfig> {
+        self.close();
+        let config = requested.clone();
+        self.thread = Some(BlockThread::spawn(
+            render,
+            config.clone(),
+            self.realtime,
+            None,
+            |_| Ok(()),
+        ));
+        self.config = Some(config.clone());
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if let Err(error) = thread.join() {
+                println!("Null audio output failed: {:?}", error);
+            }
+        }
+        self.config = None;
+    }
+
+    fn config(&self) -> Option<StreamConfig> {
+        self.config.clone()
+    }
+}
+
+impl Drop for NullBackend {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_rendering_until_closed() {
+        let control = Arc::new(OutputControl::default());
+        let shared_graph = Arc::new(Mutex::new(Graph::new()));
+        let render = GraphRenderer::new(shared_graph, control.clone(), 1.0);
+        let mut backend = NullBackend::default();
+        backend.realtime = false;
+        let config = backend
+            .open(None, &StreamConfig::default(), Box::new(render))
+            .unwrap();
+        assert_eq!(config, StreamConfig::default());
+        assert_eq!(backend.config(), Some(config));
+        while control.frames() < 10 * DEFAULT_BUFFER_SIZE as u64 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        backend.close();
+        assert!(backend.config().is_none());
+        let frames = control.frames();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(control.frames(), frames);
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright The pipewire-rs Contributors.
+// SPDX-License-Identifier: MIT
+
+//! This file is a rustic interpretation of the the [PipeWire Tutorial 4][tut]
+//!
+//! tut: https://docs.pipewire.org/page_tutorial4.html
+
+use super::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use ::pipewire as pw;
+use pw::prelude::*;
+use pw::{properties, spa};
+use spa::param::ParamType;
+use spa::pod::builder::{SpaPodBuild, SpaPodBuilder};
+
+const CHAN_SIZE: usize = std::mem::size_of::<f32>();
+// How often the main loop checks whether it should stop
+const STOP_POLL: Duration = Duration::from_millis(30);
+// Largest buffer PipeWire asks for, its default clock.max-quantum. The
+// requested size is only a latency hint.
+const MAX_QUANTUM: u32 = 8192;
+
+struct PlaybackState {
+    render: Box<dyn AudioRender>,
+    config: StreamConfig,
+    // Sized for MAX_QUANTUM frames when the stream opens, the callback only
+    // slices it
+    buffer: Vec<f32>,
+}
+
+/// Output, and capture into `audio_input()`, through PipeWire. The streams
+/// run on a thread of their own with its own main loop. PipeWire converts
+/// to the format of the device, so the requested format is always granted.
+#[derive(Default)]
+pub struct PipewireBackend {
+    config: Option<StreamConfig>,
+    capture_device: Option<Option<String>>,
+    stop: Option<Arc<AtomicBool>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+fn stream_properties(
+    category: &str,
+    device: Option<&str>,
+    config: &StreamConfig,
+) -> pw::Properties {
+    let mut properties = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::MEDIA_CATEGORY => category,
+        "node.latency" => format!("{}/{}", config.buffer_size, config.sample_rate),
+    };
+    if let Some(device) = device {
+        properties.insert("target.object", device);
+    }
+    properties
+}
+
+fn playback_stream(
+    mainloop: &pw::MainLoop,
+    device: Option<&str>,
+    playback: PlaybackState,
+) -> Result<pw::stream::Stream<PlaybackState>, pw::Error> {
+    let config = playback.config.clone();
+    let stream = pw::stream::Stream::<PlaybackState>::with_user_data(
+        mainloop,
+        "audio-src",
+        stream_properties("Playback", device, &config),
+        playback,
+    )
+    .process(|stream, playback| match stream.dequeue_buffer() {
+        None => println!("No buffer received"),
+        Some(mut buffer) => {
+            let datas = buffer.datas_mut();
+            let channels = playback.config.channels as usize;
+            let stride = CHAN_SIZE * channels;
+            let data = &mut datas[0];
+            let n_frames = if let Some(slice) = data.data() {
+                let n_frames = (slice.len() / stride).min(playback.buffer.len() / channels);
+                let out = &mut playback.buffer[..n_frames * channels];
+                playback.render.render(out, &playback.config);
+                for (chan, sample) in slice.chunks_exact_mut(CHAN_SIZE).zip(out.iter()) {
+                    chan.copy_from_slice(&sample.to_le_bytes());
+                }
+                n_frames
+            } else {
+                0
+            };
+            let chunk = data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = stride as _;
+            *chunk.size_mut() = (stride * n_frames) as _;
+        }
+    })
+    .create()?;
+
+    let mut buffer_vec = Vec::<u8>::with_capacity(1024);
+    let mut builder = SpaPodBuilder::with_buffer(&mut buffer_vec);
+    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
+    audio_info.set_rate(config.sample_rate);
+    audio_info.set_channels(config.channels as u32);
+    let obj = audio_info.build_pod(&mut builder, ParamType::EnumFormat);
+    let mut params = [obj];
+
+    stream.connect(
+        spa::Direction::Output,
+        None,
+        pw::stream::StreamFlags::AUTOCONNECT
+            | pw::stream::StreamFlags::MAP_BUFFERS
+            | pw::stream::StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+    Ok(stream)
+}
+
+/// Capture stream feeding `audio_input()`, which is read by `AudioIn` nodes.
+/// Opened at the rate of the output, with a single channel.
+fn capture_stream(
+    mainloop: &pw::MainLoop,
+    device: Option<&str>,
+    output: &StreamConfig,
+) -> Result<pw::stream::Stream<()>, pw::Error> {
+    let config = StreamConfig {
+        channels: 1,
+        ..output.clone()
+    };
+    audio_input().set_sample_rate(config.sample_rate);
+    let stream = pw::stream::Stream::<()>::with_user_data(
+        mainloop,
+        "audio-in",
+        stream_properties("Capture", device, &config),
+        (),
+    )
+    .process(|stream, _| match stream.dequeue_buffer() {
+        None => println!("No capture buffer received"),
+        Some(mut buffer) => {
+            let datas = buffer.datas_mut();
+            let data = &mut datas[0];
+            let size = data.chunk().size() as usize;
+            if let Some(slice) = data.data() {
+                let input = audio_input();
+                for frame in slice[..size.min(slice.len())].chunks_exact(CHAN_SIZE) {
+                    input.push(f32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]));
+                }
+            }
+        }
+    })
+    .create()?;
+
+    let mut buffer_vec = Vec::<u8>::with_capacity(1024);
+    let mut builder = SpaPodBuilder::with_buffer(&mut buffer_vec);
+    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
+    audio_info.set_rate(config.sample_rate);
+    audio_info.set_channels(config.channels as u32);
+    let obj = audio_info.build_pod(&mut builder, ParamType::EnumFormat);
+    let mut params = [obj];
+
+    stream.connect(
+        spa::Direction::Input,
+        None,
+        pw::stream::StreamFlags::AUTOCONNECT
+            | pw::stream::StreamFlags::MAP_BUFFERS
+            | pw::stream::StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+    Ok(stream)
+}
+
+impl PipewireBackend {
+    fn stop_thread(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl AudioBackend for PipewireBackend {
+    fn name(&self) -> &'static str {
+        "pipewire"
+    }
+
+    // Node names of the sinks, found with a round trip to the server
+    fn devices(&self) -> anyhow::Result<Vec<String>> {
+        pw::init();
+        let mainloop = pw::MainLoop::new()?;
+        let context = pw::Context::new(&mainloop)?;
+        let core = context.connect(None)?;
+        let registry = core.get_registry()?;
+
+        let sinks = Rc::new(RefCell::new(Vec::new()));
+        let registry_sinks = sinks.clone();
+        let _registry_listener = registry
+            .add_listener_local()
+            .global(move |global| {
+                let Some(props) = &global.props else {
+                    return;
+                };
+                if props.get("media.class") == Some("Audio/Sink") {
+                    if let Some(name) = props.get("node.name") {
+                        registry_sinks.borrow_mut().push(name.to_string());
+                    }
+                }
+            })
+            .register();
+
+        let done = Rc::new(Cell::new(false));
+        let core_done = done.clone();
+        let core_mainloop = mainloop.clone();
+        let pending = core.sync(0)?;
+        let _core_listener = core
+            .add_listener_local()
+            .done(move |id, seq| {
+                if id == pw::PW_ID_CORE && seq == pending {
+                    core_done.set(true);
+                    core_mainloop.quit();
+                }
+            })
+            .register();
+        while !done.get() {
+            mainloop.run();
+        }
+        let sinks = sinks.borrow().clone();
+        Ok(sinks)
+    }
+
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        requested: &StreamConfig,
+        mut render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<StreamConfig> {
+        self.close();
+        let config = requested.clone();
+        let max_frames = MAX_QUANTUM.max(config.buffer_size);
+        render.prepare(&StreamConfig {
+            buffer_size: max_frames,
+            ..config.clone()
+        });
+        let device = device.map(str::to_string);
+        let capture_device = self.capture_device.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let (started_send, started) = mpsc::channel();
+        let thread_config = config.clone();
+        let capture_config = config.clone();
+        let thread = thread::spawn(move || {
+            pw::init();
+            let run = || -> Result<(), pw::Error> {
+                let mainloop = pw::MainLoop::new()?;
+                let buffer = vec![0.0; max_frames as usize * thread_config.channels as usize];
+                let playback = PlaybackState {
+                    render,
+                    config: thread_config,
+                    buffer,
+                };
+                let _stream = playback_stream(&mainloop, device.as_deref(), playback)?;
+                let _capture = capture_device.and_then(|capture_device| {
+                    capture_stream(&mainloop, capture_device.as_deref(), &capture_config)
+                        .map_err(|error| println!("No audio capture stream: {:?}", error))
+                        .ok()
+                });
+
+                let stop_mainloop = mainloop.clone();
+                let stop_timer = mainloop.add_timer(move |_| {
+                    if thread_stop.load(Ordering::Relaxed) {
+                        stop_mainloop.quit();
+                    }
+                });
+                stop_timer.update_timer(Some(STOP_POLL), Some(STOP_POLL));
+
+                started_send.send(Ok(())).ok();
+                mainloop.run();
+                Ok(())
+            };
+            if let Err(error) = run() {
+                started_send.send(Err(error)).ok();
+            }
+        });
+        started.recv()??;
+        self.stop = Some(stop);
+        self.thread = Some(thread);
+        self.config = Some(config.clone());
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        self.stop_thread();
+        self.config = None;
+    }
+
+    fn config(&self) -> Option<StreamConfig> {
+        self.config.clone()
+    }
+
+    // The capture stream runs with the output, on the same main loop
+    fn open_capture(&mut self, device: Option<&str>) -> anyhow::Result<()> {
+        self.capture_device = Some(device.map(str::to_string));
+        Ok(())
+    }
+}
+
+impl Drop for PipewireBackend {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
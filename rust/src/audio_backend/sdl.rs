@@ -0,0 +1,133 @@
+use super::*;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpec, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+pub struct SdlCallback {
+    render: Box<dyn AudioRender>,
+    config: StreamConfig,
+}
+
+impl AudioCallback for SdlCallback {
+    type Channel = f32;
+
+    fn callback(&mut self, sdl_out: &mut [f32]) {
+        self.render.render(sdl_out, &self.config);
+    }
+}
+
+fn stream_config(spec: &AudioSpec) -> StreamConfig {
+    StreamConfig {
+        sample_rate: spec.freq as u32,
+        channels: spec.channels as u16,
+        buffer_size: spec.samples as u32,
+    }
+}
+
+pub struct InCallbacker;
+
+impl AudioCallback for InCallbacker {
+    type Channel = f32;
+
+    fn callback(&mut self, sdl_in: &mut [f32]) {
+        audio_input().push_slice(sdl_in);
+    }
+}
+
+/// Output and capture through SDL2. Has to stay on the thread that created
+/// it.
+pub struct SdlBackend {
+    audio_subsystem: AudioSubsystem,
+    device: Option<AudioDevice<SdlCallback>>,
+    capture: Option<AudioDevice<InCallbacker>>,
+}
+
+impl SdlBackend {
+    pub fn new() -> anyhow::Result<Self> {
+        let sdl_context = sdl2::init().map_err(anyhow::Error::msg)?;
+        let audio_subsystem = sdl_context.audio().map_err(anyhow::Error::msg)?;
+        Ok(Self {
+            audio_subsystem,
+            device: None,
+            capture: None,
+        })
+    }
+}
+
+impl AudioBackend for SdlBackend {
+    fn name(&self) -> &'static str {
+        "sdl"
+    }
+
+    fn devices(&self) -> anyhow::Result<Vec<String>> {
+        let n_devices = self
+            .audio_subsystem
+            .num_audio_playback_devices()
+            .unwrap_or(0);
+        (0..n_devices)
+            .map(|idx| {
+                self.audio_subsystem
+                    .audio_playback_device_name(idx)
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect()
+    }
+
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        requested: &StreamConfig,
+        mut render: Box<dyn AudioRender>,
+    ) -> anyhow::Result<StreamConfig> {
+        self.close();
+        let desired_spec = AudioSpecDesired {
+            freq: Some(requested.sample_rate as i32),
+            channels: Some(requested.channels as u8),
+            samples: Some(requested.buffer_size as u16),
+        };
+        let device = self
+            .audio_subsystem
+            .open_playback(device, &desired_spec, |spec| {
+                let config = stream_config(&spec);
+                render.prepare(&config);
+                SdlCallback { render, config }
+            })
+            .map_err(anyhow::Error::msg)?;
+        let config = stream_config(device.spec());
+        device.resume();
+        self.device = Some(device);
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        self.device = None;
+    }
+
+    fn config(&self) -> Option<StreamConfig> {
+        Some(stream_config(self.device.as_ref()?.spec()))
+    }
+
+    fn open_capture(&mut self, device: Option<&str>) -> anyhow::Result<()> {
+        self.capture = None;
+        let desired_spec = AudioSpecDesired {
+            // Captured at the output rate when it is known, AudioIn resamples
+            // whatever rate the device gives
+            freq: Some(
+                self.device
+                    .as_ref()
+                    .map_or(DEFAULT_SAMPLE_RATE as i32, |device| device.spec().freq),
+            ),
+            channels: Some(1),
+            samples: Some(1000),
+        };
+        let capture = self
+            .audio_subsystem
+            .open_capture(device, &desired_spec, |spec| {
+                audio_input().set_sample_rate(spec.freq as u32);
+                InCallbacker
+            })
+            .map_err(anyhow::Error::msg)?;
+        capture.resume();
+        self.capture = Some(capture);
+        Ok(())
+    }
+}
@@ -0,0 +1,483 @@
+use crate::synth::*;
+use std::collections::HashMap;
+
+const HISTORY_LIMIT: usize = 200;
+
+/// A reversible change to a graph. Applying an edit returns the edit that
+/// undoes it.
+pub enum Edit {
+    // `node` is the key the node had before it was removed, if any
+    AddNode {
+        node: Option<NodeKey>,
+        snapshot: Box<dyn Node>,
+        position: Option<[f32; 2]>,
+        edges: Vec<Edge>,
+        is_output: bool,
+    },
+    RemoveNode {
+        node: NodeKey,
+    },
+    Connect {
+        edge: Edge,
+    },
+    Disconnect {
+        edge: Edge,
+    },
+    SetInput {
+        node: NodeKey,
+        idx: usize,
+        value: f32,
+    },
+    SetSequence {
+        node: NodeKey,
+        sequence: Vec<Note>,
+    },
+    // Replaces the whole graph, used for patch loads
+    SwapGraph {
+        graph: Box<Graph>,
+        key_map: HashMap<NodeKey, NodeKey>,
+    },
+    Group(Vec<Edit>),
+}
+
+impl Edit {
+    // Edits that may be merged into the previous step, e.g. during a knob drag
+    fn same_target(&self, other: &Edit) -> bool {
+        match (self, other) {
+            (
+                Edit::SetInput { node, idx, .. },
+                Edit::SetInput {
+                    node: other_node,
+                    idx: other_idx,
+                    ..
+                },
+            ) => node == other_node && idx == other_idx,
+            (
+                Edit::SetSequence { node, .. },
+                Edit::SetSequence {
+                    node: other_node, ..
+                },
+            ) => node == other_node,
+            _ => false,
+        }
+    }
+}
+
+pub struct HistoryEntry {
+    pub label: String,
+    edit: Edit,
+}
+
+/// Undo and redo stacks of graph edits.
+///
+/// Nodes that are removed and added back get new keys, so edits recorded
+/// before that refer to stale keys. `key_map` maps those to the live keys.
+#[derive(Default)]
+pub struct History {
+    undo: Vec<HistoryEntry>,
+    redo: Vec<HistoryEntry>,
+    key_map: HashMap<NodeKey, NodeKey>,
+}
+
+impl History {
+    pub fn undo_entries(&self) -> &Vec<HistoryEntry> {
+        &self.undo
+    }
+
+    pub fn redo_entries(&self) -> &Vec<HistoryEntry> {
+        &self.redo
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.key_map.clear();
+    }
+
+    fn resolve(&self, node_key: NodeKey) -> NodeKey {
+        let mut node_key = node_key;
+        while let Some(mapped) = self.key_map.get(&node_key) {
+            node_key = *mapped;
+        }
+        node_key
+    }
+
+    fn resolve_edge(&self, edge: &Edge) -> Edge {
+        let mut edge = edge.clone();
+        edge.from.node = self.resolve(edge.from.node);
+        edge.to.node = self.resolve(edge.to.node);
+        edge
+    }
+
+    fn apply_edit(&mut self, graph: &mut Graph, edit: Edit) -> Edit {
+        match edit {
+            Edit::AddNode {
+                node,
+                snapshot,
+                position,
+                edges,
+                is_output,
+            } => {
+                let node_key = graph.add(snapshot);
+                if let Some(old_key) = node {
+                    let old_key = self.resolve(old_key);
+                    self.key_map.insert(old_key, node_key);
+                }
+                if let Some(position) = position {
+                    graph.positions.insert(node_key, position);
+                }
+                for edge in edges {
+                    let edge = self.resolve_edge(&edge);
+                    if graph.has_node(edge.from.node) && graph.has_node(edge.to.node) {
+                        graph.connect(edge.from, edge.to);
+                    }
+                }
+                if is_output {
+                    graph.output_node = Some(node_key);
+                }
+                Edit::RemoveNode { node: node_key }
+            }
+            Edit::RemoveNode { node } => {
+                let node_key = self.resolve(node);
+                let snapshot = graph.get_node(node_key).copy();
+                let position = graph.positions.get(&node_key).copied();
+                let edges = graph.node_inputs()[&node_key]
+                    .iter()
+                    .chain(graph.node_outputs()[&node_key].iter())
+                    .cloned()
+                    .collect();
+                let is_output = graph.output_node == Some(node_key);
+                graph.remove(node_key);
+                if is_output {
+                    graph.output_node = None;
+                }
+                Edit::AddNode {
+                    node: Some(node_key),
+                    snapshot,
+                    position,
+                    edges,
+                    is_output,
+                }
+            }
+            Edit::Connect { edge } => {
+                let edge = self.resolve_edge(&edge);
+                // Connecting replaces whatever was connected to the input
+                let replaced = graph.get_edge(edge.to.clone());
+                graph.connect(edge.from.clone(), edge.to.clone());
+                match replaced {
+                    Some(replaced) if replaced != edge => Edit::Group(vec![
+                        Edit::Disconnect { edge },
+                        Edit::Connect { edge: replaced },
+                    ]),
+                    _ => Edit::Disconnect { edge },
+                }
+            }
+            Edit::Disconnect { edge } => {
+                let edge = self.resolve_edge(&edge);
+                graph.disconnect_edge(edge.clone());
+                Edit::Connect { edge }
+            }
+            Edit::SetInput { node, idx, value } => {
+                let node_key = self.resolve(node);
+                let mut node = graph.get_node_mut(node_key);
+                let old_value = node.get_input(idx);
+                node.set(idx, value);
+                Edit::SetInput {
+                    node: node_key,
+                    idx,
+                    value: old_value,
+                }
+            }
+            Edit::SetSequence { node, mut sequence } => {
+                let node_key = self.resolve(node);
+                let mut node = graph.get_node_mut(node_key);
+                if let Some(sequencer) = node.as_any_mut().downcast_mut::<Sequencer>() {
+                    std::mem::swap(&mut sequencer.sequence, &mut sequence);
+                }
+                Edit::SetSequence {
+                    node: node_key,
+                    sequence,
+                }
+            }
+            Edit::SwapGraph {
+                graph: mut other_graph,
+                mut key_map,
+            } => {
+                // Loaded, reloaded or undone to, the graph is prepared here
+                // for the stream the running one is prepared for, as the
+                // audio thread doesn't prepare
+                if let Some(prepared) = graph.prepared() {
+                    if other_graph.prepared() != Some(prepared) {
+                        other_graph.prepare(prepared.sample_rate, prepared.max_block);
+                    }
+                }
+                std::mem::swap(graph, &mut *other_graph);
+                std::mem::swap(&mut self.key_map, &mut key_map);
+                Edit::SwapGraph {
+                    graph: other_graph,
+                    key_map,
+                }
+            }
+            Edit::Group(edits) => {
+                let mut inverse: Vec<Edit> = edits
+                    .into_iter()
+                    .map(|edit| self.apply_edit(graph, edit))
+                    .collect();
+                inverse.reverse();
+                Edit::Group(inverse)
+            }
+        }
+    }
+
+    /// Records an edit that has already been made, `inverse` undoes it. With
+    /// `merge` set the edit is folded into the last step if that changed the
+    /// same thing.
+    pub fn push(&mut self, label: &str, inverse: Edit, merge: bool) {
+        self.redo.clear();
+        if merge
+            && self
+                .undo
+                .last()
+                .is_some_and(|entry| entry.edit.same_target(&inverse))
+        {
+            return;
+        }
+        self.undo.push(HistoryEntry {
+            label: label.to_string(),
+            edit: inverse,
+        });
+        if self.undo.len() > HISTORY_LIMIT {
+            self.undo.remove(0);
+        }
+    }
+
+    /// Applies `edit` to the graph and records it.
+    pub fn apply(&mut self, graph: &mut Graph, label: &str, edit: Edit, merge: bool) {
+        let inverse = self.apply_edit(graph, edit);
+        self.push(label, inverse, merge);
+    }
+
+    pub fn add_node(&mut self, graph: &mut Graph, node: Box<dyn Node>, position: Option<[f32; 2]>) {
+        let label = format!("add {}", node.type_name());
+        self.apply(
+            graph,
+            &label,
+            Edit::AddNode {
+                node: None,
+                snapshot: node,
+                position,
+                edges: vec![],
+                is_output: false,
+            },
+            false,
+        );
+    }
+
+    pub fn remove_nodes(&mut self, graph: &mut Graph, node_keys: &[NodeKey]) {
+        let node_keys: Vec<NodeKey> = node_keys
+            .iter()
+            .copied()
+            .filter(|node_key| graph.has_node(*node_key))
+            .collect();
+        if node_keys.is_empty() {
+            return;
+        }
+        let label = match &node_keys[..] {
+            [node_key] => format!("remove {}", graph.get_node(*node_key).type_name()),
+            _ => format!("remove {} nodes", node_keys.len()),
+        };
+        let edits = node_keys
+            .into_iter()
+            .map(|node| Edit::RemoveNode { node })
+            .collect();
+        self.apply(graph, &label, Edit::Group(edits), false);
+    }
+
+    pub fn connect(&mut self, graph: &mut Graph, from: Port, to: Port) {
+        let edge = Edge { from, to };
+        let label = format!("connect {}", graph.format_edge_pair(&edge));
+        self.apply(graph, &label, Edit::Connect { edge }, false);
+    }
+
+    pub fn disconnect(&mut self, graph: &mut Graph, edge: Edge) {
+        let label = format!("disconnect {}", graph.format_edge_pair(&edge));
+        self.apply(graph, &label, Edit::Disconnect { edge }, false);
+    }
+
+    pub fn set_input(
+        &mut self,
+        graph: &mut Graph,
+        node: NodeKey,
+        idx: usize,
+        value: f32,
+        merge: bool,
+    ) {
+        let label = {
+            let node = graph.get_node(node);
+            format!("set {} {}", node.type_name(), node.inputs()[idx].name)
+        };
+        self.apply(graph, &label, Edit::SetInput { node, idx, value }, merge);
+    }
+
+    pub fn paste(&mut self, graph: &mut Graph, label: &str, other: &Graph) -> Vec<NodeKey> {
+        let node_keys = graph.paste(other);
+        let inverse = Edit::Group(
+            node_keys
+                .iter()
+                .map(|node| Edit::RemoveNode { node: *node })
+                .collect(),
+        );
+        self.push(label, inverse, false);
+        node_keys
+    }
+
+    /// Moves the nodes into a new `Subgraph` node and reconnects the edges
+    /// that crossed the group boundary to its ports.
+    pub fn group_into_subgraph(
+        &mut self,
+        graph: &mut Graph,
+        node_keys: &[NodeKey],
+    ) -> Option<NodeKey> {
+        if node_keys.is_empty() {
+            return None;
+        }
+        let (subgraph, crossing) = Subgraph::from_nodes(graph, node_keys);
+        let position = node_keys
+            .iter()
+            .filter_map(|node_key| graph.positions.get(node_key))
+            .fold(None, |min: Option<[f32; 2]>, pos| match min {
+                Some(min) => Some([min[0].min(pos[0]), min[1].min(pos[1])]),
+                None => Some(*pos),
+            });
+
+        let remove = self.apply_edit(
+            graph,
+            Edit::Group(
+                node_keys
+                    .iter()
+                    .map(|node| Edit::RemoveNode { node: *node })
+                    .collect(),
+            ),
+        );
+        let node_key = graph.add(Box::new(subgraph));
+        if let Some(position) = position {
+            graph.positions.insert(node_key, position);
+        }
+        for (from, port) in crossing.inputs {
+            let to = Port {
+                node: node_key,
+                port,
+                kind: PortKind::Input,
+            };
+            graph.connect(from, to);
+        }
+        for (port, to) in crossing.outputs {
+            let from = Port {
+                node: node_key,
+                port,
+                kind: PortKind::Output,
+            };
+            graph.connect(from, to);
+        }
+        self.push(
+            &format!("group {} nodes", node_keys.len()),
+            Edit::Group(vec![Edit::RemoveNode { node: node_key }, remove]),
+            false,
+        );
+        Some(node_key)
+    }
+
+    pub fn load_patch(&mut self, graph: &mut Graph, name: &str, loaded_graph: Graph) {
+        self.apply(
+            graph,
+            &format!("load {}", name),
+            Edit::SwapGraph {
+                graph: Box::new(loaded_graph),
+                key_map: HashMap::new(),
+            },
+            false,
+        );
+    }
+
+    // The patch file was changed by something else, undo goes back to the
+    // graph from before
+    pub fn reload_patch(&mut self, graph: &mut Graph, name: &str, reloaded_graph: Graph) {
+        self.apply(
+            graph,
+            &format!("reload {}", name),
+            Edit::SwapGraph {
+                graph: Box::new(reloaded_graph),
+                key_map: HashMap::new(),
+            },
+            false,
+        );
+    }
+
+    pub fn undo(&mut self, graph: &mut Graph) -> bool {
+        let Some(entry) = self.undo.pop() else {
+            return false;
+        };
+        let edit = self.apply_edit(graph, entry.edit);
+        self.redo.push(HistoryEntry {
+            label: entry.label,
+            edit,
+        });
+        true
+    }
+
+    pub fn redo(&mut self, graph: &mut Graph) -> bool {
+        let Some(entry) = self.redo.pop() else {
+            return false;
+        };
+        let edit = self.apply_edit(graph, entry.edit);
+        self.undo.push(HistoryEntry {
+            label: entry.label,
+            edit,
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_of_a_reload_goes_back_to_the_previous_graph() {
+        let mut graph = Graph::new();
+        let bias = graph.add(Box::new(Bias::default()));
+        graph.publish();
+        let mut reloaded = graph.copy();
+        reloaded.remove(bias);
+        reloaded.publish();
+
+        let mut history = History::default();
+        history.reload_patch(&mut graph, "patch", reloaded);
+        assert!(!graph.has_node(bias));
+        assert_eq!(history.undo_entries()[0].label, "reload patch");
+        assert!(history.undo(&mut graph));
+        assert!(graph.has_node(bias));
+        assert!(history.redo(&mut graph));
+        assert!(!graph.has_node(bias));
+    }
+
+    #[test]
+    fn swapped_in_graphs_are_prepared_like_the_running_one() {
+        let mut graph = Graph::new();
+        graph.prepare(48000.0, 512);
+        let mut history = History::default();
+        history.load_patch(&mut graph, "patch", Graph::new());
+        assert_eq!(
+            graph.prepared(),
+            Some(PrepareInfo {
+                sample_rate: 48000.0,
+                max_block: 512,
+            })
+        );
+
+        // The stream was reopened at another rate before the undo
+        graph.prepare(96000.0, 256);
+        assert!(history.undo(&mut graph));
+        assert_eq!(graph.prepared().unwrap().sample_rate, 96000.0);
+    }
+}
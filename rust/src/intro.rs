@@ -4,6 +4,7 @@ use std::sync::mpsc;
 use std::thread;
 // use std::time::Duration;
 mod audio;
+mod audio_backend;
 mod gl;
 mod synth;
 use crate::synth::*;
@@ -14,8 +15,11 @@ pub fn main() {
 
     let (video_control, video_control_recv) = mpsc::channel();
 
+    let patch_file = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "synth.patch".to_string());
     let _audio_thread_handle = thread::spawn(move || {
-        audio::audio_system(audio_control_recv, audio_status_send).unwrap();
+        audio::audio_system(&patch_file, audio_control_recv, audio_status_send).unwrap();
     });
 
     let _video_thread_handle = thread::spawn(move || {
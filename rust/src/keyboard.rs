@@ -0,0 +1,280 @@
+use crate::synth::*;
+use eframe::egui;
+use std::collections::HashMap;
+
+const PIANO_OCTAVES: i32 = 2;
+const WHITE_KEY_SIZE: egui::Vec2 = egui::vec2(18.0, 64.0);
+const BLACK_KEY_SIZE: egui::Vec2 = egui::vec2(11.0, 40.0);
+
+// Semitones of the white keys in an octave, and of the black keys with the
+// white key they sit after
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const BLACK_KEYS: [(u8, usize); 5] = [(1, 0), (3, 1), (6, 3), (8, 4), (10, 5)];
+
+// Tracker layout: the bottom row plays the current octave with the black keys
+// on the row above, the top row plays the octave above
+const QWERTY_KEYS: [(egui::Key, u8); 29] = [
+    (egui::Key::Z, 0),
+    (egui::Key::S, 1),
+    (egui::Key::X, 2),
+    (egui::Key::D, 3),
+    (egui::Key::C, 4),
+    (egui::Key::V, 5),
+    (egui::Key::G, 6),
+    (egui::Key::B, 7),
+    (egui::Key::H, 8),
+    (egui::Key::N, 9),
+    (egui::Key::J, 10),
+    (egui::Key::M, 11),
+    (egui::Key::Q, 12),
+    (egui::Key::Num2, 13),
+    (egui::Key::W, 14),
+    (egui::Key::Num3, 15),
+    (egui::Key::E, 16),
+    (egui::Key::R, 17),
+    (egui::Key::Num5, 18),
+    (egui::Key::T, 19),
+    (egui::Key::Num6, 20),
+    (egui::Key::Y, 21),
+    (egui::Key::Num7, 22),
+    (egui::Key::U, 23),
+    (egui::Key::I, 24),
+    (egui::Key::Num9, 25),
+    (egui::Key::O, 26),
+    (egui::Key::Num0, 27),
+    (egui::Key::P, 28),
+];
+
+/// Notes played from the on-screen piano and the computer keyboard. They are
+/// sent to the audio callback as MIDI, which gives them voices together with
+/// the notes from MIDI input.
+pub struct Keyboard {
+    // MIDI note of the lowest key on the piano and of Z on the keyboard
+    pub base_note: u8,
+    pub velocity: f32,
+    // Play notes with the computer keyboard. The editor shortcuts on the
+    // mapped letters take Shift, so both work while this is on.
+    pub qwerty: bool,
+    // Notes this keyboard holds down
+    held: Vec<u8>,
+    mouse_note: Option<u8>,
+    qwerty_notes: HashMap<egui::Key, u8>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self {
+            base_note: 48,
+            velocity: 0.8,
+            qwerty: false,
+            held: Vec::new(),
+            mouse_note: None,
+            qwerty_notes: HashMap::new(),
+        }
+    }
+}
+
+impl Keyboard {
+    pub fn note_on(&mut self, note: u8) {
+        if self.held.contains(&note) {
+            return;
+        }
+        // A velocity of 0 would be a note off
+        let velocity = (self.velocity * 127.0).round().clamp(1.0, 127.0) as u8;
+        if note_queue().push([0x90, note, velocity]) {
+            self.held.push(note);
+        }
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        if note_queue().push([0x80, note, 0]) {
+            self.held.retain(|held| *held != note);
+        }
+    }
+
+    /// Plays notes from the computer keyboard while `qwerty` is on. Must run
+    /// before the editor handles its shortcuts.
+    pub fn handle_qwerty(&mut self, ctx: &egui::Context) {
+        if !self.qwerty || ctx.wants_keyboard_input() {
+            // Keys released while typing or after turning this off would
+            // never be seen going up
+            self.release_qwerty();
+            return;
+        }
+        let (keys_down, octave_down, octave_up) = ctx.input_mut(|i| {
+            let octave_down = i.consume_key(egui::Modifiers::NONE, egui::Key::Minus);
+            let octave_up = i.consume_key(egui::Modifiers::NONE, egui::Key::PlusEquals);
+            i.events.retain(|event| {
+                !matches!(event, egui::Event::Key { key, modifiers, .. }
+                    if modifiers.is_none() && QWERTY_KEYS.iter().any(|(k, _)| k == key))
+            });
+            (i.keys_down.clone(), octave_down, octave_up)
+        });
+        if octave_down {
+            self.shift_octave(-1);
+        }
+        if octave_up {
+            self.shift_octave(1);
+        }
+        for (key, offset) in QWERTY_KEYS {
+            let down = keys_down.contains(&key);
+            match self.qwerty_notes.get(&key).copied() {
+                Some(note) if !down => {
+                    self.qwerty_notes.remove(&key);
+                    self.note_off(note);
+                }
+                None if down => {
+                    let note = self.base_note.saturating_add(offset).min(127);
+                    self.qwerty_notes.insert(key, note);
+                    self.note_on(note);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn release_qwerty(&mut self) {
+        for (_, note) in std::mem::take(&mut self.qwerty_notes) {
+            self.note_off(note);
+        }
+    }
+
+    pub fn shift_octave(&mut self, octaves: i32) {
+        self.base_note = (self.base_note as i32 + 12 * octaves).clamp(0, 108) as u8;
+    }
+
+    /// Releases all notes, e.g. when the patch changes
+    pub fn all_notes_off(&mut self) {
+        self.qwerty_notes.clear();
+        self.mouse_note = None;
+        self.held.clear();
+        note_queue().push([0xB0, 123, 0]);
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("-").clicked() {
+                self.shift_octave(-1);
+            }
+            ui.label(format!("octave {}", self.base_note as i32 / 12 - 1));
+            if ui.button("+").clicked() {
+                self.shift_octave(1);
+            }
+            ui.add(egui::Slider::new(&mut self.velocity, 0.0..=1.0).text("velocity"));
+            ui.checkbox(&mut self.qwerty, "computer keyboard")
+                .on_hover_text(
+                    "Z to M and Q to P play notes, - and + shift the octave. \
+                     Shift+D disconnects and Shift+R resets the zoom.",
+                );
+            if ui.button("all notes off").clicked() {
+                self.all_notes_off();
+            }
+        });
+        self.render_piano(ui);
+    }
+
+    fn render_piano(&mut self, ui: &mut egui::Ui) {
+        let n_white = 7 * PIANO_OCTAVES as usize + 1;
+        let size = egui::vec2(WHITE_KEY_SIZE.x * n_white as f32, WHITE_KEY_SIZE.y);
+        let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click_and_drag());
+
+        let mut white_keys = Vec::new();
+        let mut black_keys = Vec::new();
+        for octave in 0..=PIANO_OCTAVES {
+            let note = |semitone: u8| self.base_note.saturating_add(12 * octave as u8 + semitone);
+            let octave_x = rect.min.x + (7 * octave) as f32 * WHITE_KEY_SIZE.x;
+            for (idx, semitone) in WHITE_KEYS.iter().enumerate() {
+                if white_keys.len() == n_white {
+                    break;
+                }
+                let min = egui::pos2(octave_x + idx as f32 * WHITE_KEY_SIZE.x, rect.min.y);
+                white_keys.push((
+                    note(*semitone),
+                    egui::Rect::from_min_size(min, WHITE_KEY_SIZE),
+                ));
+            }
+            if octave == PIANO_OCTAVES {
+                break;
+            }
+            for (semitone, after) in BLACK_KEYS {
+                let x = octave_x + (after + 1) as f32 * WHITE_KEY_SIZE.x - BLACK_KEY_SIZE.x / 2.0;
+                let min = egui::pos2(x, rect.min.y);
+                black_keys.push((
+                    note(semitone),
+                    egui::Rect::from_min_size(min, BLACK_KEY_SIZE),
+                ));
+            }
+        }
+
+        // Black keys are on top
+        let pointer_note = response.interact_pointer_pos().and_then(|pointer| {
+            black_keys
+                .iter()
+                .chain(white_keys.iter())
+                .find(|(_, key_rect)| key_rect.contains(pointer))
+                .map(|(note, _)| *note)
+        });
+        let pressed_note = if response.is_pointer_button_down_on() {
+            pointer_note
+        } else {
+            None
+        };
+        if pressed_note != self.mouse_note {
+            if let Some(note) = self.mouse_note.take() {
+                self.note_off(note);
+            }
+            if let Some(note) = pressed_note {
+                self.note_on(note);
+                self.mouse_note = Some(note);
+            }
+        }
+
+        let painter = ui.painter_at(rect);
+        let stroke = egui::Stroke::new(1.0, egui::Color32::DARK_GRAY);
+        let held_color = egui::Color32::from_rgb(0x1E, 0x90, 0xFF);
+        for (note, key_rect) in &white_keys {
+            let fill = if self.held.contains(note) {
+                held_color
+            } else {
+                egui::Color32::from_gray(230)
+            };
+            painter.rect(key_rect.shrink(0.5), 2.0, fill, stroke);
+            if note % 12 == 0 {
+                painter.text(
+                    key_rect.center_bottom() - egui::vec2(0.0, 4.0),
+                    egui::Align2::CENTER_BOTTOM,
+                    format!("C{}", *note as i32 / 12 - 1),
+                    egui::FontId::proportional(9.0),
+                    egui::Color32::DARK_GRAY,
+                );
+            }
+        }
+        for (note, key_rect) in &black_keys {
+            let fill = if self.held.contains(note) {
+                held_color
+            } else {
+                egui::Color32::from_gray(30)
+            };
+            painter.rect(*key_rect, 2.0, fill, stroke);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turning_qwerty_off_releases_its_notes() {
+        let mut keyboard = Keyboard {
+            qwerty: true,
+            ..Keyboard::default()
+        };
+        keyboard.qwerty_notes.insert(egui::Key::Z, 48);
+        keyboard.held.push(48);
+        keyboard.qwerty = false;
+        keyboard.handle_qwerty(&egui::Context::default());
+        assert!(keyboard.qwerty_notes.is_empty());
+        assert!(keyboard.held.is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::patch_library::fuzzy_score;
+use crate::synth::*;
+use crate::GraphState;
+use eframe::egui;
+use std::sync::OnceLock;
+
+/// Draws the type specific part of a node, above its ports
+pub type NodeDrawer = fn(&mut egui::Ui, NodeKey, &mut Graph, &mut GraphState);
+
+pub struct NodeTypeInfo {
+    pub name: &'static str,
+    pub category: &'static str,
+    pub description: &'static str,
+    pub default_fn: fn() -> Box<dyn Node>,
+    pub can_create_in_ui: bool,
+    pub drawer: Option<NodeDrawer>,
+}
+
+impl NodeTypeInfo {
+    fn new<T: Node + Default>(category: &'static str, description: &'static str) -> Self {
+        Self {
+            name: T::name(),
+            category,
+            description,
+            default_fn: || Box::new(T::default()),
+            can_create_in_ui: true,
+            drawer: None,
+        }
+    }
+
+    fn new_uncreatable<T: Node + Default>(
+        category: &'static str,
+        description: &'static str,
+    ) -> Self {
+        let mut info = Self::new::<T>(category, description);
+        info.can_create_in_ui = false;
+        info
+    }
+
+    fn with_drawer(mut self, drawer: NodeDrawer) -> Self {
+        self.drawer = Some(drawer);
+        self
+    }
+}
+
+/// All node types known to the editor. Adding a node type only takes an
+/// entry here.
+pub struct NodeRegistry {
+    pub node_types: Vec<NodeTypeInfo>,
+}
+
+impl NodeRegistry {
+    pub fn get(&self, name: &str) -> Option<&NodeTypeInfo> {
+        self.node_types
+            .iter()
+            .find(|node_type| node_type.name == name)
+    }
+
+    /// Categories in registration order
+    pub fn categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        for node_type in &self.node_types {
+            if !categories.contains(&node_type.category) {
+                categories.push(node_type.category);
+            }
+        }
+        categories
+    }
+
+    /// Node types that can be created from the editor and match `query`,
+    /// best match first. Names match better than categories and descriptions.
+    pub fn search(&self, query: &str) -> Vec<&NodeTypeInfo> {
+        let mut matches: Vec<(i32, &NodeTypeInfo)> = self
+            .node_types
+            .iter()
+            .filter(|node_type| node_type.can_create_in_ui)
+            .filter_map(|node_type| {
+                let name_score = fuzzy_score(query, node_type.name).map(|score| score * 2);
+                let category_score = fuzzy_score(query, node_type.category);
+                let description_score = fuzzy_score(query, node_type.description);
+                let score = name_score.max(category_score).max(description_score)?;
+                Some((score, node_type))
+            })
+            .collect();
+        matches.sort_by(|a, b| b.0.cmp(&a.0));
+        matches
+            .into_iter()
+            .map(|(_, node_type)| node_type)
+            .collect()
+    }
+}
+
+static REGISTRY: OnceLock<NodeRegistry> = OnceLock::new();
+
+pub fn registry() -> &'static NodeRegistry {
+    REGISTRY.get_or_init(NodeRegistry::default)
+}
+
+impl Default for NodeRegistry {
+    fn default() -> Self {
+        use crate::*;
+        Self {
+            node_types: vec![
+                NodeTypeInfo::new::<SineOsc>("oscillators", "Sine wave oscillator"),
+                NodeTypeInfo::new::<SawOsc>("oscillators", "Sawtooth oscillator"),
+                NodeTypeInfo::new::<PhaseGen>("oscillators", "Phase ramp from 0 to 1"),
+                NodeTypeInfo::new::<Noise>("oscillators", "White, pink and brown noise"),
+                NodeTypeInfo::new::<Key>("input", "Most recent note from the piano"),
+                NodeTypeInfo::new::<VoiceKey>("input", "Notes from the piano, one voice each"),
+                NodeTypeInfo::new::<Lfo>("modulation", "Low frequency oscillator")
+                    .with_drawer(|ui, node_key, graph, _| draw_lfo(ui, node_key, graph)),
+                NodeTypeInfo::new::<Envelope>("modulation", "Attack, decay, sustain, release"),
+                NodeTypeInfo::new::<Sequencer>("modulation", "Step sequencer of notes")
+                    .with_drawer(draw_sequencer),
+                NodeTypeInfo::new::<Add>("math", "Sum of the inputs"),
+                NodeTypeInfo::new::<Scale>("math", "Multiplies the input").with_drawer(draw_scale),
+                NodeTypeInfo::new::<Bias>("math", "Adds a constant to the input"),
+                NodeTypeInfo::new::<Lowpass>("filters", "One pole lowpass filter"),
+                NodeTypeInfo::new::<Reverb>("effects", "Reverb"),
+                NodeTypeInfo::new::<Compressor>("dynamics", "Compressor"),
+                NodeTypeInfo::new::<Limiter>("dynamics", "Peak limiter"),
+                NodeTypeInfo::new::<Saturator>("dynamics", "Soft clipping saturation"),
+                NodeTypeInfo::new::<Sampler>("audio", "Plays a WAV file").with_drawer(draw_sampler),
+                NodeTypeInfo::new::<AudioIn>("audio", "Capture device or WAV file input")
+                    .with_drawer(draw_audio_in),
+                NodeTypeInfo::new::<Scope>("audio", "Oscilloscope with up to four traces")
+                    .with_drawer(draw_scope),
+                NodeTypeInfo::new_uncreatable::<Out>("audio", "Output of the graph")
+                    .with_drawer(draw_out),
+                NodeTypeInfo::new::<Subgraph>("subgraphs", "Graph nested in a node")
+                    .with_drawer(draw_subgraph),
+                NodeTypeInfo::new::<SubgraphInput>("subgraphs", "Input port of a subgraph")
+                    .with_drawer(|ui, node_key, graph, _| draw_subgraph_port(ui, node_key, graph)),
+                NodeTypeInfo::new::<SubgraphOutput>("subgraphs", "Output port of a subgraph")
+                    .with_drawer(|ui, node_key, graph, _| draw_subgraph_port(ui, node_key, graph)),
+            ],
+        }
+    }
+}
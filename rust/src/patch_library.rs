@@ -0,0 +1,208 @@
+use crate::synth::*;
+use glob::glob;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+// Length of the audition render
+const PREVIEW_SECONDS: f32 = 2.0;
+
+/// A patch file in the library
+#[derive(Clone)]
+pub struct PatchEntry {
+    // Path used to open the file
+    pub file: String,
+    // Path relative to the library root without extension, e.g. "drums/kick"
+    pub name: String,
+    pub meta: PatchMeta,
+}
+
+impl PatchEntry {
+    pub fn folder(&self) -> &str {
+        self.name
+            .rsplit_once('/')
+            .map(|(folder, _)| folder)
+            .unwrap_or("")
+    }
+
+    pub fn short_name(&self) -> &str {
+        self.name
+            .rsplit_once('/')
+            .map(|(_, name)| name)
+            .unwrap_or(&self.name)
+    }
+}
+
+// Only the metadata of a patch file. Nodes are skipped so that listing the
+// library doesn't load the samples they reference.
+#[derive(Deserialize)]
+struct PatchHeader {
+    #[serde(default)]
+    meta: PatchMeta,
+}
+
+/// Patch files below a root directory, including subfolders.
+pub struct PatchLibrary {
+    pub root: String,
+    pub entries: Vec<PatchEntry>,
+    meta_cache: HashMap<String, (Option<SystemTime>, PatchMeta)>,
+}
+
+impl PatchLibrary {
+    /// The root is taken from `SYNTH_PATCH_DIR`, defaulting to the working
+    /// directory.
+    pub fn new() -> Self {
+        Self {
+            root: std::env::var("SYNTH_PATCH_DIR").unwrap_or_else(|_| ".".to_string()),
+            entries: Vec::new(),
+            meta_cache: HashMap::new(),
+        }
+    }
+
+    pub fn scan(&mut self) {
+        let pattern = format!("{}/**/*.patch", self.root.trim_end_matches('/'));
+        let Ok(paths) = glob(&pattern) else {
+            println!("Invalid patch directory {}", self.root);
+            self.entries.clear();
+            return;
+        };
+        let mut entries = Vec::new();
+        for path in paths.filter_map(Result::ok) {
+            let name = path
+                .strip_prefix(&self.root)
+                .unwrap_or(&path)
+                .with_extension("")
+                .to_string_lossy()
+                .replace('\\', "/");
+            // Built the same way as when saving, so paths can be compared
+            let file = self.file_for(&name);
+            let meta = self.read_meta(&file);
+            entries.push(PatchEntry { file, name, meta });
+        }
+        // Sorted by folder first so the entries of a folder are contiguous
+        entries.sort_by(|a, b| (a.folder(), a.short_name()).cmp(&(b.folder(), b.short_name())));
+        self.meta_cache
+            .retain(|file, _| entries.iter().any(|entry| &entry.file == file));
+        self.entries = entries;
+    }
+
+    fn read_meta(&mut self, file: &str) -> PatchMeta {
+        let modified = std::fs::metadata(file).and_then(|m| m.modified()).ok();
+        if let Some((cached_time, meta)) = self.meta_cache.get(file) {
+            if *cached_time == modified {
+                return meta.clone();
+            }
+        }
+        let meta = std::fs::read_to_string(file)
+            .ok()
+            .and_then(|contents| ron::from_str::<PatchHeader>(&contents).ok())
+            .map(|header| header.meta)
+            .unwrap_or_default();
+        self.meta_cache
+            .insert(file.to_string(), (modified, meta.clone()));
+        meta
+    }
+
+    /// File path of the patch called `name`, relative to the root
+    pub fn file_for(&self, name: &str) -> String {
+        Path::new(&self.root)
+            .join(format!("{}.patch", name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Entries matching `query`, best match first. Names, tags and the
+    /// description are searched.
+    pub fn search(&self, query: &str) -> Vec<&PatchEntry> {
+        if query.is_empty() {
+            return self.entries.iter().collect();
+        }
+        let mut matches: Vec<(i32, &PatchEntry)> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let name_score = fuzzy_score(query, &entry.name).map(|score| score * 2);
+                let tag_score = entry
+                    .meta
+                    .tags
+                    .iter()
+                    .filter_map(|tag| fuzzy_score(query, tag))
+                    .max();
+                let description_score = fuzzy_score(query, &entry.meta.description);
+                let score = name_score.max(tag_score).max(description_score)?;
+                Some((score, entry))
+            })
+            .collect();
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        matches.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// Moves a patch to `new_name`, creating folders as needed. Returns the
+    /// new file path.
+    pub fn rename(&mut self, file: &str, new_name: &str) -> anyhow::Result<String> {
+        let new_file = self.file_for(new_name);
+        if Path::new(&new_file).exists() {
+            anyhow::bail!("{} already exists", new_file);
+        }
+        if let Some(parent) = Path::new(&new_file).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(file, &new_file)?;
+        self.scan();
+        Ok(new_file)
+    }
+
+    pub fn delete(&mut self, file: &str) -> anyhow::Result<()> {
+        std::fs::remove_file(file)?;
+        self.scan();
+        Ok(())
+    }
+}
+
+/// Scores how well `query` matches `text` when its characters appear in
+/// order, not necessarily next to each other. Consecutive matches and
+/// matches at the start of words score higher. Returns `None` if some
+/// character of the query is missing.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut last_match: Option<usize> = None;
+    for q in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let idx = pos + text[pos..].iter().position(|c| *c == q)?;
+        score += 1;
+        if last_match.is_some_and(|last| last + 1 == idx) {
+            score += 3;
+        }
+        if idx == 0 || matches!(text[idx - 1], '/' | ' ' | '_' | '-') {
+            score += 2;
+        }
+        last_match = Some(idx);
+        pos = idx + 1;
+    }
+    // Prefer shorter texts among equal matches
+    Some(score * 16 - (text.len() as i32).min(15))
+}
+
+/// Renders the first seconds of a patch file for auditioning it, at half
+/// gain like the live output.
+pub fn render_preview(file: &str, sample_rate: f32) -> anyhow::Result<Vec<f32>> {
+    let file_contents = std::fs::read_to_string(file)?;
+    let mut graph: Graph = ron::from_str(&file_contents)?;
+    Subgraph::reload_linked(&mut graph);
+    if graph.output_node.is_none() {
+        graph.output_node = graph.get_by_type_mut::<Out>().map(|(out_key, _)| out_key);
+    }
+    if graph.output_node.is_none() {
+        anyhow::bail!("{} has no output node", file);
+    }
+    graph.sort();
+    graph.prepare(sample_rate, DEFAULT_MAX_BLOCK);
+    let mut samples = vec![0.0; (PREVIEW_SECONDS * sample_rate) as usize];
+    graph.process(&mut samples, sample_rate);
+    for sample in &mut samples {
+        *sample *= 0.5;
+    }
+    Ok(samples)
+}
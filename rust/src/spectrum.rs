@@ -0,0 +1,198 @@
+use crate::synth::*;
+use eframe::egui;
+use egui::plot::{Line, Plot, PlotPoints};
+use std::f32::consts::PI;
+
+const FFT_SIZES: [usize; 4] = [1024, 2048, 4096, 8192];
+const MIN_DB: f32 = -120.0;
+// How fast held peaks fall back, in dB per frame
+const PEAK_DECAY: f32 = 0.3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FftWindow {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+const FFT_WINDOWS: [FftWindow; 4] = [
+    FftWindow::Rectangular,
+    FftWindow::Hann,
+    FftWindow::Hamming,
+    FftWindow::Blackman,
+];
+
+impl FftWindow {
+    fn coefficients(self, n: usize) -> Vec<f32> {
+        let x = |i: usize| 2.0 * PI * i as f32 / (n - 1) as f32;
+        (0..n)
+            .map(|i| match self {
+                FftWindow::Rectangular => 1.0,
+                FftWindow::Hann => 0.5 - 0.5 * x(i).cos(),
+                FftWindow::Hamming => 0.54 - 0.46 * x(i).cos(),
+                FftWindow::Blackman => 0.42 - 0.5 * x(i).cos() + 0.08 * (2.0 * x(i)).cos(),
+            })
+            .collect()
+    }
+}
+
+/// Spectrum of the final output. It reads `audio_output()`, which the audio
+/// callback fills, so the graph doesn't have to be locked.
+pub struct SpectrumAnalyzer {
+    pub fft_size: usize,
+    pub window: FftWindow,
+    pub peak_hold: bool,
+    // Magnitudes in dB of the last frame and the held peaks, per bin
+    magnitudes: Vec<f32>,
+    peaks: Vec<f32>,
+}
+
+impl Default for SpectrumAnalyzer {
+    fn default() -> Self {
+        Self {
+            fft_size: 4096,
+            window: FftWindow::Hann,
+            peak_hold: true,
+            magnitudes: Vec::new(),
+            peaks: Vec::new(),
+        }
+    }
+}
+
+impl SpectrumAnalyzer {
+    fn analyze(&mut self) {
+        let ring = audio_output();
+        let n = self.fft_size;
+        let write_pos = ring.write_pos();
+        if write_pos < n {
+            return;
+        }
+        let window = self.window.coefficients(n);
+        let window_sum: f32 = window.iter().sum();
+        let mut re: Vec<f32> = (0..n)
+            .map(|i| ring.read(write_pos - n + i) * window[i])
+            .collect();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+        // Scaled so a full scale sine reads 0 dB
+        self.magnitudes = (0..n / 2)
+            .map(|bin| {
+                let magnitude = (re[bin] * re[bin] + im[bin] * im[bin]).sqrt() * 2.0 / window_sum;
+                (20.0 * magnitude.max(1e-9).log10()).max(MIN_DB)
+            })
+            .collect();
+        if self.peaks.len() != self.magnitudes.len() {
+            self.peaks = self.magnitudes.clone();
+        }
+        for (peak, magnitude) in self.peaks.iter_mut().zip(&self.magnitudes) {
+            *peak = (*peak - PEAK_DECAY).max(*magnitude);
+        }
+    }
+
+    pub fn render(&mut self, ctx: &egui::Context, sample_rate: f32) {
+        egui::Window::new("Spectrum")
+            .default_open(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::new("fft_size", "")
+                        .selected_text(format!("{}", self.fft_size))
+                        .width(60.0)
+                        .show_ui(ui, |ui| {
+                            for size in FFT_SIZES {
+                                ui.selectable_value(&mut self.fft_size, size, format!("{}", size));
+                            }
+                        });
+                    egui::ComboBox::new("fft_window", "")
+                        .selected_text(format!("{:?}", self.window))
+                        .width(90.0)
+                        .show_ui(ui, |ui| {
+                            for window in FFT_WINDOWS {
+                                ui.selectable_value(
+                                    &mut self.window,
+                                    window,
+                                    format!("{:?}", window),
+                                );
+                            }
+                        });
+                    ui.checkbox(&mut self.peak_hold, "peak hold");
+                    if ui.button("reset peaks").clicked() {
+                        self.peaks.clear();
+                    }
+                });
+                self.analyze();
+
+                // Log frequency axis, x is log10 of the frequency
+                let bin_hz = sample_rate / self.fft_size as f32;
+                let to_points = |magnitudes: &[f32]| -> PlotPoints {
+                    magnitudes
+                        .iter()
+                        .enumerate()
+                        .skip(1)
+                        .map(|(bin, db)| [(bin as f32 * bin_hz).log10() as f64, *db as f64])
+                        .collect()
+                };
+                let spectrum = Line::new(to_points(&self.magnitudes)).name("spectrum");
+                let peaks = Line::new(to_points(&self.peaks))
+                    .name("peak")
+                    .color(egui::Color32::from_rgb(0xFF, 0x45, 0x00));
+                let peak_hold = self.peak_hold;
+                Plot::new("spectrum")
+                    .height(200.0)
+                    .width(400.0)
+                    .include_x(20f64.log10())
+                    .include_x((sample_rate as f64 / 2.0).log10())
+                    .include_y(MIN_DB)
+                    .include_y(0.0)
+                    .allow_drag(false)
+                    .allow_zoom(false)
+                    .allow_scroll(false)
+                    .x_axis_formatter(|x, _| {
+                        let hz = 10f64.powf(x);
+                        if hz >= 1000.0 {
+                            format!("{:.1}k", hz / 1000.0)
+                        } else {
+                            format!("{:.0}", hz)
+                        }
+                    })
+                    .y_axis_formatter(|y, _| format!("{:.0} dB", y))
+                    .show(ui, |plot_ui| {
+                        plot_ui.line(spectrum);
+                        if peak_hold {
+                            plot_ui.line(peaks);
+                        }
+                    });
+            });
+    }
+}
+
+/// In place radix-2 FFT, the length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two());
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len *= 2;
+    }
+}
@@ -2,7 +2,8 @@ use serde::{Deserialize, Serialize};
 use slotmap::SlotMap;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use symbol_table::GlobalSymbol;
@@ -10,38 +11,74 @@ use symbol_table::GlobalSymbol;
 // use crate::signal;
 //use std::fs::OpenOptions;
 
+pub mod activity;
 pub mod add;
+pub mod audio_in;
 pub mod bias;
+pub mod compressor;
 pub mod envelope;
 pub mod group;
 pub mod hp;
 pub mod key;
+pub mod lfo;
+pub mod limiter;
 pub mod lp;
+pub mod node_store;
+pub mod noise;
+pub mod notes;
 pub mod out;
+pub mod oversample;
+pub mod parallel;
 pub mod phase_gen;
+pub mod profile;
 pub mod reverb;
+pub mod sampler;
+pub mod saturator;
 pub mod saw_osc;
 pub mod scale;
+pub mod scope;
 pub mod sequencer;
 pub mod sine_osc;
 pub mod subgraph;
+pub mod subgraph_input;
+pub mod subgraph_output;
+pub mod topology;
+pub mod transport;
 pub mod voice_key;
 
+pub use activity::*;
 pub use add::*;
+pub use audio_in::*;
 pub use bias::*;
+pub use compressor::*;
 pub use envelope::*;
 pub use group::*;
 pub use hp::*;
 pub use key::*;
+pub use lfo::*;
+pub use limiter::*;
 pub use lp::*;
+pub use node_store::*;
+pub use noise::*;
+pub use notes::*;
 pub use out::*;
+pub use oversample::*;
+pub use parallel::*;
 pub use phase_gen::*;
+pub use profile::*;
 pub use reverb::*;
+pub use sampler::*;
+pub use saturator::*;
 pub use saw_osc::*;
 pub use scale::*;
+pub use scope::*;
 pub use sequencer::*;
 pub use sine_osc::*;
 pub use subgraph::*;
+pub use subgraph_input::*;
+pub use subgraph_output::*;
+pub use topology::*;
+pub use transport::*;
 pub use voice_key::*;
 
 slotmap::new_key_type! { pub struct ChannelId; }
@@ -64,6 +101,31 @@ slotmap::new_key_type! { pub struct NodeKey; }
 // }
 
 pub type SharedGraph = Arc<Mutex<Graph>>;
+
+// Block size graphs rendered without a stream are prepared for
+pub const DEFAULT_MAX_BLOCK: usize = 4096;
+
+// Samples a graph processes at a time, longer buffers are split. Every
+// stored output port keeps a block of this many values.
+pub const SIGNAL_BLOCK: usize = 256;
+
+// Current patch format. 1 has frequencies in Hz, they were in kHz before.
+pub const GRAPH_VERSION: u32 = 1;
+pub const HZ_VERSION: u32 = 1;
+// Nodes whose pitch outputs were in kHz before HZ_VERSION and are computed
+// in Hz now
+const HZ_SOURCES: [&str; 2] = ["Key", "VoiceKey"];
+
+// Range of input knobs, and of audio frequencies in Hz
+pub const DEFAULT_INPUT_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+pub const FREQUENCY_RANGE: RangeInclusive<f32> = 0.0..=20_000.0;
+
+/// Sample rate and block size the nodes of a graph are prepared for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PrepareInfo {
+    pub sample_rate: f32,
+    pub max_block: usize,
+}
 // pub type SharedChannels = Arc<Mutex<SlotMap<ChannelId, SharedGraph>>>;
 
 // Should be NodeInput
@@ -120,6 +182,23 @@ pub trait Node: Send + Any + 'static {
         *self.get_input_mut(idx)
     }
     fn step(&mut self, sample_rate: f32);
+    /// Called before the first `step` and whenever the sample rate changes.
+    /// Delay lines and coefficients that depend on the rate are set up here
+    /// rather than in `step`. `max_block` is the most samples the graph is
+    /// stepped per buffer.
+    fn prepare(&mut self, _sample_rate: f32, _max_block: usize) {}
+    /// Brings a node loaded from a patch saved at an older `GRAPH_VERSION`
+    /// up to date
+    fn upgrade(&mut self, _version: u32) {}
+    /// Rough cost of a step, relative to a node that does a few arithmetic
+    /// operations. Decides which layers are worth stepping on other threads.
+    fn cost(&self) -> u32 {
+        1
+    }
+    /// Values the editor knob of an input goes between
+    fn input_range(&self, _idx: usize) -> RangeInclusive<f32> {
+        DEFAULT_INPUT_RANGE
+    }
     fn name() -> &'static str
     where
         Self: Sized;
@@ -183,21 +262,55 @@ pub struct Edge {
 
 // type Edge = (NodeOutput, NodeInput);
 
+/// Description and tags of a patch, shown in the patch library
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PatchMeta {
+    pub description: String,
+    pub tags: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Graph {
-    nodes: SlotMap<NodeKey, RefCell<Box<dyn Node>>>,
+    nodes: NodeStore,
     edges: Vec<Edge>,
 
     node_order: Vec<NodeKey>,
     node_outputs: HashMap<NodeKey, Vec<Edge>>,
     node_inputs: HashMap<NodeKey, Vec<Edge>>,
     node_depths: HashMap<NodeKey, i32>,
+    // The plan the audio thread runs, replaced by `publish`. None until a
+    // loaded graph is sorted.
+    #[serde(skip)]
+    plan: Option<Arc<ExecutionPlan>>,
+    // The topology changed since the plan was built
+    #[serde(skip)]
+    dirty: bool,
 
     pub output_node: Option<NodeKey>,
 
+    // Editor canvas position of each node
+    #[serde(default)]
+    pub positions: HashMap<NodeKey, [f32; 2]>,
+
+    #[serde(default)]
+    pub meta: PatchMeta,
+
+    // Levels of the output ports, for the editor
+    #[serde(skip)]
+    pub activity: ActivityMeter,
+    #[serde(skip)]
+    pub profiler: Profiler,
+    #[serde(skip)]
+    prepared: Option<PrepareInfo>,
+
     pub volume: f32,
     pub steps: u64,
 
+    // Patch format the graph was saved with, 0 for patches from before the
+    // version was kept
+    #[serde(default)]
+    pub version: u32,
+
     #[serde(with = "serde_millis")]
     pub ctime: Instant,
 }
@@ -223,9 +336,14 @@ impl Graph {
         }
     }
     pub fn add(&mut self, node: Box<dyn Node>) -> NodeKey {
-        println!("Adding {}", node.type_name());
-        let key = self.nodes.insert(RefCell::new(node));
-        self.sort();
+        let key = self.nodes.insert(node);
+        self.node_inputs.insert(key, Vec::new());
+        self.node_outputs.insert(key, Vec::new());
+        self.prepare_node(key);
+        // Unconnected until the next publish sorts it in
+        self.node_order.push(key);
+        self.node_depths.insert(key, 0);
+        self.dirty = true;
         key
         // self.nodes.len() - 1
     }
@@ -261,14 +379,9 @@ impl Graph {
     }
 
     pub fn connect(&mut self, from: Port, to: Port) {
-        self.edges
-            .retain(|edge| !(edge.to.node == to.node && edge.to.port == to.port));
-        self.edges.push(Edge {
-            from: from.clone(),
-            to: to.clone(),
-        });
-        println!("Connected {}", self.format_edge_pair(&Edge { from, to }));
-        self.sort();
+        self.remove_edges(|edge| edge.to.node == to.node && edge.to.port == to.port);
+        self.insert_edge(Edge { from, to });
+        self.dirty = true;
     }
 
     pub fn get_edge(&self, to: Port) -> Option<Edge> {
@@ -284,79 +397,99 @@ impl Graph {
     }
 
     pub fn disconnect(&mut self, from: Port, to: Port) {
-        if let Some(idx) = self
-            .edges
-            .iter()
-            .position(|edge| edge.from == from && edge.to == to)
-        {
-            println!("Disconnecting {}", self.format_edge(idx));
-            self.edges.remove(idx);
-        } else {
-            println!("Couldn't find the edge!");
-        }
-        self.sort();
+        self.remove_edges(|edge| edge.from == from && edge.to == to);
+        self.dirty = true;
     }
 
     pub fn disconnect_input_port(&mut self, input: Port) {
-        if let Some(idx) = self.edges.iter().position(|edge| edge.to == input) {
-            println!("Disconnecting {}", self.format_edge(idx));
-            self.edges.remove(idx);
-        }
-        self.sort();
+        self.remove_edges(|edge| edge.to == input);
+        self.dirty = true;
     }
 
     pub fn disconnect_node(&mut self, node_key: NodeKey) {
-        self.edges
-            .retain(|edge| !(edge.from.node == node_key || edge.to.node == node_key));
-        self.sort();
+        self.remove_edges(|edge| edge.from.node == node_key || edge.to.node == node_key);
+        self.dirty = true;
     }
 
     pub fn remove(&mut self, node_key: NodeKey) {
-        println!("Removing {}", self.nodes[node_key].borrow().type_name());
-        self.disconnect_node(node_key);
+        self.remove_edges(|edge| edge.from.node == node_key || edge.to.node == node_key);
         self.nodes.remove(node_key);
-        self.sort();
+        self.node_inputs.remove(&node_key);
+        self.node_outputs.remove(&node_key);
+        self.positions.remove(&node_key);
+        self.node_order.retain(|key| *key != node_key);
+        self.node_depths.remove(&node_key);
+        self.dirty = true;
         // NOTE: should we free box here?
     }
 
     pub fn clear(&mut self) {
         self.edges.clear();
         self.nodes.clear();
+        self.node_inputs.clear();
+        self.node_outputs.clear();
+        self.node_order.clear();
+        self.node_depths.clear();
+        self.positions.clear();
         self.add(Box::new(Out::default()));
         // _ = self.nodes.split_off(1);
-        self.sort();
     }
 
     pub fn new() -> Self {
         let mut g = Graph {
-            nodes: SlotMap::with_key(),
+            nodes: NodeStore::default(),
             edges: vec![],
             node_order: vec![],
             node_outputs: HashMap::new(),
             node_inputs: HashMap::new(),
             node_depths: HashMap::new(),
+            plan: None,
+            dirty: false,
             output_node: None,
+            positions: HashMap::new(),
+            meta: PatchMeta::default(),
+            activity: ActivityMeter::default(),
+            profiler: Profiler::default(),
+            prepared: None,
             volume: 1.0,
             steps: 0,
+            version: GRAPH_VERSION,
             ctime: Instant::now(),
         };
         let out_key = g.add(Box::new(Out::default()));
         g.output_node = Some(out_key);
+        g.publish();
         g
     }
 
     pub fn copy(&self) -> Self {
-        // panic!();
+        let node_keys: Vec<NodeKey> = self.nodes.keys().collect();
+        self.copy_nodes(&node_keys)
+    }
+
+    /// Copies the given nodes and the edges between them into a new graph
+    pub fn copy_nodes(&self, node_keys: &[NodeKey]) -> Self {
+        self.copy_nodes_with_lookup(node_keys).0
+    }
+
+    /// Like `copy_nodes`, also returns the keys of the copies
+    pub fn copy_nodes_with_lookup(
+        &self,
+        node_keys: &[NodeKey],
+    ) -> (Self, HashMap<NodeKey, NodeKey>) {
         let mut node_lookup: HashMap<NodeKey, NodeKey> = HashMap::new();
-        let mut edge_lookup: HashMap<Edge, Edge> = HashMap::new();
-        let mut new_sm: SlotMap<NodeKey, _> = SlotMap::with_key();
-        self.nodes.iter().for_each(|(key, node)| {
-            let new_key = new_sm.insert(RefCell::new(node.borrow().copy()));
-            node_lookup.insert(key, new_key);
+        let mut new_nodes = NodeStore::default();
+        node_keys.iter().for_each(|key| {
+            let new_key = new_nodes.insert(self.nodes[*key].borrow().copy());
+            node_lookup.insert(*key, new_key);
         });
-        let mut new_edges: Vec<Edge> = Vec::new();
-        self.edges.iter().for_each(|edge| {
-            let new_edge = Edge {
+        let new_edges: Vec<Edge> = self
+            .edges
+            .iter()
+            .filter(|edge| {
+                node_lookup.contains_key(&edge.from.node) && node_lookup.contains_key(&edge.to.node)
+            })
+            .map(|edge| Edge {
                 from: Port {
                     node: node_lookup[&edge.from.node],
                     port: edge.from.port,
@@ -367,28 +500,121 @@ impl Graph {
                     port: edge.to.port,
                     kind: edge.to.kind.clone(),
                 },
-            };
-            new_edges.push(new_edge.clone());
-            edge_lookup.insert(edge.clone(), new_edge);
-        });
+            })
+            .collect();
         let mut graph = Graph {
-            nodes: new_sm,
+            nodes: new_nodes,
             edges: new_edges,
             node_order: self
                 .node_order
                 .iter()
-                .map(|node_key| node_lookup[node_key])
+                .filter_map(|node_key| node_lookup.get(node_key).copied())
                 .collect(),
             node_outputs: HashMap::new(),
             node_inputs: HashMap::new(),
             node_depths: HashMap::new(),
-            output_node: self.output_node.map(|node_key| node_lookup[&node_key]),
+            plan: None,
+            dirty: false,
+            output_node: self
+                .output_node
+                .and_then(|node_key| node_lookup.get(&node_key).copied()),
+            positions: self
+                .positions
+                .iter()
+                .filter_map(|(node_key, pos)| node_lookup.get(node_key).map(|key| (*key, *pos)))
+                .collect(),
+            meta: self.meta.clone(),
+            activity: ActivityMeter::default(),
+            profiler: Profiler::default(),
+            prepared: None,
             volume: self.volume,
             steps: self.steps,
+            version: self.version,
             ctime: Instant::now(),
         };
         graph.sort();
-        graph
+        (graph, node_lookup)
+    }
+
+    /// Adds copies of all nodes and edges in `other`, returns the keys of
+    /// the new nodes
+    pub fn paste(&mut self, other: &Graph) -> Vec<NodeKey> {
+        let mut node_lookup: HashMap<NodeKey, NodeKey> = HashMap::new();
+        for (key, node) in other.nodes.iter() {
+            let mut node = node.borrow().copy();
+            node.upgrade(other.version);
+            let new_key = self.nodes.insert(node);
+            if let Some(pos) = other.positions.get(&key) {
+                self.positions.insert(new_key, *pos);
+            }
+            self.node_order.push(new_key);
+            self.node_depths.insert(new_key, 0);
+            node_lookup.insert(key, new_key);
+            self.node_inputs.insert(new_key, Vec::new());
+            self.node_outputs.insert(new_key, Vec::new());
+            self.prepare_node(new_key);
+        }
+        // The clipboard is plain text, edges between nodes or ports that
+        // aren't there are dropped
+        for edge in &other.edges {
+            let (Some(from), Some(to)) = (
+                node_lookup.get(&edge.from.node),
+                node_lookup.get(&edge.to.node),
+            ) else {
+                continue;
+            };
+            if edge.from.port >= self.nodes[*from].borrow().outputs().len()
+                || edge.to.port >= self.nodes[*to].borrow().inputs().len()
+            {
+                continue;
+            }
+            let mut new_edge = edge.clone();
+            new_edge.from.node = *from;
+            new_edge.to.node = *to;
+            self.insert_edge(new_edge);
+        }
+        self.dirty = true;
+        other
+            .nodes
+            .keys()
+            .map(|node_key| node_lookup[&node_key])
+            .collect()
+    }
+
+    /// The nodes of this graph, a newer version of the patch that `previous`
+    /// is the loaded version of, that are the same in both. Nodes are
+    /// compared serialized, so this is done before the running graph is
+    /// locked.
+    pub fn unchanged_nodes(&self, previous: &Graph) -> Vec<NodeKey> {
+        self.nodes
+            .iter()
+            .filter(|(node_key, node)| {
+                previous.nodes.get(*node_key).is_some_and(|previous_node| {
+                    let previous_node = ron::to_string(&*previous_node.borrow()).ok();
+                    previous_node.is_some() && previous_node == ron::to_string(&*node.borrow()).ok()
+                })
+            })
+            .map(|(node_key, _)| node_key)
+            .collect()
+    }
+
+    /// Takes the running instances of the `unchanged` nodes from `running`,
+    /// before this graph replaces it, so oscillator phases and other state
+    /// carry over. Returns the number of kept nodes.
+    pub fn take_running_nodes(&mut self, running: &mut Graph, unchanged: &[NodeKey]) -> usize {
+        let mut kept = 0;
+        for node_key in unchanged {
+            if let (Some(node), Some(running_node)) = (
+                self.nodes.get_mut(*node_key),
+                running.nodes.get_mut(*node_key),
+            ) {
+                std::mem::swap(node, running_node);
+                kept += 1;
+            }
+        }
+        self.steps = running.steps;
+        self.ctime = running.ctime;
+        kept
     }
 
     pub fn get_by_type_mut<T: Node>(&mut self) -> Option<(NodeKey, core::cell::RefMut<'_, T>)> {
@@ -488,113 +714,133 @@ impl Graph {
             .collect();
         unconnected_outputs
     }
+    /// Brings the nodes of a graph loaded from an older patch up to date
+    pub fn upgrade(&mut self) {
+        if self.version < HZ_VERSION {
+            for edge in self.khz_frequency_edges() {
+                println!(
+                    "Frequency set in kHz by an older patch, scale it to Hz: {}",
+                    self.format_edge_pair(&edge)
+                );
+            }
+        }
+        for node in self.nodes.values_mut() {
+            node.get_mut().upgrade(self.version);
+        }
+        self.version = GRAPH_VERSION;
+    }
+
+    /// Edges into frequency inputs from nodes other than keys. Nodes only
+    /// scale their own knobs from kHz to Hz, values from these edges are
+    /// still in kHz when the graph is from before HZ_VERSION.
+    pub fn khz_frequency_edges(&self) -> Vec<Edge> {
+        self.edges
+            .iter()
+            .filter(|edge| {
+                let (Some(from), Some(to)) =
+                    (self.nodes.get(edge.from.node), self.nodes.get(edge.to.node))
+                else {
+                    return false;
+                };
+                to.borrow().input_range(edge.to.port) == FREQUENCY_RANGE
+                    && !HZ_SOURCES.contains(&from.borrow().typetag_name())
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Rebuilds the adjacency of all nodes from `edges`, and the execution
+    /// plan from that. Edits keep both up to date on their own, this is for
+    /// loaded graphs and code that changes `edges` directly. Graphs from
+    /// older patches are upgraded first.
     pub fn sort(&mut self) {
-        let mut new_node_order = vec![];
-        let mut new_output_edges = HashMap::new();
-        let mut new_input_edges = HashMap::new();
-        let mut node_depths: HashMap<NodeKey, i32> = HashMap::new();
-        let mut depth: i32 = 0;
-
-        // Find nodes without connected inputs, these are the initial nodes
-        let mut node_idxs: Vec<_> = self
+        self.upgrade();
+        self.node_inputs = self
             .nodes
             .keys()
-            .filter(|node_key| {
-                self.edges
-                    .iter()
-                    .filter(|edge| edge.to.node == *node_key)
-                    .count()
-                    == 0
-            })
-            .collect();
-        let edges_clone = self.edges.clone();
-        let connected_opt: HashMap<Port, Port> = edges_clone
-            .into_iter()
-            .map(|edge| (edge.to, edge.from))
+            .map(|node_key| (node_key, Vec::new()))
             .collect();
+        self.node_outputs = self.node_inputs.clone();
+        for edge in &self.edges {
+            if self.nodes.contains_key(edge.from.node) && self.nodes.contains_key(edge.to.node) {
+                self.node_outputs
+                    .get_mut(&edge.from.node)
+                    .unwrap()
+                    .push(edge.clone());
+                self.node_inputs
+                    .get_mut(&edge.to.node)
+                    .unwrap()
+                    .push(edge.clone());
+            }
+        }
+        self.dirty = true;
+        self.publish();
+    }
 
-        // Traverse until leaf output nodes
-        let mut updated_connections: HashSet<Port> = HashSet::new();
-        while node_idxs.len() > 0 {
-            let mut next_nodes: Vec<NodeKey> = Vec::new();
-            for node_idx in node_idxs {
-                let node_inputs = self.nodes[node_idx].borrow().inputs();
-                let connected_inputs: Vec<_> = node_inputs
-                    .iter()
-                    .filter(|port_id| {
-                        connected_opt.contains_key(&Port {
-                            node: node_idx,
-                            port: port_id.port,
-                            kind: PortKind::Input,
-                        })
-                    })
-                    .collect();
-                if connected_inputs
-                    .iter()
-                    .map(|port_id| {
-                        let input = Port {
-                            node: node_idx,
-                            port: port_id.port,
-                            kind: PortKind::Input,
-                        };
-                        updated_connections.contains(&input)
-                    })
-                    .all(|x| x)
-                {
-                    // Get edges connecting outputs of node_idx to other nodes
-                    let connections: Vec<_> = self
-                        .edges
-                        .iter()
-                        // .clone()
-                        // .into_iter()
-                        .filter(move |edge| edge.from.node == node_idx)
-                        .cloned()
-                        .collect();
-
-                    let connections_inputs: Vec<_> = self
-                        .edges
-                        .iter()
-                        // .clone()
-                        // .into_iter()
-                        .filter(move |edge| edge.to.node == node_idx)
-                        .cloned()
-                        .collect();
-
-                    if !new_node_order.contains(&node_idx) {
-                        new_node_order.push(node_idx.clone());
-                        node_depths.insert(node_idx.clone(), depth);
-                        new_output_edges.insert(node_idx.clone(), connections.clone());
-                        new_input_edges.insert(node_idx.clone(), connections_inputs.clone());
-                    }
-
-                    // for ((_edge_out_idx, _port_out_idx), (edge_in_idx, port_in_idx)) in connections
-                    for edge in connections {
-                        // Add the downstream node to the traversal list
-                        if !next_nodes.contains(&edge.to.node) {
-                            next_nodes.push(edge.to.node);
-                        }
-                        updated_connections.insert(Port {
-                            node: edge.to.node,
-                            port: edge.to.port,
-                            kind: PortKind::Input,
-                        });
-                    }
-                }
+    /// Builds the execution plan after the topology changed and hands it to
+    /// the audio thread, which never builds one itself. Edits only mark the
+    /// graph dirty, the editor publishes once per frame so that a paste or a
+    /// grouped undo builds the plan once. Graphs of subgraphs are published
+    /// along with it.
+    pub fn publish(&mut self) {
+        for node in self.nodes.values_mut() {
+            if let Some(subgraph) = node.get_mut().as_any_mut().downcast_mut::<Subgraph>() {
+                subgraph.subgraph.publish();
             }
-            node_idxs = next_nodes;
-            depth += 1;
         }
-        self.node_order = new_node_order;
-        for nk in &self.node_order {
-            println!(
-                "{:?}, {}",
-                self.get_node(*nk).typetag_name(),
-                node_depths[nk]
-            );
+        if !self.dirty && self.plan.is_some() {
+            return;
+        }
+        let mut plan = ExecutionPlan::new(
+            self.nodes.keys(),
+            &self.node_inputs,
+            &self.node_outputs,
+            self.output_node,
+        );
+        let costs: Vec<u32> = plan
+            .order
+            .iter()
+            .map(|node_key| self.nodes[*node_key].borrow().cost())
+            .collect();
+        plan.parallel = parallel_layers(&plan, &costs);
+        self.nodes.arrange(
+            &plan.order,
+            plan.steps
+                .iter()
+                .map(|step| step.signals.len() * SIGNAL_BLOCK),
+        );
+        self.node_order = plan.order.clone();
+        self.node_depths = plan.depths();
+        self.activity.update_signals(&plan.signals);
+        self.plan = Some(Arc::new(plan));
+        self.dirty = false;
+    }
+
+    fn insert_edge(&mut self, edge: Edge) {
+        self.node_outputs
+            .entry(edge.from.node)
+            .or_default()
+            .push(edge.clone());
+        self.node_inputs
+            .entry(edge.to.node)
+            .or_default()
+            .push(edge.clone());
+        self.edges.push(edge);
+    }
+
+    fn remove_edges(&mut self, mut remove: impl FnMut(&Edge) -> bool) {
+        let (removed, kept): (Vec<Edge>, Vec<Edge>) = std::mem::take(&mut self.edges)
+            .into_iter()
+            .partition(|edge| remove(edge));
+        self.edges = kept;
+        for edge in removed {
+            if let Some(outputs) = self.node_outputs.get_mut(&edge.from.node) {
+                outputs.retain(|output| *output != edge);
+            }
+            if let Some(inputs) = self.node_inputs.get_mut(&edge.to.node) {
+                inputs.retain(|input| *input != edge);
+            }
         }
-        self.node_outputs = new_output_edges;
-        self.node_inputs = new_input_edges;
-        self.node_depths = node_depths;
     }
 
     pub fn node_order(&self) -> &Vec<NodeKey> {
@@ -612,25 +858,333 @@ impl Graph {
         &self.node_inputs
     }
 
-    pub fn step(&mut self, sample_rate: f32) -> f32 {
-        let mut retn: f32 = 0.0;
-        for node_idx in &self.node_order {
-            self.nodes[*node_idx].borrow_mut().step(sample_rate);
-
-            // for ((_edge_out_idx, port_out_idx), (edge_in_idx, port_in_idx)) in
-            for edge in &self.node_outputs[node_idx] {
-                // Set the input of edge_in to the output value of edge_out
-                let out_val = self.nodes[*node_idx].borrow().get(edge.from.port);
-                self.nodes[edge.to.node]
-                    .borrow_mut()
-                    .set(edge.to.port, out_val);
+    /// Prepares all nodes, including those in subgraphs, for `sample_rate`.
+    /// Nodes added later are prepared when they are added. The audio thread
+    /// never prepares, a graph is prepared before it is handed to it.
+    pub fn prepare(&mut self, sample_rate: f32, max_block: usize) {
+        for node in self.nodes.values() {
+            node.borrow_mut().prepare(sample_rate, max_block);
+        }
+        self.prepared = Some(PrepareInfo {
+            sample_rate,
+            max_block,
+        });
+    }
+
+    pub fn prepared(&self) -> Option<PrepareInfo> {
+        self.prepared
+    }
+
+    fn prepare_node(&self, node_key: NodeKey) {
+        if let Some(prepared) = self.prepared {
+            self.nodes[node_key]
+                .borrow_mut()
+                .prepare(prepared.sample_rate, prepared.max_block);
+        }
+    }
+
+    /// Renders `out.len()` samples of the output node, in blocks of at most
+    /// `SIGNAL_BLOCK` samples. Each node steps through a whole block before
+    /// the next node does, reading its inputs from the signals of the nodes
+    /// before it.
+    pub fn process(&mut self, out: &mut [f32], sample_rate: f32) {
+        // Graphs that were never published have nothing to run
+        let Some(plan) = self.plan.take() else {
+            out.fill(0.0);
+            return;
+        };
+        for block in out.chunks_mut(SIGNAL_BLOCK) {
+            self.process_block(&plan, block, sample_rate);
+        }
+        self.plan = Some(plan);
+    }
+
+    fn process_block(&mut self, plan: &ExecutionPlan, out: &mut [f32], sample_rate: f32) {
+        let n_samples = out.len();
+        let profiling = profiling();
+        let (states, outputs) = self.nodes.states_mut();
+        // Profiling times every node on its own, serially
+        if parallel() && !profiling && has_wide_layers(plan) {
+            step_layers(states, outputs, plan, n_samples, sample_rate);
+        } else {
+            for (idx, step) in plan.steps.iter().enumerate() {
+                let started = profiling.then(Instant::now);
+                let (sources, rest) = outputs.split_at_mut(idx);
+                step_block(
+                    &mut **states[idx].node.get_mut(),
+                    plan,
+                    step,
+                    sources,
+                    &mut rest[0],
+                    n_samples,
+                    sample_rate,
+                );
+                if let Some(started) = started {
+                    self.profiler.record(plan.order[idx], started.elapsed());
+                }
             }
         }
 
-        if let Some(output_node_key) = self.output_node {
-            retn = self.nodes[output_node_key].borrow().get(0);
+        for (idx, step) in plan.steps.iter().enumerate() {
+            for (slot, signal) in step.signals.clone().enumerate() {
+                let start = slot * SIGNAL_BLOCK;
+                self.activity
+                    .record_block(signal, &outputs[idx][start..start + n_samples]);
+            }
+        }
+        self.activity.end_samples(n_samples);
+
+        match plan.output {
+            Some(idx) => out.copy_from_slice(&outputs[idx][..n_samples]),
+            None => out.fill(0.0),
         }
+    }
 
-        retn
+    /// Steps every node once and returns the value of the output node
+    pub fn step(&mut self, sample_rate: f32) -> f32 {
+        let mut out = [0.0];
+        self.process(&mut out, sample_rate);
+        out[0]
+    }
+}
+
+// Steps a node through a block, setting its inputs from the signals of its
+// sources before every sample and storing its own signals after it
+pub(crate) fn step_block(
+    node: &mut dyn Node,
+    plan: &ExecutionPlan,
+    step: &PlanStep,
+    sources: &[Vec<f32>],
+    signals: &mut [f32],
+    n_samples: usize,
+    sample_rate: f32,
+) {
+    let inputs = &plan.inputs[step.inputs.clone()];
+    let ports = &plan.signals[step.signals.clone()];
+    for sample_idx in 0..n_samples {
+        for input in inputs {
+            let value = sources[input.source][input.slot * SIGNAL_BLOCK + sample_idx];
+            node.set(input.port, value);
+        }
+        node.step(sample_rate);
+        for (slot, port) in ports.iter().enumerate() {
+            signals[slot * SIGNAL_BLOCK + sample_idx] = node.get(port.port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(node: NodeKey, port: usize) -> Port {
+        Port {
+            node,
+            port,
+            kind: PortKind::Output,
+        }
+    }
+
+    fn input(node: NodeKey, port: usize) -> Port {
+        Port {
+            node,
+            port,
+            kind: PortKind::Input,
+        }
+    }
+
+    #[test]
+    fn paste_copies_the_edges_between_the_copied_nodes() {
+        let mut graph = Graph::new();
+        let bias = graph.add(Box::new(Bias::default()));
+        let scale = graph.add(Box::new(Scale::default()));
+        graph.connect(output(bias, 0), input(scale, 0));
+        graph.connect(output(scale, 0), input(graph.output_node.unwrap(), 0));
+        let clipboard = graph.copy_nodes(&[bias, scale]);
+        assert_eq!(clipboard.edges.len(), 1);
+
+        let mut target = Graph::new();
+        let pasted = target.paste(&clipboard);
+        assert_eq!(pasted.len(), 2);
+        assert_eq!(target.nodes.len(), 3);
+        assert_eq!(target.edges.len(), 1);
+        let edge = &target.edges[0];
+        assert!(pasted.contains(&edge.from.node) && pasted.contains(&edge.to.node));
+        assert_eq!(target.node_inputs[&edge.to.node].len(), 1);
+    }
+
+    #[test]
+    fn paste_skips_edges_between_missing_nodes_or_ports() {
+        let mut graph = Graph::new();
+        let bias = graph.add(Box::new(Bias::default()));
+        let mut clipboard = graph.copy_nodes(&[bias]);
+        let copied = clipboard.nodes.keys().next().unwrap();
+        // The clipboard holds a single node, the second key of the source
+        // graph is not one of its keys
+        assert!(!clipboard.has_node(bias));
+        // As if the clipboard text was edited or came from another version
+        clipboard.edges.push(Edge {
+            from: output(copied, 0),
+            to: input(bias, 0),
+        });
+        clipboard.edges.push(Edge {
+            from: output(copied, 3),
+            to: input(copied, 0),
+        });
+        clipboard.edges.push(Edge {
+            from: output(copied, 0),
+            to: input(copied, 9),
+        });
+
+        let mut target = Graph::new();
+        let pasted = target.paste(&clipboard);
+        assert_eq!(pasted.len(), 1);
+        assert!(target.edges.is_empty());
+        assert!(target.node_inputs[&pasted[0]].is_empty());
+        target.publish();
+        assert_eq!(target.step(44100.0), 0.0);
+    }
+
+    #[test]
+    fn edits_only_mark_the_graph_dirty_until_it_is_published() {
+        let mut graph = Graph::new();
+        let empty = graph.plan.clone().unwrap();
+        let osc = graph.add(Box::new(SineOsc {
+            freq: 440.0,
+            ..SineOsc::default()
+        }));
+        let bias = graph.add(Box::new(Bias::default()));
+        graph.connect(output(osc, 0), input(bias, 0));
+        assert!(graph.dirty);
+        // The audio thread runs the plan of the output node alone until then
+        assert_eq!(graph.plan.as_ref().unwrap().order.len(), 1);
+        assert!(Arc::ptr_eq(&empty, graph.plan.as_ref().unwrap()));
+
+        graph.publish();
+        assert!(!graph.dirty);
+        let plan = graph.plan.clone().unwrap();
+        assert_eq!(plan.order.len(), 3);
+        // Nothing changed, the audio thread keeps the plan it has
+        graph.publish();
+        assert!(Arc::ptr_eq(&plan, graph.plan.as_ref().unwrap()));
+
+        // Several edits build a single plan
+        graph.disconnect(output(osc, 0), input(bias, 0));
+        graph.connect(output(osc, 0), input(graph.output_node.unwrap(), 0));
+        assert!(graph.dirty);
+        assert!(Arc::ptr_eq(&plan, graph.plan.as_ref().unwrap()));
+        graph.publish();
+        let republished = graph.plan.clone().unwrap();
+        assert!(!Arc::ptr_eq(&plan, &republished));
+        assert_eq!(
+            republished.order[republished.output.unwrap()],
+            graph.output_node.unwrap()
+        );
+        // The sine starts at zero
+        graph.step(44100.0);
+        assert_ne!(graph.step(44100.0), 0.0);
+    }
+
+    #[test]
+    fn processing_blocks_matches_stepping_every_sample() {
+        let mut graph = Graph::new();
+        let osc = graph.add(Box::new(SawOsc {
+            freq: 220.0,
+            ..SawOsc::default()
+        }));
+        let lowpass = graph.add(Box::new(Lowpass::default()));
+        graph.connect(output(osc, 0), input(lowpass, 0));
+        graph.connect(output(lowpass, 0), input(graph.output_node.unwrap(), 0));
+        let patch = ron::to_string(&graph).unwrap();
+
+        let mut stepped: Graph = ron::from_str(&patch).unwrap();
+        let mut processed: Graph = ron::from_str(&patch).unwrap();
+        for graph in [&mut stepped, &mut processed] {
+            graph.publish();
+            graph.prepare(44100.0, DEFAULT_MAX_BLOCK);
+        }
+        // Spans several signal blocks, the last one partly
+        let n_samples = SIGNAL_BLOCK * 2 + 100;
+        let expected: Vec<f32> = (0..n_samples).map(|_| stepped.step(44100.0)).collect();
+        let mut out = vec![0.0; n_samples];
+        processed.process(&mut out, 44100.0);
+        assert!(out.iter().any(|sample| *sample != 0.0));
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn reload_keeps_the_state_of_unchanged_nodes() {
+        let mut running = Graph::new();
+        let osc = running.add(Box::new(SineOsc {
+            freq: 440.0,
+            ..SineOsc::default()
+        }));
+        let bias = running.add(Box::new(Bias::default()));
+        running.connect(output(osc, 0), input(running.output_node.unwrap(), 0));
+        running.publish();
+        let loaded_patch = ron::to_string(&running).unwrap();
+        for _ in 0..100 {
+            running.step(44100.0);
+        }
+        let running_phase = running
+            .get_node(osc)
+            .as_any()
+            .downcast_ref::<SineOsc>()
+            .unwrap()
+            .phase;
+        assert!(running_phase > 0.0);
+
+        // The file changed the bias only
+        let previous: Graph = ron::from_str(&loaded_patch).unwrap();
+        let mut reloaded: Graph = ron::from_str(&loaded_patch).unwrap();
+        reloaded.get_node_mut(bias).set(1, 1.0);
+        let unchanged = reloaded.unchanged_nodes(&previous);
+        assert!(unchanged.contains(&osc));
+        assert!(!unchanged.contains(&bias));
+
+        reloaded.sort();
+        let kept = reloaded.take_running_nodes(&mut running, &unchanged);
+        assert_eq!(kept, unchanged.len());
+        let node = reloaded.get_node(osc);
+        let reloaded_osc = node.as_any().downcast_ref::<SineOsc>().unwrap();
+        assert_eq!(reloaded_osc.phase, running_phase);
+        drop(node);
+        // The changed node has the value from the file
+        let reloaded_bias = reloaded
+            .get_node(bias)
+            .as_any()
+            .downcast_ref::<Bias>()
+            .unwrap()
+            .shift;
+        assert_eq!(reloaded_bias, 1.0);
+        assert_eq!(reloaded.steps, running.steps);
+    }
+
+    #[test]
+    fn old_patches_get_their_frequencies_in_hz() {
+        let mut graph = Graph::new();
+        let bias = graph.add(Box::new(Bias::default()));
+        let key = graph.add(Box::new(Key::default()));
+        let sine = graph.add(Box::new(SineOsc {
+            freq: 0.44,
+            ..SineOsc::default()
+        }));
+        let saw = graph.add(Box::new(SawOsc::default()));
+        let lowpass = graph.add(Box::new(Lowpass::default()));
+        graph.connect(output(bias, 0), input(sine, 0));
+        graph.connect(output(key, 0), input(saw, 0));
+        graph.connect(output(sine, 0), input(lowpass, 0));
+        graph.version = 0;
+
+        // Only the frequency driven by a constant is still in kHz
+        let edges = graph.khz_frequency_edges();
+        assert_eq!(edges.len(), 1);
+        assert!(edges[0].from.node == bias && edges[0].to.node == sine);
+
+        graph.sort();
+        assert_eq!(graph.version, GRAPH_VERSION);
+        let node = graph.get_node(sine);
+        let freq = node.as_any().downcast_ref::<SineOsc>().unwrap().freq;
+        assert!((freq - 440.0).abs() < 1e-3);
     }
 }
@@ -0,0 +1,215 @@
+use crate::graph::*;
+
+// Samples per block the levels are computed over
+pub const ACTIVITY_BLOCK: usize = 512;
+// Blocks of level history kept per port
+pub const ACTIVITY_HISTORY: usize = 48;
+const GATE_THRESHOLD: f32 = 0.5;
+const FLOOR_DB: f32 = -60.0;
+
+/// Signal level of an output port, updated by the audio thread once per block
+#[derive(Clone)]
+pub struct PortActivity {
+    pub rms: f32,
+    pub peak: f32,
+    // Blocks since the signal last rose above the gate threshold
+    pub gate_age: u32,
+    // Peak of each of the last blocks, oldest first
+    pub history: VecDeque<f32>,
+
+    sum_squares: f32,
+    samples: usize,
+    block_peak: f32,
+    prev: f32,
+    gate_rose: bool,
+}
+
+impl Default for PortActivity {
+    fn default() -> Self {
+        Self {
+            rms: 0.0,
+            peak: 0.0,
+            gate_age: u32::MAX,
+            history: VecDeque::from(vec![0.0; ACTIVITY_HISTORY]),
+            sum_squares: 0.0,
+            samples: 0,
+            block_peak: 0.0,
+            prev: 0.0,
+            gate_rose: false,
+        }
+    }
+}
+
+impl PortActivity {
+    fn record(&mut self, val: f32) {
+        self.sum_squares += val * val;
+        self.samples += 1;
+        self.block_peak = self.block_peak.max(val.abs());
+        if self.prev < GATE_THRESHOLD && val >= GATE_THRESHOLD {
+            self.gate_rose = true;
+        }
+        self.prev = val;
+    }
+
+    fn end_block(&mut self) {
+        self.rms = if self.samples > 0 {
+            (self.sum_squares / self.samples as f32).sqrt()
+        } else {
+            0.0
+        };
+        self.peak = self.block_peak;
+        self.gate_age = if self.gate_rose {
+            0
+        } else {
+            self.gate_age.saturating_add(1)
+        };
+        self.history.pop_front();
+        self.history.push_back(self.peak);
+        self.sum_squares = 0.0;
+        self.samples = 0;
+        self.block_peak = 0.0;
+        self.gate_rose = false;
+    }
+
+    /// Maps a level to 0..1 on a dB scale, for brightness
+    pub fn normalized(level: f32) -> f32 {
+        let db = 20.0 * level.max(1e-6).log10();
+        ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+    }
+}
+
+/// Levels of the connected output ports of a graph, one slot per signal of
+/// its execution plan
+#[derive(Default)]
+pub struct ActivityMeter {
+    ports: Vec<PortActivity>,
+    // Slot of each port, for the editor
+    lookup: HashMap<Port, usize>,
+    samples: usize,
+}
+
+impl ActivityMeter {
+    /// Gives the signals of a new plan their slots, keeping the levels of
+    /// ports that were already metered. Done when the plan is built, so the
+    /// audio thread only indexes.
+    pub fn update_signals(&mut self, signals: &[Port]) {
+        let mut previous = std::mem::take(&mut self.ports);
+        self.ports = signals
+            .iter()
+            .map(|port| match self.lookup.get(port) {
+                Some(slot) => std::mem::take(&mut previous[*slot]),
+                None => PortActivity::default(),
+            })
+            .collect();
+        self.lookup = signals
+            .iter()
+            .enumerate()
+            .map(|(slot, port)| (port.clone(), slot))
+            .collect();
+    }
+
+    /// Adds the values of a signal for a block of samples
+    pub fn record_block(&mut self, signal: usize, samples: &[f32]) {
+        if let Some(activity) = self.ports.get_mut(signal) {
+            for val in samples {
+                activity.record(*val);
+            }
+        }
+    }
+
+    /// Called after every signal was recorded for a block of `n_samples`
+    pub fn end_samples(&mut self, n_samples: usize) {
+        self.samples += n_samples;
+        if self.samples >= ACTIVITY_BLOCK {
+            self.samples = 0;
+            for activity in &mut self.ports {
+                activity.end_block();
+            }
+        }
+    }
+
+    pub fn get(&self, port: &Port) -> Option<&PortActivity> {
+        self.lookup.get(port).map(|slot| &self.ports[*slot])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(n: usize) -> Vec<Port> {
+        let mut keys = SlotMap::<NodeKey, ()>::with_key();
+        (0..n)
+            .map(|_| Port {
+                node: keys.insert(()),
+                port: 0,
+                kind: PortKind::Output,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn levels_are_computed_per_block() {
+        let ports = ports(1);
+        let mut meter = ActivityMeter::default();
+        meter.update_signals(&ports);
+        let square: Vec<f32> = (0..ACTIVITY_BLOCK)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        meter.record_block(0, &square[..ACTIVITY_BLOCK / 2]);
+        meter.end_samples(ACTIVITY_BLOCK / 2);
+        assert_eq!(meter.get(&ports[0]).unwrap().peak, 0.0);
+        meter.record_block(0, &square[ACTIVITY_BLOCK / 2..]);
+        meter.end_samples(ACTIVITY_BLOCK / 2);
+
+        let activity = meter.get(&ports[0]).unwrap();
+        assert_eq!(activity.rms, 1.0);
+        assert_eq!(activity.peak, 1.0);
+        assert_eq!(activity.gate_age, 0);
+        assert_eq!(activity.history.len(), ACTIVITY_HISTORY);
+        assert_eq!(activity.history.back(), Some(&1.0));
+    }
+
+    #[test]
+    fn new_signals_keep_the_levels_of_metered_ports() {
+        let ports = ports(3);
+        let mut meter = ActivityMeter::default();
+        meter.update_signals(&ports[..2]);
+        meter.record_block(1, &[0.5; ACTIVITY_BLOCK]);
+        meter.end_samples(ACTIVITY_BLOCK);
+
+        // Another plan orders the signals differently
+        meter.update_signals(&[ports[2].clone(), ports[1].clone()]);
+        assert!(meter.get(&ports[0]).is_none());
+        assert_eq!(meter.get(&ports[1]).unwrap().peak, 0.5);
+        assert_eq!(meter.get(&ports[2]).unwrap().peak, 0.0);
+        // Out of range slots are ignored
+        meter.record_block(5, &[1.0]);
+    }
+
+    #[test]
+    fn graphs_meter_their_signals() {
+        let mut graph = Graph::new();
+        let bias = graph.add(Box::new(Bias {
+            shift: 0.5,
+            ..Bias::default()
+        }));
+        let out = graph.output_node.unwrap();
+        let from = Port {
+            node: bias,
+            port: 0,
+            kind: PortKind::Output,
+        };
+        let to = Port {
+            node: out,
+            port: 0,
+            kind: PortKind::Input,
+        };
+        graph.connect(from.clone(), to);
+        graph.publish();
+        let mut buffer = vec![0.0; ACTIVITY_BLOCK];
+        graph.process(&mut buffer, 44100.0);
+        assert_eq!(graph.activity.get(&from).unwrap().peak, 0.5);
+        assert_eq!(buffer[ACTIVITY_BLOCK - 1], 0.5);
+    }
+}
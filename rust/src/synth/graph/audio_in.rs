@@ -0,0 +1,261 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+pub const INPUT_RING_SIZE: usize = 1 << 15;
+// How far behind the capture stream AudioIn reads, in samples
+pub const INPUT_LATENCY: usize = 2048;
+
+/// Single writer, many reader ring buffer, used to pass samples between the
+/// audio thread and other threads without locking. Readers keep their own
+/// read position.
+pub struct SampleRing {
+    data: Vec<AtomicU32>,
+    write: AtomicUsize,
+    // Rate of the pushed samples, 0 while the writer has not set it
+    sample_rate: AtomicU32,
+}
+
+impl SampleRing {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: (0..size).map(|_| AtomicU32::new(0)).collect(),
+            write: AtomicUsize::new(0),
+            sample_rate: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn push(&self, val: f32) {
+        let w = self.write.load(Ordering::Relaxed);
+        self.data[w % self.data.len()].store(val.to_bits(), Ordering::Relaxed);
+        self.write.store(w + 1, Ordering::Release);
+    }
+
+    pub fn push_slice(&self, vals: &[f32]) {
+        for val in vals {
+            self.push(*val);
+        }
+    }
+
+    pub fn write_pos(&self) -> usize {
+        self.write.load(Ordering::Acquire)
+    }
+
+    pub fn read(&self, pos: usize) -> f32 {
+        f32::from_bits(self.data[pos % self.data.len()].load(Ordering::Relaxed))
+    }
+}
+
+/// Filled by the capture stream of the audio backend, which sets the rate
+/// it captures at
+pub fn audio_input() -> &'static SampleRing {
+    static AUDIO_INPUT: OnceLock<SampleRing> = OnceLock::new();
+    AUDIO_INPUT.get_or_init(|| SampleRing::new(INPUT_RING_SIZE))
+}
+
+/// Filled with the final output by the audio callback, read by the spectrum
+/// analyzer
+pub fn audio_output() -> &'static SampleRing {
+    static AUDIO_OUTPUT: OnceLock<SampleRing> = OnceLock::new();
+    AUDIO_OUTPUT.get_or_init(|| SampleRing::new(INPUT_RING_SIZE))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioIn {
+    // Reads from this file instead of the capture device when set
+    pub file: SampleRef,
+
+    // input ports
+    pub gain: f32,
+
+    // internal
+    // Position in `audio_input()`, advanced by the capture to output rate ratio
+    #[serde(skip)]
+    pub read_pos: f64,
+    pub last_read: f32,
+    pub file_pos: f32,
+
+    // output ports
+    pub value: f32,
+}
+
+impl Default for AudioIn {
+    fn default() -> Self {
+        Self {
+            file: SampleRef::default(),
+            gain: 1.0,
+            read_pos: 0.0,
+            last_read: 0.0,
+            file_pos: 0.0,
+            value: 0.0,
+        }
+    }
+}
+
+impl AudioIn {
+    pub fn load(&mut self, path: &str) {
+        self.file = SampleRef::load(path);
+        self.file_pos = 0.0;
+    }
+
+    pub fn use_device(&mut self) {
+        self.file = SampleRef::default();
+    }
+
+    fn read_device(&mut self, sample_rate: f32) -> f32 {
+        self.read_ring(audio_input(), sample_rate)
+    }
+
+    fn read_ring(&mut self, ring: &SampleRing, sample_rate: f32) -> f32 {
+        let write_pos = ring.write_pos();
+        if write_pos == 0 {
+            // No capture stream running
+            return 0.0;
+        }
+        let behind = write_pos as f64 - self.read_pos;
+        if behind < 0.0 || behind > (ring.len() / 2) as f64 {
+            self.read_pos = write_pos.saturating_sub(INPUT_LATENCY) as f64;
+        }
+        // If capture is late the last sample is held
+        let pos = self.read_pos as usize;
+        if pos + 1 < write_pos {
+            let frac = (self.read_pos - pos as f64) as f32;
+            let (a, b) = (ring.read(pos), ring.read(pos + 1));
+            self.last_read = a + (b - a) * frac;
+            self.read_pos += match ring.sample_rate() {
+                0 => 1.0,
+                capture_rate => capture_rate as f64 / sample_rate as f64,
+            };
+        }
+        self.last_read
+    }
+
+    fn read_file(&mut self, sample_rate: f32) -> f32 {
+        let data = &self.file.data;
+        if data.is_empty() {
+            return 0.0;
+        }
+        let v = 0.5 * (data.read(0, self.file_pos) + data.read(1, self.file_pos));
+        self.file_pos += data.sample_rate / sample_rate;
+        if self.file_pos >= data.len() as f32 {
+            self.file_pos -= data.len() as f32;
+        }
+        v
+    }
+}
+
+#[typetag::serde]
+impl Node for AudioIn {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "AudioIn"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "gain")].into_iter().map(|t| t.into()).collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value")].into_iter().map(|t| t.into()).collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        valid_idx!(self.gain = val, idx, 1);
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        valid_idx!(&mut self.gain, idx, 1)
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        valid_idx!(self.value, idx, 1)
+    }
+
+    fn step(&mut self, sample_rate: f32) {
+        let v = if self.file.path.is_empty() {
+            self.read_device(sample_rate)
+        } else {
+            self.read_file(sample_rate)
+        };
+        self.value = self.gain * v;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // More than half the ring, so a new reader starts INPUT_LATENCY behind
+    const FILLED: usize = 20000;
+
+    fn ramp(ring: &SampleRing, n_samples: usize) {
+        for i in 0..n_samples {
+            ring.push(i as f32);
+        }
+    }
+
+    #[test]
+    fn reads_one_sample_per_step_at_the_same_rate() {
+        let ring = SampleRing::new(INPUT_RING_SIZE);
+        ring.set_sample_rate(48000);
+        ramp(&ring, FILLED);
+        let mut audio_in = AudioIn::default();
+        let first = audio_in.read_ring(&ring, 48000.0);
+        assert_eq!(first, (FILLED - INPUT_LATENCY) as f32);
+        assert_eq!(audio_in.read_ring(&ring, 48000.0), first + 1.0);
+    }
+
+    #[test]
+    fn resamples_by_the_capture_to_output_ratio() {
+        let ring = SampleRing::new(INPUT_RING_SIZE);
+        ring.set_sample_rate(24000);
+        ramp(&ring, FILLED);
+        let mut audio_in = AudioIn::default();
+        let values: Vec<f32> = (0..5).map(|_| audio_in.read_ring(&ring, 48000.0)).collect();
+        let first = values[0];
+        assert_eq!(
+            values,
+            vec![first, first + 0.5, first + 1.0, first + 1.5, first + 2.0]
+        );
+
+        ring.set_sample_rate(96000);
+        let before = audio_in.read_ring(&ring, 48000.0);
+        assert_eq!(audio_in.read_ring(&ring, 48000.0), before + 2.0);
+    }
+
+    #[test]
+    fn holds_the_last_sample_when_capture_is_late() {
+        let ring = SampleRing::new(INPUT_RING_SIZE);
+        ring.set_sample_rate(48000);
+        ramp(&ring, FILLED);
+        let mut audio_in = AudioIn::default();
+        for _ in 0..INPUT_LATENCY + 10 {
+            audio_in.read_ring(&ring, 48000.0);
+        }
+        assert_eq!(audio_in.read_ring(&ring, 48000.0), (FILLED - 2) as f32);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Compressor {
+    // input ports
+    pub input: f32,
+    // linear amplitude where compression starts
+    pub threshold: f32,
+    // 0 is no compression, 1 is limiting, the ratio is 1 / (1 - slope)
+    pub slope: f32,
+    pub attack: f32,
+    pub release: f32,
+    pub makeup: f32,
+
+    // internal
+    pub envelope: f32,
+
+    // output ports
+    pub value: f32,
+    pub gain: f32,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self {
+            input: 0.0,
+            threshold: 0.5,
+            slope: 0.75,
+            attack: 0.01,
+            release: 0.1,
+            makeup: 1.0,
+            envelope: 0.0,
+            value: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Node for Compressor {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Compressor"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![
+            (0, "input"),
+            (1, "threshold"),
+            (2, "slope"),
+            (3, "attack"),
+            (4, "release"),
+            (5, "makeup"),
+        ]
+        .into_iter()
+        .map(|t| t.into())
+        .collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value"), (1, "gain")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        match idx {
+            0 => self.input = val,
+            1 => self.threshold = val,
+            2 => self.slope = val,
+            3 => self.attack = val,
+            4 => self.release = val,
+            5 => self.makeup = val,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        match idx {
+            0 => &mut self.input,
+            1 => &mut self.threshold,
+            2 => &mut self.slope,
+            3 => &mut self.attack,
+            4 => &mut self.release,
+            5 => &mut self.makeup,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        match idx {
+            0 => self.value,
+            1 => self.gain,
+            _ => panic!("Invalid output id"),
+        }
+    }
+
+    fn step(&mut self, sample_rate: f32) {
+        let level = self.input.abs();
+        let time = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        let coef = (-1.0 / (time.max(0.0001) * sample_rate)).exp();
+        self.envelope = level + (self.envelope - level) * coef;
+
+        let threshold = self.threshold.max(0.0001);
+        self.gain = if self.envelope > threshold {
+            (threshold / self.envelope).powf(self.slope.clamp(0.0, 1.0))
+        } else {
+            1.0
+        };
+        self.value = self.input * self.gain * self.makeup;
+    }
+}
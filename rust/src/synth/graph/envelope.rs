@@ -1,8 +1,18 @@
 use crate::graph::*;
-use interp1d::*;
 use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+pub enum EnvelopeStage {
+    #[default]
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Envelope {
     // input ports
     pub attack: f32,
@@ -10,16 +20,26 @@ pub struct Envelope {
     pub sustain: f32,
     pub release: f32,
     pub trigger: f32,
+    // 0 is linear, 1 is a steep exponential (RC-like) segment
+    pub curve: f32,
+    pub velocity: f32,
+    // > 0.5 restarts the attack from zero, otherwise attack continues from the current level
+    pub retrigger: f32,
 
     pub input: f32,
 
     // internal
-    pub phase: f32,
-    pub release_phase: f32,
+    pub stage: EnvelopeStage,
+    // position within the current stage, 0..1
+    pub stage_pos: f32,
+    // level the current stage started from
+    pub stage_from: f32,
+    pub level: f32,
     pub old_trigger: f32,
 
     // output ports
     pub value: f32,
+    pub eoc: f32,
 }
 
 impl Default for Envelope {
@@ -29,36 +49,52 @@ impl Default for Envelope {
             decay: 0.1,
             release: 0.5,
             sustain: 0.3,
-            phase: 0.0,
-            release_phase: 0.0,
-            input: 0.0,
-            value: 0.0,
             trigger: 0.0,
+            curve: 0.0,
+            velocity: 1.0,
+            retrigger: 1.0,
+            input: 0.0,
+            stage: EnvelopeStage::Idle,
+            stage_pos: 0.0,
+            stage_from: 0.0,
+            level: 0.0,
             old_trigger: 0.0,
+            value: 0.0,
+            eoc: 0.0,
         }
     }
 }
 
+// Maps 0..1 to 0..1, linear for curve == 0 and increasingly
+// "fast start, slow finish" like a charging capacitor for curve > 0.
+fn shape(x: f32, curve: f32) -> f32 {
+    let k = curve.clamp(0.0, 1.0) * 8.0;
+    if k < 1e-3 {
+        x
+    } else {
+        (1.0 - (-k * x).exp()) / (1.0 - (-k).exp())
+    }
+}
+
 impl Envelope {
     fn env(&self) -> f32 {
-        let t1: f32 = 0.0;
-        let t2: f32 = self.attack;
-        let t3: f32 = t2 + self.decay;
-        let t4: f32 = t3 + 1.0;
-        //let t5: f32 = t4 + self.release;
-        //let x: Vec<f32> = vec![t1, t2, t3, t4, t5];
-        //let y: Vec<f32> = vec![0.0, 1.0, self.sustain, self.sustain, 0.0];
-        let x: Vec<f32> = vec![t1, t2, t3, t4];
-        let y: Vec<f32> = vec![0.0, 1.0, self.sustain, self.sustain];
-
-        let interpolator = Interp1d::new_sorted(x, y).unwrap();
+        self.level * self.velocity
+    }
 
-        let xr: Vec<f32> = vec![0.0, self.release, self.release + 1.0];
-        let yr: Vec<f32> = vec![1.0, 0.0, 0.0];
+    fn enter(&mut self, stage: EnvelopeStage) {
+        self.stage = stage;
+        self.stage_pos = 0.0;
+        self.stage_from = self.level;
+    }
 
-        let release_interpolator = Interp1d::new_sorted(xr, yr).unwrap();
-        let release = release_interpolator.interpolate(self.release_phase);
-        interpolator.interpolate(self.phase) * release
+    // Advances stage_pos for a segment of `duration` seconds, returns true when the segment is done
+    fn advance(&mut self, duration: f32, sample_rate: f32) -> bool {
+        if duration <= 0.0 {
+            self.stage_pos = 1.0;
+        } else {
+            self.stage_pos = (self.stage_pos + 1.0 / (duration * sample_rate)).min(1.0);
+        }
+        self.stage_pos >= 1.0
     }
 }
 
@@ -86,13 +122,16 @@ impl Node for Envelope {
             (3, "sustain"),
             (4, "release"),
             (5, "trigger"),
+            (6, "curve"),
+            (7, "velocity"),
+            (8, "retrigger"),
         ]
         .into_iter()
         .map(|t| t.into())
         .collect()
     }
     fn outputs(&self) -> Vec<OutputId> {
-        vec![(0, "V"), (1, "env")]
+        vec![(0, "V"), (1, "env"), (2, "eoc")]
             .into_iter()
             .map(|t| t.into())
             .collect()
@@ -106,6 +145,9 @@ impl Node for Envelope {
             3 => self.sustain,
             4 => self.release,
             5 => self.trigger,
+            6 => self.curve,
+            7 => self.velocity,
+            8 => self.retrigger,
             _ => panic!("Invalid idx"),
         }
     }
@@ -119,6 +161,9 @@ impl Node for Envelope {
             3 => self.sustain = val,
             4 => self.release = val,
             5 => self.trigger = val,
+            6 => self.curve = val,
+            7 => self.velocity = val,
+            8 => self.retrigger = val,
             _ => panic!("Invalid idx"),
         }
     }
@@ -131,6 +176,9 @@ impl Node for Envelope {
             3 => &mut self.sustain,
             4 => &mut self.release,
             5 => &mut self.trigger,
+            6 => &mut self.curve,
+            7 => &mut self.velocity,
+            8 => &mut self.retrigger,
             _ => panic!("Invalid input id"),
         }
     }
@@ -138,24 +186,170 @@ impl Node for Envelope {
     // Get value of output index idx
     fn get(&self, idx: usize) -> f32 {
         match idx {
-            0 => self.input * self.env(),
+            0 => self.value,
             1 => self.env(),
+            2 => self.eoc,
             _ => panic!("unknown output"),
         }
     }
 
     fn step(&mut self, sample_rate: f32) {
-        //self.value = f32::sin(2.0 * consts::PI * self.phase);
-        if self.trigger - self.old_trigger > 0.0 {
-            self.phase = 0.0;
-            self.release_phase = 0.0;
-        }
-        if self.trigger > 0.0 {
-            self.phase += 1.0 / sample_rate;
-        } else {
-            self.release_phase += 1.0 / sample_rate;
+        self.eoc = 0.0;
+        let gate = self.trigger > 0.0;
+        if gate && self.old_trigger <= 0.0 {
+            if self.retrigger > 0.5 {
+                self.level = 0.0;
+            }
+            self.enter(EnvelopeStage::Attack);
+        } else if !gate && self.stage != EnvelopeStage::Idle && self.stage != EnvelopeStage::Release
+        {
+            self.enter(EnvelopeStage::Release);
         }
         self.old_trigger = self.trigger;
-        // self.phase = self.phase % (2.0 * (self.attack + self.decay + 1.0 + self.release));
+
+        let sustain = self.sustain.clamp(0.0, 1.0);
+        match self.stage {
+            EnvelopeStage::Idle => {
+                self.level = 0.0;
+            }
+            EnvelopeStage::Attack => {
+                let done = self.advance(self.attack, sample_rate);
+                self.level =
+                    self.stage_from + (1.0 - self.stage_from) * shape(self.stage_pos, self.curve);
+                if done {
+                    self.level = 1.0;
+                    self.enter(EnvelopeStage::Decay);
+                }
+            }
+            EnvelopeStage::Decay => {
+                let done = self.advance(self.decay, sample_rate);
+                self.level = 1.0 + (sustain - 1.0) * shape(self.stage_pos, self.curve);
+                if done {
+                    self.enter(EnvelopeStage::Sustain);
+                }
+            }
+            EnvelopeStage::Sustain => {
+                self.level = sustain;
+            }
+            EnvelopeStage::Release => {
+                let done = self.advance(self.release, sample_rate);
+                self.level = self.stage_from * (1.0 - shape(self.stage_pos, self.curve));
+                if done {
+                    self.level = 0.0;
+                    self.enter(EnvelopeStage::Idle);
+                    self.eoc = 1.0;
+                }
+            }
+        }
+        self.value = self.input * self.env();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            attack: 0.01,
+            decay: 0.01,
+            sustain: 0.5,
+            release: 0.01,
+            input: 1.0,
+            ..Envelope::default()
+        }
+    }
+
+    fn run(envelope: &mut Envelope, n_samples: usize) {
+        for _ in 0..n_samples {
+            envelope.step(SAMPLE_RATE);
+        }
+    }
+
+    #[test]
+    fn goes_through_all_stages() {
+        let mut envelope = envelope();
+        envelope.trigger = 1.0;
+        envelope.step(SAMPLE_RATE);
+        assert_eq!(envelope.stage, EnvelopeStage::Attack);
+        // 10 samples of attack reach the peak
+        run(&mut envelope, 9);
+        assert_eq!(envelope.stage, EnvelopeStage::Decay);
+        assert_eq!(envelope.level, 1.0);
+        run(&mut envelope, 11);
+        assert_eq!(envelope.stage, EnvelopeStage::Sustain);
+        assert_eq!(envelope.value, 0.5);
+
+        envelope.trigger = 0.0;
+        envelope.step(SAMPLE_RATE);
+        assert_eq!(envelope.stage, EnvelopeStage::Release);
+        run(&mut envelope, 9);
+        assert_eq!(envelope.stage, EnvelopeStage::Idle);
+        assert_eq!(envelope.eoc, 1.0);
+        envelope.step(SAMPLE_RATE);
+        assert_eq!(envelope.eoc, 0.0);
+        assert_eq!(envelope.value, 0.0);
+    }
+
+    #[test]
+    fn release_during_attack_starts_from_the_current_level() {
+        let mut envelope = envelope();
+        envelope.trigger = 1.0;
+        run(&mut envelope, 5);
+        let level = envelope.level;
+        assert!(level > 0.0 && level < 1.0);
+        envelope.trigger = 0.0;
+        envelope.step(SAMPLE_RATE);
+        assert_eq!(envelope.stage, EnvelopeStage::Release);
+        assert_eq!(envelope.stage_from, level);
+        assert!(envelope.level < level);
+    }
+
+    #[test]
+    fn legato_continues_from_the_current_level() {
+        let mut envelope = envelope();
+        envelope.retrigger = 0.0;
+        envelope.trigger = 1.0;
+        run(&mut envelope, 30);
+        envelope.trigger = 0.0;
+        run(&mut envelope, 3);
+        let level = envelope.level;
+        envelope.trigger = 1.0;
+        envelope.step(SAMPLE_RATE);
+        assert_eq!(envelope.stage, EnvelopeStage::Attack);
+        assert!(envelope.level >= level);
+    }
+
+    #[test]
+    fn retrigger_restarts_from_zero() {
+        let mut envelope = envelope();
+        envelope.trigger = 1.0;
+        run(&mut envelope, 30);
+        envelope.trigger = 0.0;
+        envelope.step(SAMPLE_RATE);
+        envelope.trigger = 1.0;
+        envelope.step(SAMPLE_RATE);
+        assert_eq!(envelope.stage_from, 0.0);
+        assert!(envelope.level <= 0.1 + 1e-6);
+    }
+
+    #[test]
+    fn velocity_scales_the_output() {
+        let mut envelope = envelope();
+        envelope.velocity = 0.5;
+        envelope.trigger = 1.0;
+        run(&mut envelope, 30);
+        assert_eq!(envelope.get(1), 0.25);
+    }
+
+    #[test]
+    fn curves_keep_the_end_points() {
+        for curve in [0.0, 0.5, 1.0] {
+            assert_eq!(shape(0.0, curve), 0.0);
+            assert!((shape(1.0, curve) - 1.0).abs() < 1e-6);
+            assert!(shape(0.5, curve) >= 0.5 - 1e-6);
+        }
     }
 }
@@ -48,7 +48,7 @@ impl Default for Highpass {
             input: 0.0,
             prev: 0.0,
             prev_out: 0.0,
-            cutoff: 10.0,
+            cutoff: 10_000.0,
             value: 0.0,
             //buffer: VecDeque::new()
         }
@@ -103,6 +103,19 @@ impl Node for Highpass {
         valid_idx!(self.value, idx, 1)
     }
 
+    fn upgrade(&mut self, version: u32) {
+        if version < HZ_VERSION {
+            self.cutoff *= 1000.0;
+        }
+    }
+
+    fn input_range(&self, idx: usize) -> RangeInclusive<f32> {
+        match idx {
+            1 => FREQUENCY_RANGE,
+            _ => DEFAULT_INPUT_RANGE,
+        }
+    }
+
     fn step(&mut self, sample_rate: f32) {
         /*
         // void hp(float* buffer, size_t size, float cutoff) {
@@ -119,7 +132,7 @@ impl Node for Highpass {
         // }
          */
         let dt = 1.0 / sample_rate;
-        let rch = 1.0 / (2.0 * 3.14159 * self.cutoff * 1000.0);
+        let rch = 1.0 / (2.0 * std::f32::consts::PI * self.cutoff);
         let alpha = rch / (dt + rch);
         let v = alpha * self.prev_out + alpha * (self.input - self.prev);
         //if self.buffer.len() > 1 {
@@ -6,6 +6,8 @@ pub struct Key {
     // output ports
     pub pitch: f32,
     pub trigger: f32,
+    #[serde(default)]
+    pub velocity: f32,
     pub buff: VecDeque<f32>,
 }
 
@@ -29,7 +31,7 @@ impl Node for Key {
         Vec::new()
     }
     fn outputs(&self) -> Vec<OutputId> {
-        vec![(0, "pitch"), (1, "trigger")]
+        vec![(0, "pitch"), (1, "trigger"), (2, "velocity")]
             .into_iter()
             .map(|t| t.into())
             .collect()
@@ -43,6 +45,7 @@ impl Node for Key {
         match idx {
             0 => self.pitch,
             1 => self.trigger,
+            2 => self.velocity,
             _ => panic!("Invalid output id"),
         }
     }
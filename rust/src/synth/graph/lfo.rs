@@ -0,0 +1,293 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+use std::f32::consts;
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+pub enum LfoShape {
+    #[default]
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleHold,
+    SmoothRandom,
+}
+
+pub const LFO_SHAPES: [LfoShape; 6] = [
+    LfoShape::Sine,
+    LfoShape::Triangle,
+    LfoShape::Saw,
+    LfoShape::Square,
+    LfoShape::SampleHold,
+    LfoShape::SmoothRandom,
+];
+
+// Rate knob range in Hz
+pub const LFO_RATE_RANGE: RangeInclusive<f32> = 0.0..=20.0;
+
+// (label, length of one cycle in beats)
+pub const LFO_DIVISIONS: [(&str, f32); 10] = [
+    ("1/32", 0.125),
+    ("1/16", 0.25),
+    ("1/8T", 1.0 / 3.0),
+    ("1/8", 0.5),
+    ("1/4T", 2.0 / 3.0),
+    ("1/4", 1.0),
+    ("1/2", 2.0),
+    ("1 bar", 4.0),
+    ("2 bars", 8.0),
+    ("4 bars", 16.0),
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Lfo {
+    // input ports
+    // cycles per second, used when not tempo synced
+    pub rate: f32,
+    // phase offset, 0..1
+    pub phase_offset: f32,
+    // > 0.5 gives -1..1, otherwise 0..1
+    pub bipolar: f32,
+    // a rising edge restarts the cycle
+    pub reset: f32,
+
+    // settings
+    pub shape: LfoShape,
+    pub tempo_sync: bool,
+    pub bpm: f32,
+    // index into LFO_DIVISIONS
+    pub division: usize,
+
+    // internal
+    pub phase: f32,
+    pub prev_reset: f32,
+    pub seed: u32,
+    pub random_from: f32,
+    pub random_to: f32,
+
+    // output ports
+    pub value: f32,
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            phase_offset: 0.0,
+            bipolar: 1.0,
+            reset: 0.0,
+            shape: LfoShape::Sine,
+            tempo_sync: false,
+            bpm: 120.0,
+            division: 5,
+            phase: 0.0,
+            prev_reset: 0.0,
+            seed: fresh_seed(),
+            random_from: 0.0,
+            random_to: 0.0,
+            value: 0.0,
+        }
+    }
+}
+
+impl Lfo {
+    pub fn frequency(&self) -> f32 {
+        if self.tempo_sync {
+            let beats = LFO_DIVISIONS[self.division.min(LFO_DIVISIONS.len() - 1)].1;
+            // An external transport sets the tempo while it runs
+            let bpm = transport().state().map_or(self.bpm, |state| state.bpm);
+            bpm / 60.0 / beats
+        } else {
+            self.rate
+        }
+    }
+
+    fn next_cycle(&mut self) {
+        self.random_from = self.random_to;
+        self.random_to = next_random(&mut self.seed);
+    }
+
+    fn waveform(&self, phase: f32) -> f32 {
+        match self.shape {
+            LfoShape::Sine => f32::sin(2.0 * consts::PI * phase),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleHold => self.random_to,
+            LfoShape::SmoothRandom => {
+                let t = 0.5 - 0.5 * f32::cos(consts::PI * phase);
+                self.random_from + (self.random_to - self.random_from) * t
+            }
+        }
+    }
+}
+
+#[typetag::serde]
+impl Node for Lfo {
+    fn copy(&self) -> Box<dyn Node> {
+        let mut c = (*self).clone();
+        c.seed = fresh_seed();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Lfo"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "rate"), (1, "phase"), (2, "bipolar"), (3, "reset")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value")].into_iter().map(|t| t.into()).collect()
+    }
+    fn input_range(&self, idx: usize) -> RangeInclusive<f32> {
+        match idx {
+            0 => LFO_RATE_RANGE,
+            _ => DEFAULT_INPUT_RANGE,
+        }
+    }
+
+    fn read_input(&self, idx: usize) -> f32 {
+        match idx {
+            0 => self.rate,
+            1 => self.phase_offset,
+            2 => self.bipolar,
+            3 => self.reset,
+            _ => panic!("Invalid idx"),
+        }
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        match idx {
+            0 => self.rate = val,
+            1 => self.phase_offset = val,
+            2 => self.bipolar = val,
+            3 => self.reset = val,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        match idx {
+            0 => &mut self.rate,
+            1 => &mut self.phase_offset,
+            2 => &mut self.bipolar,
+            3 => &mut self.reset,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        valid_idx!(self.value, idx, 1)
+    }
+
+    fn step(&mut self, sample_rate: f32) {
+        if self.reset > 0.0 && self.prev_reset <= 0.0 {
+            self.phase = 0.0;
+            self.next_cycle();
+        }
+        self.prev_reset = self.reset;
+
+        let v = self.waveform((self.phase + self.phase_offset).rem_euclid(1.0));
+        self.value = if self.bipolar > 0.5 {
+            v
+        } else {
+            0.5 * (v + 1.0)
+        };
+
+        self.phase += self.frequency().max(0.0) / sample_rate;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            self.next_cycle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn lfo(shape: LfoShape) -> Lfo {
+        Lfo {
+            shape,
+            rate: 10.0,
+            ..Lfo::default()
+        }
+    }
+
+    #[test]
+    fn square_flips_every_half_cycle() {
+        let mut lfo = lfo(LfoShape::Square);
+        let values: Vec<f32> = (0..100)
+            .map(|_| {
+                lfo.step(SAMPLE_RATE);
+                lfo.value
+            })
+            .collect();
+        // 10 Hz at 1 kHz is 100 samples per cycle, the phase is summed up
+        // so the edges may be a sample off
+        assert!(values[1..49].iter().all(|value| *value == 1.0));
+        assert!(values[51..99].iter().all(|value| *value == -1.0));
+    }
+
+    #[test]
+    fn unipolar_stays_between_zero_and_one() {
+        let mut lfo = lfo(LfoShape::Sine);
+        lfo.bipolar = 0.0;
+        for _ in 0..1000 {
+            lfo.step(SAMPLE_RATE);
+            assert!((0.0..=1.0).contains(&lfo.value));
+        }
+    }
+
+    #[test]
+    fn reset_restarts_the_cycle() {
+        let mut lfo = lfo(LfoShape::Saw);
+        for _ in 0..30 {
+            lfo.step(SAMPLE_RATE);
+        }
+        lfo.set(3, 1.0);
+        lfo.step(SAMPLE_RATE);
+        assert_eq!(lfo.value, -1.0);
+    }
+
+    #[test]
+    fn tempo_sync_follows_the_division() {
+        let mut lfo = lfo(LfoShape::Sine);
+        lfo.tempo_sync = true;
+        lfo.bpm = 120.0;
+        // A quarter note at 120 bpm
+        lfo.division = 5;
+        assert_eq!(lfo.frequency(), 2.0);
+        // Out of range divisions use the longest one
+        lfo.division = 100;
+        assert_eq!(lfo.frequency(), 2.0 / 16.0);
+    }
+
+    #[test]
+    fn copies_get_their_own_random_sequence() {
+        let lfo = lfo(LfoShape::SampleHold);
+        let copy = lfo.copy();
+        let copy = copy.as_any().downcast_ref::<Lfo>().unwrap();
+        assert_ne!(lfo.seed, copy.seed);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+
+pub const SAFETY_CEILING: f32 = 0.98;
+pub const SAFETY_RELEASE: f32 = 0.05;
+
+// Peak limiter gain computer with instant attack and exponential release
+pub fn limiter_gain(gain: f32, x: f32, ceiling: f32, release: f32, sample_rate: f32) -> f32 {
+    let peak = x.abs();
+    let target = if peak > ceiling && peak > 0.0 {
+        ceiling / peak
+    } else {
+        1.0
+    };
+    if target < gain {
+        target
+    } else {
+        let coef = (-1.0 / (release.max(0.001) * sample_rate)).exp();
+        target + (gain - target) * coef
+    }
+}
+
+/// Limiter stage that sits between the graph and an audio backend.
+#[derive(Clone)]
+pub struct SafetyLimiter {
+    pub enabled: bool,
+    gain: f32,
+}
+
+impl Default for SafetyLimiter {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gain: 1.0,
+        }
+    }
+}
+
+impl SafetyLimiter {
+    pub fn process(&mut self, x: f32, sample_rate: f32) -> f32 {
+        if !x.is_finite() {
+            return 0.0;
+        }
+        if !self.enabled {
+            return x;
+        }
+        self.gain = limiter_gain(self.gain, x, SAFETY_CEILING, SAFETY_RELEASE, sample_rate);
+        (x * self.gain).clamp(-SAFETY_CEILING, SAFETY_CEILING)
+    }
+
+    // Current gain reduction, 1.0 means the limiter is idle
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Limiter {
+    // input ports
+    pub input: f32,
+    pub ceiling: f32,
+    pub release: f32,
+
+    // internal
+    pub gain: f32,
+
+    // output ports
+    pub value: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            input: 0.0,
+            ceiling: 1.0,
+            release: 0.1,
+            gain: 1.0,
+            value: 0.0,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Node for Limiter {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Limiter"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "input"), (1, "ceiling"), (2, "release")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value"), (1, "gain")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        match idx {
+            0 => self.input = val,
+            1 => self.ceiling = val,
+            2 => self.release = val,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        match idx {
+            0 => &mut self.input,
+            1 => &mut self.ceiling,
+            2 => &mut self.release,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        match idx {
+            0 => self.value,
+            1 => self.gain,
+            _ => panic!("Invalid output id"),
+        }
+    }
+
+    fn step(&mut self, sample_rate: f32) {
+        let ceiling = self.ceiling.max(0.0001);
+        self.gain = limiter_gain(self.gain, self.input, ceiling, self.release, sample_rate);
+        self.value = (self.input * self.gain).clamp(-ceiling, ceiling);
+    }
+}
@@ -46,7 +46,7 @@ impl Default for Lowpass {
         Self {
             input: 0.0,
             prev: 0.0,
-            cutoff: 10.0,
+            cutoff: 10_000.0,
             value: 0.0,
             //buffer: VecDeque::new()
         }
@@ -101,6 +101,19 @@ impl Node for Lowpass {
         valid_idx!(self.value, idx, 1)
     }
 
+    fn upgrade(&mut self, version: u32) {
+        if version < HZ_VERSION {
+            self.cutoff *= 1000.0;
+        }
+    }
+
+    fn input_range(&self, idx: usize) -> RangeInclusive<f32> {
+        match idx {
+            1 => FREQUENCY_RANGE,
+            _ => DEFAULT_INPUT_RANGE,
+        }
+    }
+
     fn step(&mut self, sample_rate: f32) {
         /*
         // void lp(float* buffer, size_t size, float cutoff) {
@@ -116,9 +129,9 @@ impl Node for Lowpass {
         //     std::copy(internal.begin(), internal.end(), buffer);
         // }
          */
-        let clamped_cutoff = self.cutoff.max(0.0001);
+        let clamped_cutoff = self.cutoff.max(0.1);
         let dt = 1.0 / sample_rate;
-        let rc = 1.0 / (2.0 * 3.14159 * clamped_cutoff * 1000.0);
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * clamped_cutoff);
         let alpha = dt / (dt + rc);
         let v = alpha * self.input + (1.0 - alpha) * self.prev;
         //if self.buffer.len() > 1 {
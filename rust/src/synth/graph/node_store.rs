@@ -0,0 +1,263 @@
+use crate::graph::*;
+use serde::de::Error;
+use serde::{Deserializer, Serializer};
+use std::ops::Index;
+
+/// The nodes of a graph, looked up by key like in a slot map but stored in a
+/// dense list that `arrange` puts in the order of the execution plan, so the
+/// audio thread walks it front to back. The output values stored for each
+/// node are kept apart from the nodes, so the threads stepping one layer can
+/// all read the outputs of the layers before it.
+/// Removed nodes stay in place until the next `arrange`, so the positions a
+/// published plan refers to remain valid until it is replaced.
+#[derive(Default)]
+pub struct NodeStore {
+    index: SlotMap<NodeKey, usize>,
+    states: Vec<NodeState>,
+    outputs: Vec<Vec<f32>>,
+}
+
+pub struct NodeState {
+    pub key: NodeKey,
+    pub node: RefCell<Box<dyn Node>>,
+    removed: bool,
+}
+
+impl NodeStore {
+    pub fn insert(&mut self, node: Box<dyn Node>) -> NodeKey {
+        let pos = self.states.len();
+        let key = self.index.insert(pos);
+        self.states.push(NodeState {
+            key,
+            node: RefCell::new(node),
+            removed: false,
+        });
+        self.outputs.push(Vec::new());
+        key
+    }
+
+    pub fn remove(&mut self, key: NodeKey) -> bool {
+        match self.index.remove(key) {
+            Some(pos) => {
+                self.states[pos].removed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.index.clear();
+        for state in &mut self.states {
+            state.removed = true;
+        }
+    }
+
+    pub fn contains_key(&self, key: NodeKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Position of the node in `states`, its index in the plan once arranged
+    pub fn position(&self, key: NodeKey) -> Option<usize> {
+        self.index.get(key).copied()
+    }
+
+    pub fn get(&self, key: NodeKey) -> Option<&RefCell<Box<dyn Node>>> {
+        self.position(key).map(|pos| &self.states[pos].node)
+    }
+
+    pub fn get_mut(&mut self, key: NodeKey) -> Option<&mut RefCell<Box<dyn Node>>> {
+        self.position(key).map(|pos| &mut self.states[pos].node)
+    }
+
+    // Live nodes in the order of their keys, as a slot map iterates
+    pub fn keys(&self) -> impl Iterator<Item = NodeKey> + '_ {
+        self.index.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeKey, &RefCell<Box<dyn Node>>)> {
+        self.index
+            .iter()
+            .map(|(key, pos)| (key, &self.states[*pos].node))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &RefCell<Box<dyn Node>>> {
+        self.iter().map(|(_, node)| node)
+    }
+
+    // In storage order, which is the plan order once arranged
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut RefCell<Box<dyn Node>>> {
+        self.states
+            .iter_mut()
+            .filter(|state| !state.removed)
+            .map(|state| &mut state.node)
+    }
+
+    /// Nodes and their stored outputs by position, for the audio thread
+    pub fn states_mut(&mut self) -> (&mut [NodeState], &mut [Vec<f32>]) {
+        (&mut self.states, &mut self.outputs)
+    }
+
+    /// Moves the nodes of `order` to the front in that order, followed by
+    /// the nodes left out of it, and drops removed nodes. `outputs` is the
+    /// number of values stored for each node of `order`.
+    pub fn arrange(&mut self, order: &[NodeKey], outputs: impl Iterator<Item = usize>) {
+        let mut states: Vec<Option<NodeState>> = std::mem::take(&mut self.states)
+            .into_iter()
+            .map(|state| (!state.removed).then_some(state))
+            .collect();
+        let mut old_outputs = std::mem::take(&mut self.outputs);
+        let mut arranged = Vec::with_capacity(self.index.len());
+        for key in order {
+            if let Some(state) = states[self.index[*key]].take() {
+                arranged.push(state);
+            }
+        }
+        arranged.extend(states.into_iter().flatten());
+        for (pos, state) in arranged.iter().enumerate() {
+            self.index[state.key] = pos;
+        }
+        self.states = arranged;
+        // Buffers are reused, their contents only live for one block
+        old_outputs.resize_with(self.states.len(), Vec::new);
+        old_outputs.truncate(self.states.len());
+        let mut sizes = outputs;
+        for buffer in &mut old_outputs {
+            buffer.clear();
+            buffer.resize(sizes.next().unwrap_or(0), 0.0);
+        }
+        self.outputs = old_outputs;
+    }
+}
+
+impl Index<NodeKey> for NodeStore {
+    type Output = RefCell<Box<dyn Node>>;
+
+    fn index(&self, key: NodeKey) -> &Self::Output {
+        &self.states[self.index[key]].node
+    }
+}
+
+// Patches store the nodes the way a slot map of them is serialized, so keys
+// in the edges keep pointing at the same nodes
+#[derive(Serialize, Deserialize)]
+struct SerdeSlot<T> {
+    value: Option<T>,
+    version: u32,
+}
+
+impl Serialize for NodeStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The versions of the slots are only exposed by serializing the index
+        let index: Vec<SerdeSlot<usize>> = serde_json::to_value(&self.index)
+            .and_then(serde_json::from_value)
+            .map_err(serde::ser::Error::custom)?;
+        let slots: Vec<SerdeSlot<&RefCell<Box<dyn Node>>>> = index
+            .into_iter()
+            .map(|slot| SerdeSlot {
+                value: slot.value.map(|pos| &self.states[pos].node),
+                version: slot.version,
+            })
+            .collect();
+        slots.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let slots: Vec<SerdeSlot<RefCell<Box<dyn Node>>>> = Deserialize::deserialize(deserializer)?;
+        let mut nodes = Vec::new();
+        let mut index = Vec::with_capacity(slots.len());
+        for slot in slots {
+            index.push(SerdeSlot {
+                value: slot.value.is_some().then_some(nodes.len()),
+                version: slot.version,
+            });
+            nodes.extend(slot.value);
+        }
+        // A slot map only takes given keys when it is deserialized
+        let index: SlotMap<NodeKey, usize> = serde_json::to_value(index)
+            .and_then(serde_json::from_value)
+            .map_err(D::Error::custom)?;
+        // Positions were handed out in slot order, which the keys come in
+        let states: Vec<NodeState> = index
+            .keys()
+            .zip(nodes)
+            .map(|(key, node)| NodeState {
+                key,
+                node,
+                removed: false,
+            })
+            .collect();
+        let outputs = states.iter().map(|_| Vec::new()).collect();
+        Ok(NodeStore {
+            index,
+            states,
+            outputs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bias(shift: f32) -> Box<dyn Node> {
+        Box::new(Bias {
+            shift,
+            ..Bias::default()
+        })
+    }
+
+    fn shift(store: &NodeStore, key: NodeKey) -> f32 {
+        let node = store[key].borrow();
+        node.as_any().downcast_ref::<Bias>().unwrap().shift
+    }
+
+    #[test]
+    fn arrange_puts_the_order_first_and_drops_removed_nodes() {
+        let mut store = NodeStore::default();
+        let a = store.insert(bias(1.0));
+        let b = store.insert(bias(2.0));
+        let c = store.insert(bias(3.0));
+        store.remove(b);
+        // Until then the plan still holding `b` can run
+        assert_eq!(store.states_mut().0.len(), 3);
+
+        store.arrange(&[c], [4].into_iter());
+        assert_eq!(store.position(c), Some(0));
+        assert_eq!(store.position(a), Some(1));
+        assert!(!store.contains_key(b));
+        let (states, outputs) = store.states_mut();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].key, c);
+        assert_eq!(outputs[0].len(), 4);
+        assert!(outputs[1].is_empty());
+        assert_eq!(shift(&store, a), 1.0);
+        assert_eq!(shift(&store, c), 3.0);
+    }
+
+    #[test]
+    fn keys_survive_a_round_trip() {
+        let mut store = NodeStore::default();
+        let a = store.insert(bias(1.0));
+        let b = store.insert(bias(2.0));
+        let c = store.insert(bias(3.0));
+        store.remove(b);
+        // Takes the slot of `b` with a newer version
+        let d = store.insert(bias(4.0));
+        store.arrange(&[d, c, a], std::iter::empty());
+
+        let text = ron::to_string(&store).unwrap();
+        let loaded: NodeStore = ron::from_str(&text).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert!(!loaded.contains_key(b));
+        for (key, value) in [(a, 1.0), (c, 3.0), (d, 4.0)] {
+            assert_eq!(shift(&loaded, key), value);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Noise {
+    // internal
+    pub seed: u32,
+    pub pink_state: [f32; 7],
+    pub brown_state: f32,
+
+    // output ports
+    pub white: f32,
+    pub pink: f32,
+    pub brown: f32,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            seed: fresh_seed(),
+            pink_state: [0.0; 7],
+            brown_state: 0.0,
+            white: 0.0,
+            pink: 0.0,
+            brown: 0.0,
+        }
+    }
+}
+
+// Xorshift32, returns a value in -1..1
+pub fn next_random(seed: &mut u32) -> f32 {
+    let mut x = *seed;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *seed = x;
+    (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+}
+
+// A seed that differs from every other one handed out, so copies of a node
+// don't produce the same sequence
+pub fn fresh_seed() -> u32 {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let mut x = COUNTER
+        .fetch_add(1, Ordering::Relaxed)
+        .wrapping_mul(0x9E37_79B9)
+        .wrapping_add(0x7F4A_7C15);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    // Xorshift never leaves zero
+    x.max(1)
+}
+
+#[typetag::serde]
+impl Node for Noise {
+    fn copy(&self) -> Box<dyn Node> {
+        let mut c = (*self).clone();
+        c.seed = fresh_seed();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Noise"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        Vec::new()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "white"), (1, "pink"), (2, "brown")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, _idx: usize, _val: f32) {}
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        match idx {
+            0 => self.white,
+            1 => self.pink,
+            2 => self.brown,
+            _ => panic!("Invalid output id"),
+        }
+    }
+
+    fn get_input_mut(&mut self, _idx: usize) -> &mut f32 {
+        panic!();
+    }
+
+    fn step(&mut self, _sample_rate: f32) {
+        let white = next_random(&mut self.seed);
+        self.white = white;
+
+        // Paul Kellet's refined pink noise filter
+        let b = &mut self.pink_state;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153_852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        self.pink = (b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362) * 0.11;
+        b[6] = white * 0.115926;
+
+        // Leaky integrator, gain compensated to roughly -1..1
+        self.brown_state = (self.brown_state + 0.02 * white) / 1.02;
+        self.brown = self.brown_state * 3.5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_stay_in_range() {
+        let mut noise = Noise::default();
+        for _ in 0..10_000 {
+            noise.step(48_000.0);
+            for port in 0..3 {
+                assert!(noise.get(port).abs() <= 1.5);
+            }
+        }
+    }
+
+    #[test]
+    fn copies_are_not_correlated() {
+        let mut noise = Noise::default();
+        let mut copy = noise.copy();
+        let mut same = 0;
+        for _ in 0..1000 {
+            noise.step(48_000.0);
+            copy.step(48_000.0);
+            if noise.get(0) == copy.get(0) {
+                same += 1;
+            }
+        }
+        assert!(same < 10);
+    }
+
+    #[test]
+    fn fresh_seeds_are_never_zero() {
+        for _ in 0..1000 {
+            assert_ne!(fresh_seed(), 0);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::graph::*;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+// Messages the editor can send before the audio thread takes them
+pub const NOTE_QUEUE_SIZE: usize = 256;
+
+/// MIDI note number to pitch in Hz
+pub fn note_to_hz(note: u8) -> f32 {
+    tone_to_hz(note as f32 - 69.0)
+}
+
+/// Single writer, single reader queue of three byte MIDI messages, used to
+/// play notes from another thread without locking. Messages that don't fit
+/// are dropped.
+pub struct MidiRing {
+    data: Vec<AtomicU32>,
+    write: AtomicUsize,
+    read: AtomicUsize,
+}
+
+impl MidiRing {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: (0..size).map(|_| AtomicU32::new(0)).collect(),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, message: [u8; 3]) -> bool {
+        let w = self.write.load(Ordering::Relaxed);
+        if w - self.read.load(Ordering::Acquire) >= self.data.len() {
+            return false;
+        }
+        let [status, data1, data2] = message;
+        self.data[w % self.data.len()].store(
+            u32::from_le_bytes([status, data1, data2, 0]),
+            Ordering::Relaxed,
+        );
+        self.write.store(w + 1, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<[u8; 3]> {
+        let r = self.read.load(Ordering::Relaxed);
+        if r == self.write.load(Ordering::Acquire) {
+            return None;
+        }
+        let [status, data1, data2, _] = self.data[r % self.data.len()]
+            .load(Ordering::Relaxed)
+            .to_le_bytes();
+        self.read.store(r + 1, Ordering::Release);
+        Some([status, data1, data2])
+    }
+}
+
+/// Notes from the piano in the editor, played by the audio callback with the
+/// MIDI it receives, so both share the same voices
+pub fn note_queue() -> &'static MidiRing {
+    static NOTE_QUEUE: OnceLock<MidiRing> = OnceLock::new();
+    NOTE_QUEUE.get_or_init(|| MidiRing::new(NOTE_QUEUE_SIZE))
+}
+
+/// Held notes and their voices. They are written to every `Key` and
+/// `VoiceKey` node in the graph, including nodes inside subgraphs. `Key`
+/// plays the most recent held note, `VoiceKey` gives each held note its own
+/// voice.
+pub struct Notes {
+    // Held notes and their velocities, oldest first
+    held: Vec<(u8, f32)>,
+    voices: [Option<(u8, f32)>; N_VOICES],
+    next_voice: usize,
+}
+
+impl Default for Notes {
+    fn default() -> Self {
+        Self {
+            // Room for every note, so holding one never allocates
+            held: Vec::with_capacity(128),
+            voices: [None; N_VOICES],
+            next_voice: 0,
+        }
+    }
+}
+
+impl Notes {
+    pub fn note_on(&mut self, graph: &mut Graph, note: u8, velocity: f32) {
+        if self.is_held(note) {
+            return;
+        }
+        self.held.push((note, velocity));
+        // A free voice if there is one, otherwise the oldest note is stolen
+        let voice = (0..N_VOICES)
+            .map(|i| (self.next_voice + i) % N_VOICES)
+            .find(|voice| self.voices[*voice].is_none())
+            .unwrap_or_else(|| {
+                let oldest = self
+                    .held
+                    .iter()
+                    .find(|held| self.voices.contains(&Some(**held)));
+                self.voices
+                    .iter()
+                    .position(|voice| *voice == oldest.copied())
+                    .unwrap_or(self.next_voice)
+            });
+        self.voices[voice] = Some((note, velocity));
+        self.next_voice = (voice + 1) % N_VOICES;
+        self.update_nodes(graph);
+    }
+
+    pub fn note_off(&mut self, graph: &mut Graph, note: u8) {
+        self.held.retain(|(n, _)| *n != note);
+        for voice in self.voices.iter_mut() {
+            if voice.is_some_and(|(n, _)| n == note) {
+                *voice = None;
+            }
+        }
+        self.update_nodes(graph);
+    }
+
+    pub fn is_held(&self, note: u8) -> bool {
+        self.held.iter().any(|(n, _)| *n == note)
+    }
+
+    pub fn all_notes_off(&mut self, graph: &mut Graph) {
+        self.held.clear();
+        self.voices = [None; N_VOICES];
+        self.update_nodes(graph);
+    }
+
+    /// Plays a raw MIDI message. Note on and off are used on any channel,
+    /// everything else but all notes off is ignored.
+    pub fn handle_midi(&mut self, graph: &mut Graph, message: &[u8]) {
+        match *message {
+            [status, note, velocity] if status & 0xF0 == 0x90 && velocity > 0 => {
+                self.note_on(graph, note, velocity as f32 / 127.0)
+            }
+            [status, note, _] if status & 0xF0 == 0x80 || status & 0xF0 == 0x90 => {
+                self.note_off(graph, note)
+            }
+            [status, 123, _] if status & 0xF0 == 0xB0 => self.all_notes_off(graph),
+            _ => {}
+        }
+    }
+
+    fn update_nodes(&self, graph: &mut Graph) {
+        let last = self.held.last().copied();
+        let voices = self.voices;
+        for_each_node(graph, &mut |node| {
+            if let Some(key) = node.as_any_mut().downcast_mut::<Key>() {
+                // The pitch is kept after the release so envelopes can finish
+                if let Some((note, velocity)) = last {
+                    key.pitch = note_to_hz(note);
+                    key.velocity = velocity;
+                }
+                key.trigger = if last.is_some() { 1.0 } else { 0.0 };
+            } else if let Some(voice_key) = node.as_any_mut().downcast_mut::<VoiceKey>() {
+                for (voice, note) in voices.iter().enumerate() {
+                    match note {
+                        Some((note, velocity)) => {
+                            voice_key.pitch[voice] = note_to_hz(*note);
+                            voice_key.velocity[voice] = *velocity;
+                            voice_key.trigger[voice] = 1.0;
+                        }
+                        None => voice_key.trigger[voice] = 0.0,
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Calls `f` for every node in the graph and its subgraphs
+fn for_each_node(graph: &mut Graph, f: &mut dyn FnMut(&mut dyn Node)) {
+    for node in graph.nodes.values_mut() {
+        let node = node.get_mut();
+        if let Some(subgraph) = node.as_any_mut().downcast_mut::<Subgraph>() {
+            for_each_node(&mut subgraph.subgraph, f);
+        } else {
+            f(&mut **node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midi_ring_keeps_the_order_and_drops_what_does_not_fit() {
+        let ring = MidiRing::new(2);
+        assert!(ring.push([0x90, 60, 100]));
+        assert!(ring.push([0x80, 60, 0]));
+        assert!(!ring.push([0x90, 62, 100]));
+        assert_eq!(ring.pop(), Some([0x90, 60, 100]));
+        assert!(ring.push([0x90, 64, 100]));
+        assert_eq!(ring.pop(), Some([0x80, 60, 0]));
+        assert_eq!(ring.pop(), Some([0x90, 64, 100]));
+        assert_eq!(ring.pop(), None);
+    }
+
+    fn voice_notes(notes: &Notes) -> Vec<Option<u8>> {
+        notes
+            .voices
+            .iter()
+            .map(|voice| voice.map(|(note, _)| note))
+            .collect()
+    }
+
+    #[test]
+    fn the_oldest_held_note_loses_its_voice() {
+        let mut graph = Graph::new();
+        let mut notes = Notes::default();
+        for note in 60..60 + N_VOICES as u8 {
+            notes.note_on(&mut graph, note, 1.0);
+        }
+        notes.note_on(&mut graph, 70, 1.0);
+        let mut expected: Vec<Option<u8>> = (60..60 + N_VOICES as u8).map(Some).collect();
+        expected[0] = Some(70);
+        assert_eq!(voice_notes(&notes), expected);
+
+        // A released voice is taken before anything is stolen
+        notes.note_off(&mut graph, 62);
+        notes.note_on(&mut graph, 72, 1.0);
+        expected[2] = Some(72);
+        assert_eq!(voice_notes(&notes), expected);
+
+        // Notes that are already held don't take another voice
+        notes.note_on(&mut graph, 72, 1.0);
+        assert_eq!(voice_notes(&notes), expected);
+    }
+
+    #[test]
+    fn midi_messages_reach_the_key_nodes() {
+        let mut graph = Graph::new();
+        let key = graph.add(Box::new(Key::default()));
+        let voice_key = graph.add(Box::new(VoiceKey::default()));
+        let mut notes = Notes::default();
+        notes.handle_midi(&mut graph, &[0x91, 69, 127]);
+        {
+            let node = graph.get_node(key);
+            let key = node.as_any().downcast_ref::<Key>().unwrap();
+            assert_eq!(key.pitch, 440.0);
+            assert_eq!(key.trigger, 1.0);
+            assert_eq!(key.velocity, 1.0);
+            let node = graph.get_node(voice_key);
+            let voice_key = node.as_any().downcast_ref::<VoiceKey>().unwrap();
+            assert_eq!(voice_key.trigger[0], 1.0);
+            assert_eq!(voice_key.pitch[0], 440.0);
+        }
+
+        // A note on with velocity 0 is a note off, the pitch stays
+        notes.handle_midi(&mut graph, &[0x90, 69, 0]);
+        let node = graph.get_node(key);
+        let key = node.as_any().downcast_ref::<Key>().unwrap();
+        assert_eq!(key.trigger, 0.0);
+        assert_eq!(key.pitch, 440.0);
+        assert!(!notes.is_held(69));
+    }
+}
@@ -64,9 +64,7 @@ impl Node for Out {
 
     // Get value of output index idx
     fn get(&self, idx: usize) -> f32 {
-        let out = self.value; //compress(self.value);
-                              //let out = self.value;
-        valid_idx!(out, idx, 1)
+        valid_idx!(self.value, idx, 1)
     }
 
     fn step(&mut self, _sample_rate: f32) {
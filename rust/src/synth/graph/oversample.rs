@@ -0,0 +1,106 @@
+use std::f32::consts::PI;
+
+pub const OVERSAMPLE_FACTORS: [usize; 4] = [1, 2, 4, 8];
+// Filter taps per sample at the outer rate, the latency of the up- and
+// downsampling together is about this many outer samples
+const TAPS_PER_PHASE: usize = 16;
+// Passband edge relative to the outer Nyquist frequency, the rest of the
+// band is the transition
+const PASSBAND: f32 = 0.9;
+
+/// Interpolates the inputs of an oversampled graph and decimates its
+/// outputs. Both directions use the same windowed sinc lowpass, which cuts
+/// at the Nyquist frequency of the outer rate, so the partials that the inner
+/// graph makes above it are removed before they can alias.
+#[derive(Clone, Default)]
+pub struct Oversampler {
+    factor: usize,
+    filter: Vec<f32>,
+    // Recent outer samples of each input, newest first
+    inputs: Vec<Vec<f32>>,
+    // Recent inner samples of each output, a ring written at `output_pos`
+    outputs: Vec<Vec<f32>>,
+    output_pos: usize,
+}
+
+impl Oversampler {
+    pub fn new(factor: usize) -> Self {
+        let factor = factor.max(1);
+        let len = TAPS_PER_PHASE * factor;
+        let center = (len - 1) as f32 / 2.0;
+        // Cutoff in cycles per inner sample
+        let cutoff = PASSBAND * 0.5 / factor as f32;
+        let mut filter: Vec<f32> = (0..len)
+            .map(|i| {
+                let x = i as f32 - center;
+                let sinc = if x == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (2.0 * PI * cutoff * x).sin() / (PI * x)
+                };
+                let phase = 2.0 * PI * i as f32 / (len - 1) as f32;
+                let blackman = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+                sinc * blackman
+            })
+            .collect();
+        let sum: f32 = filter.iter().sum();
+        filter.iter_mut().for_each(|tap| *tap /= sum);
+        Self {
+            factor,
+            filter,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            output_pos: 0,
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// Takes the next outer sample of every input
+    pub fn push_inputs(&mut self, values: impl ExactSizeIterator<Item = f32>) {
+        self.inputs
+            .resize_with(values.len(), || vec![0.0; TAPS_PER_PHASE]);
+        for (history, value) in self.inputs.iter_mut().zip(values) {
+            history.rotate_right(1);
+            history[0] = value;
+        }
+    }
+
+    /// Value of `input` at inner sample `phase` of the current outer sample
+    pub fn upsampled(&self, input: usize, phase: usize) -> f32 {
+        // Zero stuffing leaves every factor-th tap, scaled back up by the
+        // factor
+        let history = &self.inputs[input];
+        let sum: f32 = history
+            .iter()
+            .enumerate()
+            .map(|(j, x)| self.filter[j * self.factor + phase] * x)
+            .sum();
+        sum * self.factor as f32
+    }
+
+    /// Takes the next inner sample of every output
+    pub fn push_outputs(&mut self, values: impl ExactSizeIterator<Item = f32>) {
+        let len = self.filter.len();
+        self.outputs.resize_with(values.len(), || vec![0.0; len]);
+        self.output_pos = (self.output_pos + 1) % len;
+        for (ring, value) in self.outputs.iter_mut().zip(values) {
+            ring[self.output_pos] = value;
+        }
+    }
+
+    /// Filtered value of `output` at the latest inner sample
+    pub fn downsampled(&self, output: usize) -> f32 {
+        let Some(ring) = self.outputs.get(output) else {
+            return 0.0;
+        };
+        let len = ring.len();
+        self.filter
+            .iter()
+            .enumerate()
+            .map(|(i, tap)| tap * ring[(self.output_pos + len - i) % len])
+            .sum()
+    }
+}
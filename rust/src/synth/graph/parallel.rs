@@ -0,0 +1,179 @@
+use crate::graph::*;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+// Layers with fewer nodes are stepped on their own, handing a single node to
+// another thread costs more than it saves
+const PARALLEL_MIN_LAYER: usize = 2;
+// Nor are layers whose nodes add up to less than this in `Node::cost`, waking
+// the workers takes longer than stepping them
+const PARALLEL_MIN_COST: u32 = 16;
+
+static PARALLEL: AtomicBool = AtomicBool::new(false);
+
+/// Turns parallel evaluation on or off for all graphs. Off by default, it
+/// only pays off for patches with several expensive branches. It is not real
+/// time safe: the audio callback hands layers to the worker pool and waits
+/// for them, so it can block on the locks of the pool and on workers that
+/// don't run at audio priority. Opt in with `SYNTH_PARALLEL` or the profiler
+/// menu.
+pub fn set_parallel(enabled: bool) {
+    PARALLEL.store(enabled, Ordering::Relaxed);
+}
+
+pub fn parallel() -> bool {
+    PARALLEL.load(Ordering::Relaxed)
+}
+
+/// Threads that step the nodes of a layer. A pool of its own, so the audio
+/// thread never waits behind work that others put on the global rayon pool.
+pub fn worker_pool() -> &'static rayon::ThreadPool {
+    static WORKER_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    WORKER_POOL.get_or_init(|| {
+        let threads = std::thread::available_parallelism().map_or(2, |threads| threads.get());
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|idx| format!("synth-worker-{}", idx))
+            .build()
+            .expect("Failed to start the synth worker pool")
+    })
+}
+
+/// Which layers of the plan are worth stepping on the worker pool, given the
+/// `Node::cost` of every node of its order
+pub(crate) fn parallel_layers(plan: &ExecutionPlan, costs: &[u32]) -> Vec<bool> {
+    plan.layers
+        .iter()
+        .map(|layer| {
+            layer.len() >= PARALLEL_MIN_LAYER
+                && costs[layer.clone()].iter().sum::<u32>() >= PARALLEL_MIN_COST
+        })
+        .collect()
+}
+
+/// Whether stepping the plan on the worker pool can gain anything
+pub(crate) fn has_wide_layers(plan: &ExecutionPlan) -> bool {
+    plan.parallel.iter().any(|parallel| *parallel)
+}
+
+/// Steps the nodes of a plan through a block layer by layer, the nodes of
+/// wide layers at the same time, such as the branches of the voices of a
+/// patch. The pool is entered once per block. A node only reads the signals
+/// of earlier layers, so the result is the same as stepping one node after
+/// the other.
+pub(crate) fn step_layers(
+    states: &mut [NodeState],
+    outputs: &mut [Vec<f32>],
+    plan: &ExecutionPlan,
+    n_samples: usize,
+    sample_rate: f32,
+) {
+    worker_pool().install(|| {
+        for (layer, parallel) in plan.layers.iter().zip(&plan.parallel) {
+            let (sources, rest) = outputs.split_at_mut(layer.start);
+            let sources = &*sources;
+            let signals = &mut rest[..layer.len()];
+            let states = &mut states[layer.clone()];
+            let steps = &plan.steps[layer.clone()];
+            let step_node =
+                |((state, signals), step): ((&mut NodeState, &mut Vec<f32>), &PlanStep)| {
+                    step_block(
+                        &mut **state.node.get_mut(),
+                        plan,
+                        step,
+                        sources,
+                        signals,
+                        n_samples,
+                        sample_rate,
+                    )
+                };
+            if *parallel {
+                states
+                    .par_iter_mut()
+                    .zip(signals.par_iter_mut())
+                    .zip(steps.par_iter())
+                    .for_each(step_node);
+            } else {
+                states
+                    .iter_mut()
+                    .zip(signals.iter_mut())
+                    .zip(steps)
+                    .for_each(step_node);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(node: NodeKey, port: usize) -> Port {
+        Port {
+            node,
+            port,
+            kind: PortKind::Output,
+        }
+    }
+
+    fn input(node: NodeKey, port: usize) -> Port {
+        Port {
+            node,
+            port,
+            kind: PortKind::Input,
+        }
+    }
+
+    // A sine feeding `n_branches` side by side nodes made by `branch`, the
+    // first of which is heard
+    fn branches(n_branches: usize, branch: impl Fn() -> Box<dyn Node>) -> Graph {
+        let mut graph = Graph::new();
+        let osc = graph.add(Box::new(SineOsc {
+            freq: 440.0,
+            ..SineOsc::default()
+        }));
+        for idx in 0..n_branches {
+            let node = graph.add(branch());
+            graph.connect(output(osc, 0), input(node, 0));
+            if idx == 0 {
+                graph.connect(output(node, 0), input(graph.output_node.unwrap(), 0));
+            }
+        }
+        graph.publish();
+        graph
+    }
+
+    #[test]
+    fn only_wide_and_costly_layers_are_parallel() {
+        let cheap = branches(8, || Box::new(Bias::default()));
+        assert!(!has_wide_layers(cheap.plan.as_ref().unwrap()));
+        // One reverb is costly but has nothing to share the layer with
+        let single = branches(1, || Box::new(Reverb::default()));
+        assert!(!has_wide_layers(single.plan.as_ref().unwrap()));
+
+        let costly = branches(4, || Box::new(Reverb::default()));
+        let plan = costly.plan.as_ref().unwrap();
+        assert_eq!(plan.parallel, vec![false, true, false]);
+        assert_eq!(plan.layers[1].len(), 4);
+    }
+
+    #[test]
+    fn parallel_output_matches_serial() {
+        let patch = ron::to_string(&branches(4, || Box::new(Reverb::default()))).unwrap();
+        let mut blocks = Vec::new();
+        for enabled in [true, false] {
+            let mut graph: Graph = ron::from_str(&patch).unwrap();
+            graph.publish();
+            graph.prepare(44100.0, 512);
+            let mut out = vec![0.0; 512];
+            set_parallel(enabled);
+            for _ in 0..8 {
+                graph.process(&mut out, 44100.0);
+            }
+            blocks.push(out);
+        }
+        assert!(blocks[0].iter().any(|sample| *sample != 0.0));
+        assert_eq!(blocks[0], blocks[1]);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::graph::*;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Weight of the newest block in the running averages
+const AVERAGE_WEIGHT: f32 = 0.05;
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Turns timing of node steps on or off for all graphs. Off by default, as
+/// reading the clock around every node step costs more than many nodes do.
+pub fn set_profiling(enabled: bool) {
+    PROFILING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn profiling() -> bool {
+    PROFILING.load(Ordering::Relaxed)
+}
+
+/// Time a node spends per block, including passing its outputs on
+#[derive(Clone, Default)]
+pub struct NodeTiming {
+    pub average: Duration,
+    pub peak: Duration,
+    block: Duration,
+}
+
+/// Node timings and DSP load of a graph, per block of samples
+#[derive(Default)]
+pub struct Profiler {
+    nodes: HashMap<NodeKey, NodeTiming>,
+    // Average time of a whole block
+    pub block_average: Duration,
+    // Processing time relative to the duration of the block, 1.0 is 100%
+    pub dsp_load: f32,
+    pub peak_load: f32,
+    // Blocks that took longer than their duration
+    pub underruns: u64,
+    pub blocks: u64,
+}
+
+fn running_average(average: Duration, value: Duration) -> Duration {
+    average.mul_f32(1.0 - AVERAGE_WEIGHT) + value.mul_f32(AVERAGE_WEIGHT)
+}
+
+impl Profiler {
+    pub fn record(&mut self, node_key: NodeKey, took: Duration) {
+        self.nodes.entry(node_key).or_default().block += took;
+    }
+
+    /// Closes a block of `frames` samples. `elapsed` is the time from when
+    /// the block was requested until it was done, so time spent waiting for
+    /// the graph counts towards underruns as well.
+    pub fn end_block(&mut self, elapsed: Duration, frames: usize, sample_rate: f32) {
+        let deadline = Duration::from_secs_f32(frames as f32 / sample_rate);
+        let load = elapsed.as_secs_f32() / deadline.as_secs_f32();
+        self.dsp_load = if self.blocks == 0 {
+            load
+        } else {
+            self.dsp_load * (1.0 - AVERAGE_WEIGHT) + load * AVERAGE_WEIGHT
+        };
+        self.peak_load = self.peak_load.max(load);
+        if elapsed > deadline {
+            self.underruns += 1;
+        }
+        self.block_average = running_average(self.block_average, elapsed);
+        self.end_node_block();
+    }
+
+    fn end_node_block(&mut self) {
+        for timing in self.nodes.values_mut() {
+            timing.average = if self.blocks == 0 {
+                timing.block
+            } else {
+                running_average(timing.average, timing.block)
+            };
+            timing.peak = timing.peak.max(timing.block);
+            timing.block = Duration::ZERO;
+        }
+        self.blocks += 1;
+    }
+
+    pub fn node(&self, node_key: NodeKey) -> Option<&NodeTiming> {
+        self.nodes.get(&node_key)
+    }
+
+    /// Share of the block time spent in the node, 0..1
+    pub fn node_share(&self, node_key: NodeKey) -> f32 {
+        let total: Duration = self.nodes.values().map(|timing| timing.average).sum();
+        match self.nodes.get(&node_key) {
+            Some(timing) if !total.is_zero() => timing.average.as_secs_f32() / total.as_secs_f32(),
+            _ => 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Profiler::default();
+    }
+}
+
+/// Node timings of a profiled graph, most expensive first
+pub struct ProfileSummary {
+    pub nodes: Vec<NodeProfileSummary>,
+    pub block_average: Duration,
+    pub dsp_load: f32,
+    pub peak_load: f32,
+    pub underruns: u64,
+    pub blocks: u64,
+}
+
+pub struct NodeProfileSummary {
+    pub node_key: NodeKey,
+    pub name: String,
+    pub average: Duration,
+    pub peak: Duration,
+    pub share: f32,
+}
+
+impl fmt::Display for ProfileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} blocks, dsp load {:.1}% (peak {:.1}%), {} underruns, {:?} per block",
+            self.blocks,
+            100.0 * self.dsp_load,
+            100.0 * self.peak_load,
+            self.underruns,
+            self.block_average
+        )?;
+        for node in &self.nodes {
+            writeln!(
+                f,
+                "{:>6.1}% {:>12?} (peak {:?}) {}",
+                100.0 * node.share,
+                node.average,
+                node.peak,
+                node.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Graph {
+    /// Closes a profiling block for this graph and the graphs of its
+    /// subgraphs
+    pub fn end_profile_block(&mut self, elapsed: Duration, frames: usize, sample_rate: f32) {
+        let nodes = &self.nodes;
+        self.profiler
+            .nodes
+            .retain(|node_key, _| nodes.contains_key(*node_key));
+        self.profiler.end_block(elapsed, frames, sample_rate);
+        for node in self.nodes.values() {
+            let mut node = node.borrow_mut();
+            if let Some(subgraph) = node.as_any_mut().downcast_mut::<Subgraph>() {
+                subgraph
+                    .subgraph
+                    .end_profile_block(elapsed, frames, sample_rate);
+            }
+        }
+    }
+
+    pub fn profile_summary(&self) -> ProfileSummary {
+        let profiler = &self.profiler;
+        let mut nodes: Vec<NodeProfileSummary> = profiler
+            .nodes
+            .iter()
+            .filter(|(node_key, _)| self.nodes.contains_key(**node_key))
+            .map(|(node_key, timing)| NodeProfileSummary {
+                node_key: *node_key,
+                name: self.get_node(*node_key).typetag_name().to_string(),
+                average: timing.average,
+                peak: timing.peak,
+                share: profiler.node_share(*node_key),
+            })
+            .collect();
+        nodes.sort_by_key(|node| std::cmp::Reverse(node.average));
+        ProfileSummary {
+            nodes,
+            block_average: profiler.block_average,
+            dsp_load: profiler.dsp_load,
+            peak_load: profiler.peak_load,
+            underruns: profiler.underruns,
+            blocks: profiler.blocks,
+        }
+    }
+
+    /// Runs the graph for `n_blocks` blocks with profiling on, as fast as
+    /// possible, and returns the timings. For finding expensive nodes without
+    /// an audio device.
+    pub fn run_profiled(
+        &mut self,
+        sample_rate: f32,
+        block_size: usize,
+        n_blocks: usize,
+    ) -> ProfileSummary {
+        let was_profiling = profiling();
+        set_profiling(true);
+        self.prepare(sample_rate, block_size);
+        self.profiler.reset();
+        let mut block = vec![0.0; block_size];
+        for _ in 0..n_blocks {
+            let started = Instant::now();
+            self.process(&mut block, sample_rate);
+            self.end_profile_block(started.elapsed(), block_size, sample_rate);
+        }
+        set_profiling(was_profiling);
+        self.profile_summary()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_lists_every_node_most_expensive_first() {
+        let mut graph = Graph::new();
+        let osc = graph.add(Box::new(SineOsc::default()));
+        let out = graph.output_node.unwrap();
+        graph.connect(
+            Port {
+                node: osc,
+                port: 0,
+                kind: PortKind::Output,
+            },
+            Port {
+                node: out,
+                port: 0,
+                kind: PortKind::Input,
+            },
+        );
+        graph.publish();
+        let summary = graph.run_profiled(48000.0, 64, 10);
+        assert_eq!(summary.blocks, 10);
+        assert_eq!(summary.nodes.len(), 2);
+        assert!(summary
+            .nodes
+            .windows(2)
+            .all(|pair| pair[0].average >= pair[1].average));
+        assert!(!profiling());
+    }
+}
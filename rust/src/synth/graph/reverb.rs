@@ -15,6 +15,16 @@ pub struct Reverb {
     pub value: f32,
 
     buffer: VecDeque<f32>,
+    // Tap distances in samples and the longest delay that is kept, set up by
+    // `prepare`
+    #[serde(skip)]
+    taps: Vec<usize>,
+    #[serde(skip)]
+    buffer_len: usize,
+    #[serde(skip)]
+    sample_rate: f32,
+    #[serde(skip)]
+    prepared_delay: f32,
 }
 
 impl Default for Reverb {
@@ -26,10 +36,31 @@ impl Default for Reverb {
             damp: 0.8,
             value: 0.0,
             buffer: VecDeque::new(),
+            taps: Vec::new(),
+            buffer_len: 0,
+            sample_rate: 0.0,
+            prepared_delay: 0.0,
         }
     }
 }
 
+impl Reverb {
+    // Refills the taps in place, `prepare` made room for all of them so a
+    // delay change doesn't allocate on the audio thread
+    fn update_taps(&mut self) {
+        // Tap i is delay / i seconds back. Tap 0 would be infinitely far
+        // back, so it never contributes but still counts in the damping.
+        self.taps.clear();
+        for i in 1..self.channels {
+            let tap = (self.sample_rate * self.delay / (i as f32)) as usize;
+            if tap < self.buffer_len {
+                self.taps.push(tap);
+            }
+        }
+        self.prepared_delay = self.delay;
+    }
+}
+
 #[typetag::serde]
 impl Node for Reverb {
     fn copy(&self) -> Box<dyn Node> {
@@ -73,10 +104,24 @@ impl Node for Reverb {
         valid_idx!(self.value, idx, 1)
     }
 
-    fn step(&mut self, sample_rate: f32) {
-        let deltas: Vec<usize> = (0..self.channels)
-            .map(|i| (sample_rate * self.delay / (i as f32)) as usize)
-            .collect();
+    // A delay line read per tap
+    fn cost(&self) -> u32 {
+        self.channels.max(1) as u32
+    }
+
+    fn prepare(&mut self, sample_rate: f32, _max_block: usize) {
+        self.sample_rate = sample_rate;
+        self.buffer_len = sample_rate as usize;
+        self.buffer.truncate(self.buffer_len);
+        self.buffer.reserve(self.buffer_len + 1 - self.buffer.len());
+        self.taps = Vec::with_capacity(self.channels.max(1) as usize);
+        self.update_taps();
+    }
+
+    fn step(&mut self, _sample_rate: f32) {
+        if self.delay != self.prepared_delay {
+            self.update_taps();
+        }
         /*
         for t in -max(delays)..0 {
             for d in delays {
@@ -90,13 +135,14 @@ impl Node for Reverb {
 
         */
         let mut v: f32 = self.input;
-        for d in deltas {
+        for d in &self.taps {
+            let d = *d;
             if d < self.buffer.len() {
                 v += self.damp / (self.channels as f32) * self.buffer[d];
             }
         }
         self.buffer.push_front(v);
-        if self.buffer.len() > sample_rate as usize {
+        if self.buffer.len() > self.buffer_len {
             self.buffer.pop_back();
         }
         self.value = v;
@@ -0,0 +1,295 @@
+use crate::graph::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Decoded audio file, one `Vec` per channel.
+#[derive(Default)]
+pub struct Sample {
+    pub channels: Vec<Vec<f32>>,
+    pub sample_rate: f32,
+}
+
+impl Sample {
+    pub fn load(path: &str) -> anyhow::Result<Sample> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let n_channels = spec.channels.max(1) as usize;
+        let interleaved: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let mut channels = vec![Vec::with_capacity(interleaved.len() / n_channels); n_channels];
+        for frame in interleaved.chunks_exact(n_channels) {
+            for (channel, v) in channels.iter_mut().zip(frame) {
+                channel.push(*v);
+            }
+        }
+        Ok(Sample {
+            channels,
+            sample_rate: spec.sample_rate as f32,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.first().map(|c| c.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Cubic Hermite interpolation at fractional frame position `pos`
+    pub fn read(&self, channel: usize, pos: f32) -> f32 {
+        let Some(data) = self
+            .channels
+            .get(channel.min(self.channels.len().saturating_sub(1)))
+        else {
+            return 0.0;
+        };
+        if data.is_empty() || pos < 0.0 {
+            return 0.0;
+        }
+        let i = pos.floor() as isize;
+        let t = pos - pos.floor();
+        let at = |idx: isize| -> f32 {
+            if idx < 0 || idx as usize >= data.len() {
+                0.0
+            } else {
+                data[idx as usize]
+            }
+        };
+        let (y0, y1, y2, y3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+        ((c3 * t + c2) * t + c1) * t + y1
+    }
+}
+
+/// A sample referenced by file name. Only the path is stored in the patch,
+/// the audio data is loaded again when the patch is deserialized.
+#[derive(Clone, Default)]
+pub struct SampleRef {
+    pub path: String,
+    pub data: Arc<Sample>,
+}
+
+impl SampleRef {
+    pub fn load(path: &str) -> Self {
+        let data = match Sample::load(path) {
+            Ok(sample) => sample,
+            Err(error) => {
+                println!("Couldn't load sample {}: {:?}", path, error);
+                Sample::default()
+            }
+        };
+        Self {
+            path: path.to_string(),
+            data: Arc::new(data),
+        }
+    }
+}
+
+impl Serialize for SampleRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.path.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SampleRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        if path.is_empty() {
+            Ok(SampleRef::default())
+        } else {
+            Ok(SampleRef::load(&path))
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sampler {
+    pub sample: SampleRef,
+
+    // input ports
+    pub trigger: f32,
+    // playback speed, 1.0 plays at the original pitch
+    pub pitch: f32,
+    // positions as fractions of the sample length
+    pub start: f32,
+    pub end: f32,
+    pub loop_start: f32,
+    pub loop_end: f32,
+    pub looping: f32,
+
+    // internal
+    pub position: f32,
+    pub playing: bool,
+    pub prev_trigger: f32,
+
+    // output ports
+    pub left: f32,
+    pub right: f32,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self {
+            sample: SampleRef::default(),
+            trigger: 0.0,
+            pitch: 1.0,
+            start: 0.0,
+            end: 1.0,
+            loop_start: 0.0,
+            loop_end: 1.0,
+            looping: 0.0,
+            position: 0.0,
+            playing: false,
+            prev_trigger: 0.0,
+            left: 0.0,
+            right: 0.0,
+        }
+    }
+}
+
+impl Sampler {
+    pub fn load(&mut self, path: &str) {
+        self.sample = SampleRef::load(path);
+        self.playing = false;
+    }
+
+    fn frame(&self, fraction: f32) -> f32 {
+        fraction.clamp(0.0, 1.0) * self.sample.data.len() as f32
+    }
+}
+
+#[typetag::serde]
+impl Node for Sampler {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Sampler"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![
+            (0, "trigger"),
+            (1, "pitch"),
+            (2, "start"),
+            (3, "end"),
+            (4, "loop_start"),
+            (5, "loop_end"),
+            (6, "loop"),
+        ]
+        .into_iter()
+        .map(|t| t.into())
+        .collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value"), (1, "left"), (2, "right"), (3, "playing")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+
+    fn read_input(&self, idx: usize) -> f32 {
+        match idx {
+            0 => self.trigger,
+            1 => self.pitch,
+            2 => self.start,
+            3 => self.end,
+            4 => self.loop_start,
+            5 => self.loop_end,
+            6 => self.looping,
+            _ => panic!("Invalid idx"),
+        }
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        match idx {
+            0 => self.trigger = val,
+            1 => self.pitch = val,
+            2 => self.start = val,
+            3 => self.end = val,
+            4 => self.loop_start = val,
+            5 => self.loop_end = val,
+            6 => self.looping = val,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        match idx {
+            0 => &mut self.trigger,
+            1 => &mut self.pitch,
+            2 => &mut self.start,
+            3 => &mut self.end,
+            4 => &mut self.loop_start,
+            5 => &mut self.loop_end,
+            6 => &mut self.looping,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        match idx {
+            0 => 0.5 * (self.left + self.right),
+            1 => self.left,
+            2 => self.right,
+            3 => {
+                if self.playing {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            _ => panic!("Invalid output id"),
+        }
+    }
+
+    fn step(&mut self, sample_rate: f32) {
+        if self.trigger > 0.0 && self.prev_trigger <= 0.0 && !self.sample.data.is_empty() {
+            self.position = self.frame(self.start);
+            self.playing = true;
+        }
+        self.prev_trigger = self.trigger;
+
+        if !self.playing {
+            self.left = 0.0;
+            self.right = 0.0;
+            return;
+        }
+
+        let data = &self.sample.data;
+        self.left = data.read(0, self.position);
+        self.right = data.read(1, self.position);
+
+        self.position += self.pitch.max(0.0) * data.sample_rate / sample_rate;
+
+        let loop_start = self.frame(self.loop_start);
+        let loop_end = self.frame(self.loop_end);
+        if self.looping > 0.5 && loop_end > loop_start {
+            if self.position >= loop_end {
+                self.position = loop_start + (self.position - loop_end) % (loop_end - loop_start);
+            }
+        } else if self.position >= self.frame(self.end) {
+            self.playing = false;
+        }
+    }
+}
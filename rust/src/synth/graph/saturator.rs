@@ -0,0 +1,83 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Saturator {
+    // input ports
+    pub input: f32,
+    pub drive: f32,
+    pub mix: f32,
+
+    // output ports
+    pub value: f32,
+}
+
+impl Default for Saturator {
+    fn default() -> Self {
+        Self {
+            input: 0.0,
+            drive: 0.2,
+            mix: 1.0,
+            value: 0.0,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Node for Saturator {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Saturator"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "input"), (1, "drive"), (2, "mix")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value")].into_iter().map(|t| t.into()).collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        match idx {
+            0 => self.input = val,
+            1 => self.drive = val,
+            2 => self.mix = val,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        match idx {
+            0 => &mut self.input,
+            1 => &mut self.drive,
+            2 => &mut self.mix,
+            _ => panic!("Invalid input id"),
+        }
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        valid_idx!(self.value, idx, 1)
+    }
+
+    fn step(&mut self, _sample_rate: f32) {
+        // tanh soft clip, normalized so that a full scale input stays at full scale
+        let gain = 1.0 + 10.0 * self.drive.max(0.0);
+        let wet = (gain * self.input).tanh() / gain.tanh();
+        let mix = self.mix.clamp(0.0, 1.0);
+        self.value = mix * wet + (1.0 - mix) * self.input;
+    }
+}
@@ -50,8 +50,18 @@ impl Node for SawOsc {
         valid_idx!(self.value, idx, 1)
     }
 
+    fn upgrade(&mut self, version: u32) {
+        if version < HZ_VERSION {
+            self.freq *= 1000.0;
+        }
+    }
+
+    fn input_range(&self, _idx: usize) -> RangeInclusive<f32> {
+        FREQUENCY_RANGE
+    }
+
     fn step(&mut self, sample_rate: f32) {
-        self.phase += (self.freq * 1000.0) / sample_rate;
+        self.phase += self.freq / sample_rate;
         self.phase = self.phase % 1.0;
         self.value = 0.5 * (self.phase - 0.5);
     }
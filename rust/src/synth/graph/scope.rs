@@ -0,0 +1,184 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+pub const SCOPE_TRACES: usize = 4;
+pub const SCOPE_RING_SIZE: usize = 1 << 16;
+
+/// Samples of the traces of a scope. Written by the audio thread and read by
+/// the editor without taking the graph lock.
+pub struct ScopeData {
+    pub traces: Vec<SampleRing>,
+    sample_rate: AtomicU32,
+    // Ring position of the sweep on display, kept for the hold-off
+    pub last_trigger: AtomicUsize,
+}
+
+impl ScopeData {
+    fn new() -> Self {
+        Self {
+            traces: (0..SCOPE_TRACES)
+                .map(|_| SampleRing::new(SCOPE_RING_SIZE))
+                .collect(),
+            sample_rate: AtomicU32::new(44_100.0f32.to_bits()),
+            last_trigger: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        f32::from_bits(self.sample_rate.load(Ordering::Relaxed))
+    }
+}
+
+/// Shared handle to the `ScopeData` of a scope. Copies of a scope node get
+/// their own data so they don't show each others signals.
+pub struct ScopeTap(pub Arc<ScopeData>);
+
+impl Default for ScopeTap {
+    fn default() -> Self {
+        Self(Arc::new(ScopeData::new()))
+    }
+}
+
+impl Clone for ScopeTap {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scope {
+    // input ports, one per trace
+    pub inputs: [f32; SCOPE_TRACES],
+
+    // settings
+    // trace the trigger listens to
+    pub trigger_trace: usize,
+    pub trigger_level: f32,
+    pub trigger_rising: bool,
+    // no new sweep starts until this many ms after the last one
+    pub hold_off: f32,
+    // length of a sweep in ms
+    pub timebase: f32,
+    // shows the latest samples when there is no trigger
+    pub free_run: bool,
+
+    // internal
+    #[serde(skip)]
+    pub tap: ScopeTap,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self {
+            inputs: [0.0; SCOPE_TRACES],
+            trigger_trace: 0,
+            trigger_level: 0.0,
+            trigger_rising: true,
+            hold_off: 0.0,
+            timebase: 20.0,
+            free_run: true,
+            tap: ScopeTap::default(),
+        }
+    }
+}
+
+impl Scope {
+    /// Start of the sweep to show, as a ring position. Looks for the latest
+    /// trigger crossing that leaves room for a whole sweep and respects the
+    /// hold-off. Returns `None` when there is nothing to show.
+    pub fn find_sweep(&self) -> Option<usize> {
+        let data = &self.tap.0;
+        let trace = &data.traces[self.trigger_trace.min(SCOPE_TRACES - 1)];
+        let sample_rate = data.sample_rate();
+        let sweep = self.sweep_len();
+        let write_pos = trace.write_pos();
+        if write_pos < sweep + 1 {
+            return None;
+        }
+        let newest_start = write_pos - sweep;
+        let oldest = write_pos.saturating_sub(SCOPE_RING_SIZE - 1);
+        let last_trigger = data.last_trigger.load(Ordering::Relaxed);
+        let hold_off = (self.hold_off.max(0.0) * sample_rate / 1000.0) as usize;
+        let earliest = (last_trigger + hold_off.max(1)).max(oldest + 1);
+
+        let crossed = |pos: usize| {
+            let (prev, cur) = (trace.read(pos - 1), trace.read(pos));
+            if self.trigger_rising {
+                prev < self.trigger_level && cur >= self.trigger_level
+            } else {
+                prev > self.trigger_level && cur <= self.trigger_level
+            }
+        };
+        if let Some(pos) = (earliest..=newest_start).rev().find(|pos| crossed(*pos)) {
+            data.last_trigger.store(pos, Ordering::Relaxed);
+            return Some(pos);
+        }
+        // Keep the last sweep while it is still in the ring
+        if last_trigger > oldest && last_trigger <= newest_start {
+            return Some(last_trigger);
+        }
+        if self.free_run {
+            Some(newest_start)
+        } else {
+            None
+        }
+    }
+
+    /// Length of a sweep in samples
+    pub fn sweep_len(&self) -> usize {
+        let sample_rate = self.tap.0.sample_rate();
+        ((self.timebase.max(0.01) * sample_rate / 1000.0) as usize).clamp(2, SCOPE_RING_SIZE / 2)
+    }
+}
+
+#[typetag::serde]
+impl Node for Scope {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "Scope"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "a"), (1, "b"), (2, "c"), (3, "d")]
+            .into_iter()
+            .map(|t| t.into())
+            .collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        Vec::new()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        valid_idx!(self.inputs[idx] = val, idx, SCOPE_TRACES);
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        valid_idx!(&mut self.inputs[idx], idx, SCOPE_TRACES)
+    }
+
+    // Get value of output index idx
+    fn get(&self, _idx: usize) -> f32 {
+        panic!("Scope has no outputs");
+    }
+
+    fn step(&mut self, sample_rate: f32) {
+        let data = &self.tap.0;
+        data.sample_rate
+            .store(sample_rate.to_bits(), Ordering::Relaxed);
+        for (trace, v) in data.traces.iter().zip(self.inputs) {
+            trace.push(v);
+        }
+    }
+}
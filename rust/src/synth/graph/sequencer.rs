@@ -1,22 +1,35 @@
 use crate::graph::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Note {
     pub active: bool,
     pub pitch: u8,
     pub octave: i32,
 }
 
+fn default_steps_per_beat() -> f32 {
+    4.0
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Sequencer {
     // input ports
     // pub tempo: f32,
     pub trigger_in: f32,
 
+    // settings
+    // Steps follow the transport position instead of trigger_in
+    #[serde(default)]
+    pub follow_transport: bool,
+    #[serde(default = "default_steps_per_beat")]
+    pub steps_per_beat: f32,
+
     // internal
     pub beat: usize,
     prev_trigger_in: f32,
+    #[serde(skip)]
+    follower: TransportFollower,
 
     // output ports
     pub trigger: f32,
@@ -31,8 +44,11 @@ impl Default for Sequencer {
         Self {
             // tempo: 1.0,
             trigger_in: 0.0,
+            follow_transport: false,
+            steps_per_beat: default_steps_per_beat(),
             prev_trigger_in: 0.0,
             beat: 0,
+            follower: TransportFollower::default(),
             trigger: 0.0,
             pitch: 1.0,
             sequence: vec![
@@ -110,7 +126,7 @@ impl Node for Sequencer {
         }
     }
 
-    fn step(&mut self, _sample_rate: f32) {
+    fn step(&mut self, sample_rate: f32) {
         // tempo is fraction of 200bpm
         // let bpm = self.tempo * 400.0;
         // 60 seconds per minute / bpm
@@ -124,25 +140,35 @@ impl Node for Sequencer {
 
         // let beat_idx = (local_phase / beat_length).floor() as usize;
         // self.beat = beat_idx;
-        if self.trigger_in > 0.0 && self.prev_trigger_in <= 0.0 {
+        let mut rolling = true;
+        if self.follow_transport {
+            match self.follower.step(sample_rate) {
+                Some(beats) => {
+                    let step = (beats * self.steps_per_beat.max(0.01) as f64).floor() as i64;
+                    self.beat = step.rem_euclid(self.sequence.len() as i64) as usize;
+                }
+                None => rolling = false,
+            }
+        } else if self.trigger_in > 0.0 && self.prev_trigger_in <= 0.0 {
             self.beat += 1;
             if self.beat >= self.sequence.len() {
                 self.beat = 0;
             }
         }
         self.prev_trigger_in = self.trigger_in;
-        self.trigger = if self.sequence[self.beat].active {
+        self.trigger = if rolling && self.sequence[self.beat].active {
             1.0
         } else {
             0.0
         };
-        self.pitch = tone_to_khz(
+        self.pitch = tone_to_hz(
             (self.sequence[self.beat].pitch as i32 + 12 * (self.sequence[self.beat].octave - 4) - 9)
                 as f32,
         );
     }
 }
 
-pub fn tone_to_khz(x: f32) -> f32 {
-    0.44 * 2.0_f32.powf(x / 12.0)
+/// Semitones from A4 to pitch in Hz, the unit of all frequency ports
+pub fn tone_to_hz(x: f32) -> f32 {
+    440.0 * 2.0_f32.powf(x / 12.0)
 }
@@ -51,9 +51,19 @@ impl Node for SineOsc {
         valid_idx!(self.value, idx, 1)
     }
 
+    fn upgrade(&mut self, version: u32) {
+        if version < HZ_VERSION {
+            self.freq *= 1000.0;
+        }
+    }
+
+    fn input_range(&self, _idx: usize) -> RangeInclusive<f32> {
+        FREQUENCY_RANGE
+    }
+
     fn step(&mut self, sample_rate: f32) {
         self.value = f32::sin(2.0 * consts::PI * self.phase);
-        self.phase += (self.freq * 1000.0) / sample_rate;
+        self.phase += self.freq / sample_rate;
         self.phase = self.phase % 1.0;
     }
 }
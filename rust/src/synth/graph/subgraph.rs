@@ -1,16 +1,55 @@
 use crate::graph::*;
 use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use symbol_table::GlobalSymbol;
 
 // use lolmacros::Wiretap;
 
+/// Edges that crossed the boundary of a group of nodes moved into a
+/// `Subgraph`, with the subgraph side given as its port index.
+pub struct CrossingEdges {
+    pub inputs: Vec<(Port, usize)>,
+    pub outputs: Vec<(usize, Port)>,
+}
+
+/// A linked patch file read from disk
+pub struct LinkedPatch {
+    pub modified: Option<SystemTime>,
+    pub graph: Graph,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Subgraph {
+    #[serde(default)]
+    pub name: String,
+    // Patch file the subgraph is linked to. The inner graph is still saved
+    // with the parent and used if the file can't be read.
+    #[serde(default)]
+    pub link: Option<String>,
+    #[serde(skip)]
+    pub link_modified: Option<SystemTime>,
     pub subgraph: Graph,
     pub inputs: Vec<UnconnectedInput>,
     pub outputs: Vec<UnconnectedOutput>,
+    // Runs the inner graph at this many times the outer rate, one of
+    // OVERSAMPLE_FACTORS
+    #[serde(default = "default_oversample")]
+    pub oversample: usize,
+    // With oversampling the ports are read and written here, the inner graph
+    // gets filtered values once per inner sample
+    #[serde(skip)]
+    oversampler: Oversampler,
+    #[serde(skip)]
+    input_values: Vec<f32>,
+    #[serde(skip)]
+    output_values: Vec<f32>,
     // output ports
 }
 
+fn default_oversample() -> usize {
+    1
+}
+
 impl Default for Subgraph {
     fn default() -> Self {
         Subgraph::new()
@@ -20,33 +59,429 @@ impl Default for Subgraph {
 impl Subgraph {
     fn new() -> Self {
         let mut sg = Self {
+            name: String::new(),
+            link: None,
+            link_modified: None,
             subgraph: Graph::new(),
             inputs: Vec::new(),
             outputs: Vec::new(),
+            oversample: default_oversample(),
+            oversampler: Oversampler::default(),
+            input_values: Vec::new(),
+            output_values: Vec::new(),
         };
         // sg.inputs = sg.subgraph.get_unconnected_inputs();
         // sg.outputs = sg.subgraph.get_unconnected_outputs();
         sg
     }
+    /// Copies `node_keys` into a new subgraph. Every edge between the group
+    /// and the rest of `graph` gets a port node in the subgraph.
+    pub fn from_nodes(graph: &Graph, node_keys: &[NodeKey]) -> (Subgraph, CrossingEdges) {
+        let (mut inner, node_lookup) = graph.copy_nodes_with_lookup(node_keys);
+        let mut names: Vec<String> = Vec::new();
+        let mut unique_name = |name: &str| {
+            let mut unique = name.to_string();
+            let mut n = 1;
+            while names.contains(&unique) {
+                n += 1;
+                unique = format!("{}_{}", name, n);
+            }
+            names.push(unique.clone());
+            unique
+        };
+        let mut input_names: Vec<(Port, String)> = Vec::new();
+        let mut output_names: Vec<(String, Port)> = Vec::new();
+        for node_key in node_keys {
+            for edge in &graph.node_inputs()[node_key] {
+                if node_lookup.contains_key(&edge.from.node) {
+                    continue;
+                }
+                let to = Port {
+                    node: node_lookup[&edge.to.node],
+                    port: edge.to.port,
+                    kind: PortKind::Input,
+                };
+                let name = unique_name(graph.get_node(edge.to.node).inputs()[edge.to.port].name);
+                let marker = SubgraphInput {
+                    name: name.clone(),
+                    input: inner.get_node_mut(to.node).get_input(to.port),
+                    value: 0.0,
+                };
+                let marker_key = inner.add(Box::new(marker));
+                if let Some(pos) = inner.positions.get(&to.node).copied() {
+                    inner.positions.insert(marker_key, [pos[0], pos[1] - 100.0]);
+                }
+                let from = Port {
+                    node: marker_key,
+                    port: 0,
+                    kind: PortKind::Output,
+                };
+                inner.connect(from, to);
+                input_names.push((edge.from.clone(), name));
+            }
+        }
+        let mut output_markers: HashMap<Port, String> = HashMap::new();
+        for node_key in node_keys {
+            for edge in &graph.node_outputs()[node_key] {
+                if node_lookup.contains_key(&edge.to.node) {
+                    continue;
+                }
+                let from = Port {
+                    node: node_lookup[&edge.from.node],
+                    port: edge.from.port,
+                    kind: PortKind::Output,
+                };
+                // One output may feed several nodes outside the group
+                if let Some(name) = output_markers.get(&from) {
+                    output_names.push((name.clone(), edge.to.clone()));
+                    continue;
+                }
+                let name =
+                    unique_name(graph.get_node(edge.from.node).outputs()[edge.from.port].name);
+                let marker = SubgraphOutput {
+                    name: name.clone(),
+                    input: 0.0,
+                    value: 0.0,
+                };
+                let marker_key = inner.add(Box::new(marker));
+                if let Some(pos) = inner.positions.get(&from.node).copied() {
+                    inner.positions.insert(marker_key, [pos[0], pos[1] + 100.0]);
+                }
+                let to = Port {
+                    node: marker_key,
+                    port: 0,
+                    kind: PortKind::Input,
+                };
+                inner.connect(from.clone(), to);
+                output_markers.insert(from, name.clone());
+                output_names.push((name, edge.to.clone()));
+            }
+        }
+
+        let mut sg = Self {
+            name: String::from("group"),
+            link: None,
+            link_modified: None,
+            subgraph: inner,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            oversample: default_oversample(),
+            oversampler: Oversampler::default(),
+            input_values: Vec::new(),
+            output_values: Vec::new(),
+        };
+        sg.update_interface();
+        let crossing = CrossingEdges {
+            inputs: input_names
+                .into_iter()
+                .filter_map(|(from, name)| sg.input_idx(&name).map(|idx| (from, idx)))
+                .collect(),
+            outputs: output_names
+                .into_iter()
+                .filter_map(|(name, to)| sg.output_idx(&name).map(|idx| (idx, to)))
+                .collect(),
+        };
+        (sg, crossing)
+    }
+
+    pub fn input_idx(&self, name: &str) -> Option<usize> {
+        self.inputs.iter().position(|i| i.name.as_str() == name)
+    }
+
+    pub fn output_idx(&self, name: &str) -> Option<usize> {
+        self.outputs.iter().position(|o| o.name.as_str() == name)
+    }
+
+    /// Collects the ports of the subgraph from its `SubgraphInput` and
+    /// `SubgraphOutput` nodes. Patches without those expose every
+    /// unconnected port of the inner graph instead.
+    pub fn update_interface(&mut self) {
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        for (node_key, node) in self.subgraph.nodes.iter() {
+            let node = node.borrow();
+            if let Some(input) = node.as_any().downcast_ref::<SubgraphInput>() {
+                inputs.push(UnconnectedInput {
+                    node_key,
+                    port_idx: 0,
+                    name: input.name.as_str().into(),
+                });
+            } else if let Some(output) = node.as_any().downcast_ref::<SubgraphOutput>() {
+                outputs.push(UnconnectedOutput {
+                    node_key,
+                    port_idx: 0,
+                    name: output.name.as_str().into(),
+                });
+            }
+        }
+        if inputs.is_empty() && outputs.is_empty() {
+            inputs = self.subgraph.get_unconnected_inputs();
+            outputs = self.subgraph.get_unconnected_outputs();
+        }
+        self.inputs = inputs;
+        self.outputs = outputs;
+    }
+
+    /// Updates the interface of the subgraph node `node_key` in `graph`
+    /// after its inner graph changed. Edges to the subgraph follow their
+    /// port by name, or by port node when it was renamed. Edges to ports
+    /// that no longer exist are removed.
+    pub fn refresh(graph: &mut Graph, node_key: NodeKey) {
+        let (old_inputs, old_outputs, new_inputs, new_outputs) = {
+            let mut node = graph.get_node_mut(node_key);
+            let Some(sg) = node.as_any_mut().downcast_mut::<Subgraph>() else {
+                return;
+            };
+            let old_inputs: Vec<_> = sg.inputs.iter().map(|i| (i.name, i.node_key)).collect();
+            let old_outputs: Vec<_> = sg.outputs.iter().map(|o| (o.name, o.node_key)).collect();
+            sg.update_interface();
+            let new_inputs: Vec<_> = sg.inputs.iter().map(|i| (i.name, i.node_key)).collect();
+            let new_outputs: Vec<_> = sg.outputs.iter().map(|o| (o.name, o.node_key)).collect();
+            (old_inputs, old_outputs, new_inputs, new_outputs)
+        };
+        if old_inputs == new_inputs && old_outputs == new_outputs {
+            return;
+        }
+        let remap = |port: &Port,
+                     old: &Vec<(GlobalSymbol, NodeKey)>,
+                     new: &Vec<(GlobalSymbol, NodeKey)>| {
+            let (name, inner_key) = old.get(port.port)?;
+            let idx = new
+                .iter()
+                .position(|(n, _)| n == name)
+                .or_else(|| new.iter().position(|(_, key)| key == inner_key))?;
+            Some(Port {
+                node: port.node,
+                port: idx,
+                kind: port.kind.clone(),
+            })
+        };
+        graph.edges = graph
+            .edges
+            .iter()
+            .filter_map(|edge| {
+                let mut edge = edge.clone();
+                if edge.to.node == node_key {
+                    edge.to = remap(&edge.to, &old_inputs, &new_inputs)?;
+                }
+                if edge.from.node == node_key {
+                    edge.from = remap(&edge.from, &old_outputs, &new_outputs)?;
+                }
+                Some(edge)
+            })
+            .collect();
+        graph.sort();
+    }
+
+    /// Embeds a copy of the patch, later changes to the file are not seen
     pub fn load(&mut self, filename: String) {
-        let file_contents = std::fs::read_to_string(filename).unwrap();
-        let graph: Graph = ron::from_str(&file_contents).unwrap();
+        match read_patch(&filename) {
+            Ok(graph) => {
+                self.replace_subgraph(graph);
+                self.link = None;
+                self.link_modified = None;
+                self.update_interface();
+            }
+            Err(error) => println!("Couldn't load patch {}: {:?}", filename, error),
+        }
+    }
+
+    // The inner graph is replaced on the editor thread, prepared for the
+    // rate the previous one ran at
+    fn replace_subgraph(&mut self, graph: Graph) {
+        let prepared = self.subgraph.prepared();
         self.subgraph = graph;
-        self.inputs = self.subgraph.get_unconnected_inputs();
-        self.outputs = self.subgraph.get_unconnected_outputs();
+        if let Some(prepared) = prepared {
+            self.subgraph
+                .prepare(prepared.sample_rate, prepared.max_block);
+        }
+    }
+
+    /// Changes the oversampling factor, preparing the inner graph for its
+    /// new rate here instead of on the audio thread
+    pub fn set_oversample(&mut self, factor: usize) {
+        let previous = self.oversample.max(1);
+        self.oversample = factor;
+        self.oversampler = Oversampler::new(factor.max(1));
+        if let Some(prepared) = self.subgraph.prepared() {
+            self.prepare(
+                prepared.sample_rate / previous as f32,
+                prepared.max_block / previous,
+            );
+        }
+    }
+
+    /// Links the subgraph to the patch file, it is reloaded when the file
+    /// changes
+    pub fn link(&mut self, filename: String) {
+        self.load(filename.clone());
+        self.link_modified = modified_time(&filename);
+        self.link = Some(filename);
+    }
+
+    /// Writes the inner graph to the linked patch file
+    pub fn save_link(&mut self) -> anyhow::Result<()> {
+        let Some(filename) = &self.link else {
+            return Ok(());
+        };
+        let serialized =
+            ron::ser::to_string_pretty(&self.subgraph.copy(), ron::ser::PrettyConfig::default())?;
+        std::fs::write(filename, serialized)?;
+        // Not a change that needs reloading
+        self.link_modified = modified_time(filename);
+        Ok(())
+    }
+
+    /// Turns a linked subgraph into an embedded one
+    pub fn make_local(&mut self) {
+        self.link = None;
+        self.link_modified = None;
+    }
+
+    // Starts the oversampled port values from the inner nodes, when the ports
+    // changed or oversampling was just turned on
+    fn sync_input_values(&mut self) {
+        if self.input_values.len() == self.inputs.len() {
+            return;
+        }
+        self.input_values = self
+            .inputs
+            .iter()
+            .map(|sinput| {
+                if self.subgraph.has_node(sinput.node_key) {
+                    self.subgraph
+                        .get_node_mut(sinput.node_key)
+                        .get_input(sinput.port_idx)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+    }
+
+    /// Reloads all linked subgraphs in `graph`, including nested ones, whose
+    /// files changed. Parent connections are kept by port name.
+    /// Reloads the linked subgraphs in `graph`, at any depth, whose files
+    /// changed since they were read
+    pub fn reload_linked(graph: &mut Graph) {
+        let patches = Subgraph::read_changed_links(&Subgraph::links(graph));
+        Subgraph::apply_links(graph, &patches);
+    }
+
+    /// The files of the linked subgraphs in `graph`, at any depth, with the
+    /// time each was last read
+    pub fn links(graph: &Graph) -> Vec<(String, Option<SystemTime>)> {
+        let mut links = Vec::new();
+        for node in graph.nodes.values() {
+            let node = node.borrow();
+            let Some(sg) = node.as_any().downcast_ref::<Subgraph>() else {
+                continue;
+            };
+            if let Some(link) = &sg.link {
+                links.push((link.clone(), sg.link_modified));
+            }
+            links.extend(Subgraph::links(&sg.subgraph));
+        }
+        links
+    }
+
+    /// Reads the files of `links` that changed since they were read. Parsing
+    /// a patch can load samples, so this is done without holding the running
+    /// graph.
+    pub fn read_changed_links(
+        links: &[(String, Option<SystemTime>)],
+    ) -> HashMap<String, LinkedPatch> {
+        let mut patches = HashMap::new();
+        for (filename, link_modified) in links {
+            let modified = modified_time(filename);
+            if modified.is_none() || modified == *link_modified || patches.contains_key(filename) {
+                continue;
+            }
+            match read_patch(filename) {
+                Ok(mut graph) => {
+                    Subgraph::reload_linked(&mut graph);
+                    patches.insert(filename.clone(), LinkedPatch { modified, graph });
+                }
+                Err(error) => println!("Couldn't reload patch {}: {:?}", filename, error),
+            }
+        }
+        patches
+    }
+
+    /// Puts the patches read by `read_changed_links` into the subgraphs
+    /// linked to them
+    pub fn apply_links(graph: &mut Graph, patches: &HashMap<String, LinkedPatch>) {
+        if patches.is_empty() {
+            return;
+        }
+        let node_keys: Vec<NodeKey> = graph.nodes.keys().collect();
+        for node_key in node_keys {
+            let reloaded = {
+                let mut node = graph.get_node_mut(node_key);
+                let Some(sg) = node.as_any_mut().downcast_mut::<Subgraph>() else {
+                    continue;
+                };
+                let patch = sg.link.as_ref().and_then(|link| patches.get(link));
+                let reloaded = match patch {
+                    Some(patch) if patch.modified != sg.link_modified => {
+                        println!("Reloading linked patch {}", sg.link.as_ref().unwrap());
+                        sg.link_modified = patch.modified;
+                        sg.replace_subgraph(patch.graph.copy());
+                        sg.update_interface();
+                        true
+                    }
+                    _ => false,
+                };
+                Subgraph::apply_links(&mut sg.subgraph, patches);
+                reloaded
+            };
+            if reloaded {
+                Subgraph::refresh(graph, node_key);
+            }
+        }
     }
 }
 
 #[typetag::serde]
 impl Node for Subgraph {
     fn copy(&self) -> Box<dyn Node> {
+        // The copied inner nodes get new keys, the ports follow them
+        let node_keys: Vec<NodeKey> = self.subgraph.nodes.keys().collect();
+        let (subgraph, node_lookup) = self.subgraph.copy_nodes_with_lookup(&node_keys);
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|input| UnconnectedInput {
+                node_key: node_lookup[&input.node_key],
+                ..input.clone()
+            })
+            .collect();
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|output| UnconnectedOutput {
+                node_key: node_lookup[&output.node_key],
+                ..output.clone()
+            })
+            .collect();
         let c = Subgraph {
-            subgraph: self.subgraph.copy(),
-            inputs: self.inputs.clone(),
-            outputs: self.outputs.clone(),
+            name: self.name.clone(),
+            link: self.link.clone(),
+            link_modified: self.link_modified,
+            subgraph,
+            inputs,
+            outputs,
+            oversample: self.oversample,
+            oversampler: Oversampler::default(),
+            input_values: self.input_values.clone(),
+            output_values: Vec::new(),
         };
         Box::new(c)
     }
+    // The inner graph keeps the version it was saved with
+    fn upgrade(&mut self, _version: u32) {
+        self.subgraph.upgrade();
+    }
     fn inputs(&self) -> Vec<InputId> {
         self.inputs
             .iter()
@@ -68,11 +503,17 @@ impl Node for Subgraph {
 
     // Set input at index idx to value val
     fn set(&mut self, idx: usize, val: f32) {
-        if idx < self.inputs.len() {
+        if self.oversample > 1 && idx < self.inputs.len() {
+            self.sync_input_values();
+            self.input_values[idx] = val;
+        } else if idx < self.inputs.len() {
             let sinput = &self.inputs[idx];
-            self.subgraph
-                .get_node_mut(sinput.node_key)
-                .set(sinput.port_idx, val);
+            // The inner node may have been removed while editing the subgraph
+            if self.subgraph.has_node(sinput.node_key) {
+                self.subgraph
+                    .get_node_mut(sinput.node_key)
+                    .set(sinput.port_idx, val);
+            }
         } else {
             panic!();
         }
@@ -80,8 +521,13 @@ impl Node for Subgraph {
 
     // Get value of output index idx
     fn get(&self, idx: usize) -> f32 {
-        if idx < self.outputs.len() {
+        if self.oversample > 1 && idx < self.outputs.len() {
+            self.output_values.get(idx).copied().unwrap_or(0.0)
+        } else if idx < self.outputs.len() {
             let soutput = &self.outputs[idx];
+            if !self.subgraph.has_node(soutput.node_key) {
+                return 0.0;
+            }
             self.subgraph
                 .get_node_mut(soutput.node_key)
                 .get(soutput.port_idx)
@@ -104,8 +550,14 @@ impl Node for Subgraph {
     }
 
     fn get_input(&mut self, idx: usize) -> f32 {
-        if idx < self.inputs.len() {
+        if self.oversample > 1 && idx < self.inputs.len() {
+            self.sync_input_values();
+            self.input_values[idx]
+        } else if idx < self.inputs.len() {
             let sinput = &self.inputs[idx];
+            if !self.subgraph.has_node(sinput.node_key) {
+                return 0.0;
+            }
             self.subgraph
                 .get_node_mut(sinput.node_key)
                 .get_input(sinput.port_idx)
@@ -115,7 +567,57 @@ impl Node for Subgraph {
     }
 
     fn step(&mut self, sample_rate: f32) {
-        self.subgraph.step(sample_rate);
+        let factor = self.oversample.max(1);
+        if factor == 1 {
+            self.subgraph.step(sample_rate);
+            return;
+        }
+        if self.oversampler.factor() != factor {
+            self.oversampler = Oversampler::new(factor);
+        }
+        self.sync_input_values();
+        self.oversampler
+            .push_inputs(self.input_values.iter().copied());
+        for phase in 0..factor {
+            for (idx, sinput) in self.inputs.iter().enumerate() {
+                if self.subgraph.has_node(sinput.node_key) {
+                    self.subgraph
+                        .get_node_mut(sinput.node_key)
+                        .set(sinput.port_idx, self.oversampler.upsampled(idx, phase));
+                }
+            }
+            self.subgraph.step(sample_rate * factor as f32);
+            let subgraph = &self.subgraph;
+            self.oversampler
+                .push_outputs(self.outputs.iter().map(|soutput| {
+                    if subgraph.has_node(soutput.node_key) {
+                        subgraph.get_node(soutput.node_key).get(soutput.port_idx)
+                    } else {
+                        0.0
+                    }
+                }));
+        }
+        self.output_values.resize(self.outputs.len(), 0.0);
+        for (idx, value) in self.output_values.iter_mut().enumerate() {
+            *value = self.oversampler.downsampled(idx);
+        }
+    }
+
+    // The inner nodes, stepped as many times as the subgraph is oversampled
+    fn cost(&self) -> u32 {
+        let inner: u32 = self
+            .subgraph
+            .nodes
+            .values()
+            .map(|node| node.borrow().cost())
+            .sum();
+        inner * self.oversample.max(1) as u32
+    }
+
+    fn prepare(&mut self, sample_rate: f32, max_block: usize) {
+        let factor = self.oversample.max(1);
+        self.subgraph
+            .prepare(sample_rate * factor as f32, max_block * factor);
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -129,3 +631,114 @@ impl Node for Subgraph {
         "Subgraph"
     }
 }
+
+fn read_patch(filename: &str) -> anyhow::Result<Graph> {
+    let file_contents = std::fs::read_to_string(filename)?;
+    let mut graph: Graph = ron::from_str(&file_contents)?;
+    graph.sort();
+    Ok(graph)
+}
+
+fn modified_time(filename: &str) -> Option<SystemTime> {
+    std::fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(node: NodeKey) -> Port {
+        Port {
+            node,
+            port: 0,
+            kind: PortKind::Output,
+        }
+    }
+
+    fn input(node: NodeKey) -> Port {
+        Port {
+            node,
+            port: 0,
+            kind: PortKind::Input,
+        }
+    }
+
+    // Bias -> Scale -> Out, with `spare` next to Scale
+    fn grouped() -> (Subgraph, CrossingEdges) {
+        let mut graph = Graph::new();
+        let bias = graph.add(Box::new(Bias::default()));
+        let spare = graph.add(Box::new(Bias::default()));
+        let scale = graph.add(Box::new(Scale {
+            scale: 2.0,
+            ..Scale::default()
+        }));
+        graph.connect(output(bias), input(scale));
+        graph.connect(output(scale), input(graph.output_node.unwrap()));
+        Subgraph::from_nodes(&graph, &[spare, scale])
+    }
+
+    #[test]
+    fn crossing_edges_get_port_nodes() {
+        let (sg, crossing) = grouped();
+        assert_eq!(sg.inputs.len(), 1);
+        assert_eq!(sg.outputs.len(), 1);
+        assert_eq!(crossing.inputs.len(), 1);
+        assert_eq!(crossing.outputs.len(), 1);
+        assert_eq!(crossing.inputs[0].1, 0);
+        assert_eq!(crossing.outputs[0].0, 0);
+        assert_eq!(sg.inputs[0].name.as_str(), "input");
+        assert_eq!(sg.outputs[0].name.as_str(), "value");
+    }
+
+    #[test]
+    fn copies_remap_the_ports_to_the_new_inner_keys() {
+        let (mut sg, _) = grouped();
+        // Leaves a hole in the keys, so the copied nodes get other keys
+        let spare = sg.subgraph.nodes.keys().next().unwrap();
+        sg.subgraph.remove(spare);
+        sg.subgraph.publish();
+
+        let mut copy = sg.copy();
+        let copied = copy.as_any().downcast_ref::<Subgraph>().unwrap();
+        assert_ne!(copied.inputs[0].node_key, sg.inputs[0].node_key);
+        assert_ne!(copied.outputs[0].node_key, sg.outputs[0].node_key);
+        let input_node = copied.subgraph.get_node(copied.inputs[0].node_key);
+        assert!(input_node.as_any().is::<SubgraphInput>());
+        drop(input_node);
+        let output_node = copied.subgraph.get_node(copied.outputs[0].node_key);
+        assert!(output_node.as_any().is::<SubgraphOutput>());
+        drop(output_node);
+
+        copy.set(0, 0.25);
+        copy.step(44100.0);
+        assert_eq!(copy.get(0), 0.5);
+    }
+
+    #[test]
+    fn publishing_a_graph_publishes_its_subgraphs() {
+        let (sg, _) = grouped();
+        assert!(sg.subgraph.dirty);
+        let mut graph = Graph::new();
+        let node_key = graph.add(Box::new(sg));
+        graph.publish();
+        assert!(!graph.dirty);
+
+        let node = graph.get_node(node_key);
+        let sg = node.as_any().downcast_ref::<Subgraph>().unwrap();
+        assert!(!sg.subgraph.dirty);
+        let plan = sg.subgraph.plan.as_ref().unwrap();
+        assert_eq!(plan.order.len(), sg.subgraph.nodes.len());
+    }
+
+    #[test]
+    fn oversampling_prepares_the_inner_graph_for_its_rate() {
+        let (mut sg, _) = grouped();
+        sg.prepare(48000.0, 512);
+        sg.set_oversample(4);
+        let prepared = sg.subgraph.prepared().unwrap();
+        assert_eq!(prepared.sample_rate, 192000.0);
+        assert_eq!(prepared.max_block, 2048);
+        sg.set_oversample(1);
+        assert_eq!(sg.subgraph.prepared().unwrap().sample_rate, 48000.0);
+    }
+}
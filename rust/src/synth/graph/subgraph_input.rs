@@ -0,0 +1,57 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+
+/// Input port of the `Subgraph` containing this node. The parent sets the
+/// input, which holds the default when the parent port is unconnected.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct SubgraphInput {
+    pub name: String,
+
+    // input ports
+    pub input: f32,
+
+    // output ports
+    pub value: f32,
+}
+
+#[typetag::serde]
+impl Node for SubgraphInput {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "SubgraphInput"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "default")].into_iter().map(|t| t.into()).collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value")].into_iter().map(|t| t.into()).collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        valid_idx!(self.input = val, idx, 1);
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        valid_idx!(self.value, idx, 1)
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        valid_idx!(&mut self.input, idx, 1)
+    }
+
+    fn step(&mut self, _sample_rate: f32) {
+        self.value = self.input
+    }
+}
@@ -0,0 +1,56 @@
+use crate::graph::*;
+use serde::{Deserialize, Serialize};
+
+/// Output port of the `Subgraph` containing this node.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct SubgraphOutput {
+    pub name: String,
+
+    // input ports
+    pub input: f32,
+
+    // output ports
+    pub value: f32,
+}
+
+#[typetag::serde]
+impl Node for SubgraphOutput {
+    fn copy(&self) -> Box<dyn Node> {
+        let c = (*self).clone();
+        Box::new(c)
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name() -> &'static str {
+        "SubgraphOutput"
+    }
+    fn inputs(&self) -> Vec<InputId> {
+        vec![(0, "input")].into_iter().map(|t| t.into()).collect()
+    }
+    fn outputs(&self) -> Vec<OutputId> {
+        vec![(0, "value")].into_iter().map(|t| t.into()).collect()
+    }
+
+    // Set input at index idx to value val
+    fn set(&mut self, idx: usize, val: f32) {
+        valid_idx!(self.input = val, idx, 1);
+    }
+
+    // Get value of output index idx
+    fn get(&self, idx: usize) -> f32 {
+        valid_idx!(self.value, idx, 1)
+    }
+
+    fn get_input_mut(&mut self, idx: usize) -> &mut f32 {
+        valid_idx!(&mut self.input, idx, 1)
+    }
+
+    fn step(&mut self, _sample_rate: f32) {
+        self.value = self.input
+    }
+}
@@ -0,0 +1,287 @@
+use crate::graph::*;
+use std::ops::Range;
+
+/// What the audio thread runs for a graph: the nodes in an order where every
+/// node comes after the nodes it reads from, grouped in layers whose nodes
+/// only read from earlier layers. The graph keeps its nodes in this order,
+/// so the plan refers to a node by its index in `order`. Every node stores
+/// the values of its connected output ports for a block of samples, its
+/// signals, and reads its inputs from the signals of the nodes before it.
+/// A plan is never changed once built, an edit to the graph builds a new one
+/// and the old one stays valid for whoever still holds it.
+#[derive(Default)]
+pub struct ExecutionPlan {
+    pub order: Vec<NodeKey>,
+    // One per node of `order`, at the same index
+    pub steps: Vec<PlanStep>,
+    pub inputs: Vec<SignalInput>,
+    // Output port of each signal, the signals of a node are consecutive
+    pub signals: Vec<Port>,
+    // Ranges of `order`, the nodes of a layer can step at the same time
+    pub layers: Vec<Range<usize>>,
+    // One per layer, whether it has enough work for the worker pool. Set by
+    // the graph, which knows the nodes.
+    pub parallel: Vec<bool>,
+    // Index of the output node, which always stores its port 0 first
+    pub output: Option<usize>,
+}
+
+/// A node of the plan, with the ranges of `inputs` to set before it steps
+/// and of `signals` it stores
+#[derive(Clone)]
+pub struct PlanStep {
+    pub inputs: Range<usize>,
+    pub signals: Range<usize>,
+}
+
+/// An input port read from a signal of an earlier node
+pub struct SignalInput {
+    // Index of the node in the plan
+    pub source: usize,
+    // Which of the signals of that node
+    pub slot: usize,
+    pub port: usize,
+}
+
+impl ExecutionPlan {
+    /// Sorts `node_keys` by their inputs, linear in the number of nodes and
+    /// edges. Nodes in a cycle, and everything downstream of one, are left
+    /// out as they have no valid place in the order.
+    pub fn new(
+        node_keys: impl Iterator<Item = NodeKey>,
+        node_inputs: &HashMap<NodeKey, Vec<Edge>>,
+        node_outputs: &HashMap<NodeKey, Vec<Edge>>,
+        output_node: Option<NodeKey>,
+    ) -> Self {
+        let mut unsorted_inputs: HashMap<NodeKey, usize> = HashMap::new();
+        let mut ready: VecDeque<NodeKey> = VecDeque::new();
+        for node_key in node_keys {
+            let n_inputs = node_inputs.get(&node_key).map_or(0, Vec::len);
+            if n_inputs == 0 {
+                ready.push_back(node_key);
+            }
+            unsorted_inputs.insert(node_key, n_inputs);
+        }
+
+        // Every source of a node is sorted before it, so its layer is known
+        let mut layer_of: HashMap<NodeKey, usize> = HashMap::new();
+        let mut order = Vec::new();
+        while let Some(node_key) = ready.pop_front() {
+            for edge in node_outputs.get(&node_key).into_iter().flatten() {
+                if let Some(n_inputs) = unsorted_inputs.get_mut(&edge.to.node) {
+                    *n_inputs -= 1;
+                    if *n_inputs == 0 {
+                        ready.push_back(edge.to.node);
+                    }
+                }
+            }
+            let layer = node_inputs
+                .get(&node_key)
+                .into_iter()
+                .flatten()
+                .filter_map(|edge| layer_of.get(&edge.from.node))
+                .map(|layer| layer + 1)
+                .max()
+                .unwrap_or(0);
+            layer_of.insert(node_key, layer);
+            order.push(node_key);
+        }
+        order.sort_by_key(|node_key| layer_of[node_key]);
+
+        let mut plan = Self {
+            order,
+            ..Self::default()
+        };
+        let position: HashMap<NodeKey, usize> = plan
+            .order
+            .iter()
+            .enumerate()
+            .map(|(idx, node_key)| (*node_key, idx))
+            .collect();
+        for (idx, node_key) in plan.order.iter().enumerate() {
+            let layer = layer_of[node_key];
+            if plan.layers.len() <= layer {
+                plan.layers.push(idx..idx);
+            }
+            plan.layers[layer].end = idx + 1;
+        }
+        plan.parallel = vec![false; plan.layers.len()];
+        plan.output = output_node.and_then(|node_key| position.get(&node_key).copied());
+        for idx in 0..plan.order.len() {
+            plan.push_step(idx, &position, node_inputs, node_outputs);
+        }
+        plan
+    }
+
+    // Stores the output ports of the node that sorted nodes read, and reads
+    // its inputs from the signals of its sources
+    fn push_step(
+        &mut self,
+        idx: usize,
+        position: &HashMap<NodeKey, usize>,
+        node_inputs: &HashMap<NodeKey, Vec<Edge>>,
+        node_outputs: &HashMap<NodeKey, Vec<Edge>>,
+    ) {
+        let node_key = self.order[idx];
+        let mut ports: Vec<usize> = node_outputs
+            .get(&node_key)
+            .into_iter()
+            .flatten()
+            .filter(|edge| position.contains_key(&edge.to.node))
+            .map(|edge| edge.from.port)
+            .collect();
+        if self.output == Some(idx) {
+            ports.push(0);
+        }
+        ports.sort_unstable();
+        ports.dedup();
+        let signals_start = self.signals.len();
+        self.signals.extend(ports.into_iter().map(|port| Port {
+            node: node_key,
+            port,
+            kind: PortKind::Output,
+        }));
+
+        // Sources come first in the order, their signals are already known.
+        // Inputs fed by several edges end up with the last source in the
+        // order, as when the values were copied after each node stepped.
+        let inputs_start = self.inputs.len();
+        for edge in node_inputs.get(&node_key).into_iter().flatten() {
+            let source = position[&edge.from.node];
+            let slot = self.signals[self.steps[source].signals.clone()]
+                .iter()
+                .position(|signal| signal.port == edge.from.port)
+                .unwrap();
+            self.inputs.push(SignalInput {
+                source,
+                slot,
+                port: edge.to.port,
+            });
+        }
+        self.inputs[inputs_start..].sort_by_key(|input| (input.source, input.slot));
+        self.steps.push(PlanStep {
+            inputs: inputs_start..self.inputs.len(),
+            signals: signals_start..self.signals.len(),
+        });
+    }
+
+    /// Layer of every sorted node, the depth the editor lays nodes out by
+    pub fn depths(&self) -> HashMap<NodeKey, i32> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(layer, nodes)| {
+                self.order[nodes.clone()]
+                    .iter()
+                    .map(move |node_key| (*node_key, layer as i32))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(n_keys: usize) -> Vec<NodeKey> {
+        let mut slots: SlotMap<NodeKey, ()> = SlotMap::with_key();
+        (0..n_keys).map(|_| slots.insert(())).collect()
+    }
+
+    fn edge(from: NodeKey, from_port: usize, to: NodeKey, to_port: usize) -> Edge {
+        Edge {
+            from: Port {
+                node: from,
+                port: from_port,
+                kind: PortKind::Output,
+            },
+            to: Port {
+                node: to,
+                port: to_port,
+                kind: PortKind::Input,
+            },
+        }
+    }
+
+    fn plan(node_keys: &[NodeKey], edges: &[Edge], output: Option<NodeKey>) -> ExecutionPlan {
+        let mut node_inputs: HashMap<NodeKey, Vec<Edge>> = HashMap::new();
+        let mut node_outputs: HashMap<NodeKey, Vec<Edge>> = HashMap::new();
+        for edge in edges {
+            node_outputs
+                .entry(edge.from.node)
+                .or_default()
+                .push(edge.clone());
+            node_inputs
+                .entry(edge.to.node)
+                .or_default()
+                .push(edge.clone());
+        }
+        ExecutionPlan::new(
+            node_keys.iter().copied(),
+            &node_inputs,
+            &node_outputs,
+            output,
+        )
+    }
+
+    #[test]
+    fn nodes_are_layered_by_their_longest_path() {
+        let [a, b, c, d] = keys(4)[..] else {
+            unreachable!()
+        };
+        let plan = plan(
+            &[a, b, c, d],
+            &[edge(a, 0, b, 0), edge(b, 0, c, 0), edge(a, 0, c, 1)],
+            None,
+        );
+        assert_eq!(plan.order, vec![a, d, b, c]);
+        assert_eq!(plan.layers, vec![0..2, 2..3, 3..4]);
+        assert_eq!(plan.parallel, vec![false; 3]);
+        assert_eq!(plan.depths()[&c], 2);
+
+        // A port read twice is stored once, a port nobody reads not at all
+        assert_eq!(plan.steps[0].signals.len(), 1);
+        assert!(plan.steps[1].signals.is_empty());
+        assert!(plan.steps[3].signals.is_empty());
+        let inputs: Vec<(usize, usize, usize)> = plan.inputs[plan.steps[3].inputs.clone()]
+            .iter()
+            .map(|input| (input.source, input.slot, input.port))
+            .collect();
+        assert_eq!(inputs, vec![(0, 0, 1), (2, 0, 0)]);
+    }
+
+    #[test]
+    fn cycles_and_what_they_feed_are_left_out() {
+        let [a, b, c, d] = keys(4)[..] else {
+            unreachable!()
+        };
+        let plan = plan(
+            &[a, b, c, d],
+            &[edge(a, 0, b, 0), edge(b, 0, a, 0), edge(b, 0, c, 0)],
+            Some(c),
+        );
+        assert_eq!(plan.order, vec![d]);
+        assert_eq!(plan.layers, vec![0..1]);
+        assert_eq!(plan.output, None);
+    }
+
+    #[test]
+    fn the_output_node_stores_its_first_port_first() {
+        let [source, out, after] = keys(3)[..] else {
+            unreachable!()
+        };
+        let plan = plan(
+            &[source, out, after],
+            &[edge(source, 0, out, 0), edge(out, 1, after, 0)],
+            Some(out),
+        );
+        assert_eq!(plan.output, Some(1));
+        let ports: Vec<usize> = plan.signals[plan.steps[1].signals.clone()]
+            .iter()
+            .map(|signal| signal.port)
+            .collect();
+        assert_eq!(ports, vec![0, 1]);
+        let input = &plan.inputs[plan.steps[2].inputs.clone()][0];
+        assert_eq!((input.source, input.slot), (1, 1));
+    }
+}
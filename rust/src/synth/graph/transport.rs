@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Tempo and position of an external transport, such as JACK's. Written by
+/// the audio backend once per block and followed by tempo synced nodes.
+pub struct TransportClock {
+    rolling: AtomicBool,
+    bpm: AtomicU32,
+    // Position in beats at the start of the block
+    beats: AtomicU64,
+    // Bumped on every update, 0 while no backend drives the transport
+    generation: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransportState {
+    pub rolling: bool,
+    pub bpm: f32,
+    pub beats: f64,
+    pub generation: u64,
+}
+
+impl TransportClock {
+    pub fn update(&self, rolling: bool, bpm: f32, beats: f64) {
+        self.rolling.store(rolling, Ordering::Relaxed);
+        self.bpm.store(bpm.to_bits(), Ordering::Relaxed);
+        self.beats.store(beats.to_bits(), Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Latest state, or `None` when nothing drives the transport
+    pub fn state(&self) -> Option<TransportState> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation == 0 {
+            return None;
+        }
+        Some(TransportState {
+            rolling: self.rolling.load(Ordering::Relaxed),
+            bpm: f32::from_bits(self.bpm.load(Ordering::Relaxed)),
+            beats: f64::from_bits(self.beats.load(Ordering::Relaxed)),
+            generation,
+        })
+    }
+
+    /// Hands the tempo back to the nodes, e.g. when the backend closes
+    pub fn release(&self) {
+        self.generation.store(0, Ordering::Release);
+    }
+}
+
+pub fn transport() -> &'static TransportClock {
+    static TRANSPORT: OnceLock<TransportClock> = OnceLock::new();
+    TRANSPORT.get_or_init(|| TransportClock {
+        rolling: AtomicBool::new(false),
+        bpm: AtomicU32::new(120.0f32.to_bits()),
+        beats: AtomicU64::new(0.0f64.to_bits()),
+        generation: AtomicU64::new(0),
+    })
+}
+
+/// Position within the transport, advanced sample by sample between the
+/// updates of the backend
+#[derive(Clone, Default)]
+pub struct TransportFollower {
+    pub beats: f64,
+    generation: u64,
+}
+
+impl TransportFollower {
+    /// Position in beats for the current sample, `None` when there is no
+    /// transport or it is stopped
+    pub fn step(&mut self, sample_rate: f32) -> Option<f64> {
+        let state = transport().state()?;
+        if state.generation != self.generation {
+            self.generation = state.generation;
+            self.beats = state.beats;
+        } else if state.rolling {
+            self.beats += state.bpm as f64 / 60.0 / sample_rate as f64;
+        }
+        state.rolling.then_some(self.beats)
+    }
+}
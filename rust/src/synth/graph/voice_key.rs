@@ -22,6 +22,10 @@ enum VoiceKeyIO {
     // gate5,
     // gate6,
     // gate7,
+    vel0,
+    vel1,
+    vel2,
+    vel3,
     MAX,
 }
 
@@ -46,6 +50,8 @@ pub struct VoiceKey {
     // output ports
     pub pitch: [f32; N_VOICES],
     pub trigger: [f32; N_VOICES],
+    #[serde(default)]
+    pub velocity: [f32; N_VOICES],
     pub count: usize,
 }
 
@@ -93,6 +99,8 @@ impl Node for VoiceKey {
             self.pitch[idx]
         } else if idx < 2 * N_VOICES {
             self.trigger[idx - N_VOICES]
+        } else if idx < 3 * N_VOICES {
+            self.velocity[idx - 2 * N_VOICES]
         } else {
             panic!("VoiceKey::get Index out of range");
         }
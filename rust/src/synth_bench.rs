@@ -0,0 +1,164 @@
+// Times `Graph::process`, which steps each node through a block at a time,
+// against stepping the graph a sample at a time by walking the node order
+// and its edges, as before the graph was compiled to a plan. Runs on a patch
+// file or on a generated patch with many voices, with
+// `cargo run --profile bench --bin synth_bench [patch] [seconds]`.
+use std::time::{Duration, Instant};
+mod synth;
+use crate::synth::*;
+
+const SAMPLE_RATE: f32 = 48_000.0;
+const DEFAULT_SECONDS: f32 = 10.0;
+const VOICES: usize = 48;
+// Buffer size `process` is called with, like a sound card would
+const BLOCK: usize = 512;
+
+// Saw, lowpass and saturator per voice, summed by a chain of adders
+fn voices_patch(n_voices: usize) -> Graph {
+    let mut graph = Graph::new();
+    let output = |node| Port {
+        node,
+        port: 0,
+        kind: PortKind::Output,
+    };
+    let input = |node, port| Port {
+        node,
+        port,
+        kind: PortKind::Input,
+    };
+    let mut mix = None;
+    for voices in (0..n_voices).collect::<Vec<_>>().chunks(3) {
+        let add = graph.add(Box::new(Add::default()));
+        if let Some(mix) = mix {
+            graph.connect(output(mix), input(add, 0));
+        }
+        for (idx, voice) in voices.iter().enumerate() {
+            let saw = graph.add(Box::new(SawOsc::default()));
+            graph
+                .get_node_mut(saw)
+                .set(0, 55.0 * (1 + voice % 12) as f32);
+            let lowpass = graph.add(Box::new(Lowpass::default()));
+            let saturator = graph.add(Box::new(Saturator::default()));
+            graph.connect(output(saw), input(lowpass, 0));
+            graph.connect(output(lowpass), input(saturator, 0));
+            graph.connect(output(saturator), input(add, idx + 1));
+        }
+        mix = Some(add);
+    }
+    if let (Some(mix), Some(out)) = (mix, graph.output_node) {
+        graph.connect(output(mix), input(out, 0));
+    }
+    graph.publish();
+    graph
+}
+
+fn load_patch(patch_file: &str) -> anyhow::Result<Graph> {
+    let file_contents = std::fs::read_to_string(patch_file)?;
+    let mut graph: Graph = ron::from_str(&file_contents)?;
+    Subgraph::reload_linked(&mut graph);
+    if graph.output_node.is_none() {
+        graph.output_node = graph.get_by_type_mut::<Out>().map(|(out_key, _)| out_key);
+    }
+    graph.sort();
+    Ok(graph)
+}
+
+// A fresh instance of the patch, so every run starts from the same state.
+// `Graph::copy` would give noise nodes new seeds.
+fn instance(patch: &str) -> anyhow::Result<Graph> {
+    let mut graph: Graph = ron::from_str(patch)?;
+    graph.sort();
+    graph.prepare(SAMPLE_RATE, BLOCK);
+    Ok(graph)
+}
+
+// Steps every node once in the node order and copies its outputs along its
+// edges, as `Graph::step` did before
+fn step_baseline(graph: &Graph) -> f32 {
+    for node_key in graph.node_order() {
+        graph.get_node_mut(*node_key).step(SAMPLE_RATE);
+        for edge in &graph.node_outputs()[node_key] {
+            let value = graph.get_node(*node_key).get(edge.from.port);
+            graph.get_node_mut(edge.to.node).set(edge.to.port, value);
+        }
+    }
+    graph
+        .output_node
+        .map_or(0.0, |output_node| graph.get_node(output_node).get(0))
+}
+
+fn render_baseline(patch: &str, n_samples: usize) -> anyhow::Result<(Duration, Vec<f32>)> {
+    let graph = instance(patch)?;
+    let mut samples = Vec::with_capacity(n_samples);
+    let started = Instant::now();
+    for _ in 0..n_samples {
+        samples.push(step_baseline(&graph));
+    }
+    Ok((started.elapsed(), samples))
+}
+
+fn render_blocks(patch: &str, n_samples: usize) -> anyhow::Result<(Duration, Vec<f32>)> {
+    let mut graph = instance(patch)?;
+    let mut samples = vec![0.0; n_samples];
+    let started = Instant::now();
+    for block in samples.chunks_mut(BLOCK) {
+        graph.process(block, SAMPLE_RATE);
+    }
+    Ok((started.elapsed(), samples))
+}
+
+fn report(name: &str, took: Duration, baseline: Duration, n_samples: usize) {
+    println!(
+        "{:<12} {:>8.1} ms {:>8.1} ns/sample {:>6.2}x",
+        name,
+        took.as_secs_f64() * 1e3,
+        took.as_secs_f64() * 1e9 / n_samples as f64,
+        baseline.as_secs_f64() / took.as_secs_f64()
+    );
+}
+
+pub fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let graph = match args.next() {
+        Some(patch_file) => load_patch(&patch_file)?,
+        None => voices_patch(VOICES),
+    };
+    let seconds = args
+        .next()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(DEFAULT_SECONDS);
+    let n_samples = (seconds * SAMPLE_RATE) as usize;
+    println!(
+        "{} nodes, {:.1} s at {} Hz",
+        graph.node_order().len(),
+        seconds,
+        SAMPLE_RATE
+    );
+
+    let patch = ron::to_string(&graph)?;
+
+    let (baseline, expected) = render_baseline(&patch, n_samples)?;
+    report("per sample", baseline, baseline, n_samples);
+
+    let same = |samples: &[f32]| {
+        samples
+            .iter()
+            .zip(&expected)
+            .all(|(sample, expected)| sample.to_bits() == expected.to_bits())
+    };
+
+    let (blocks, samples) = render_blocks(&patch, n_samples)?;
+    report("blocks", blocks, baseline, n_samples);
+    if !same(&samples) {
+        anyhow::bail!("block output differs from stepping per sample");
+    }
+
+    set_parallel(true);
+    let (parallel, samples) = render_blocks(&patch, n_samples)?;
+    set_parallel(false);
+    report("parallel", parallel, baseline, n_samples);
+    if !same(&samples) {
+        anyhow::bail!("parallel output differs from stepping per sample");
+    }
+    Ok(())
+}
@@ -11,25 +11,34 @@ use std::cell::OnceCell;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
+use std::path::Path;
 //use egui::plot::{Line, Plot, PlotPoints};
 use glob::*;
 use itertools::Itertools;
 use ron::*;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 
 // use slotmap::SlotMap;
 
-use std::time::{Duration, Instant};
-
-use sdl2::{
-    audio::AudioCallback, audio::AudioDevice, audio::AudioSpec, audio::AudioSpecDesired,
-    AudioSubsystem,
-};
+use std::time::{Duration, Instant, SystemTime};
 
 // mod graph;
 // use graph::*;
+mod audio_backend;
+use audio_backend::*;
+mod history;
+use history::*;
+mod keyboard;
+use keyboard::*;
 mod knob;
 use knob::*;
+mod node_registry;
+use node_registry::*;
+mod patch_library;
+use patch_library::*;
+mod spectrum;
+use spectrum::*;
 mod synth;
 use synth::*;
 
@@ -64,70 +73,45 @@ fn palette() -> &'static HashMap<String, Color32> {
     })
 }
 
-#[derive(Clone)]
-pub struct OutCallbacker {
-    spec: AudioSpec,
-    shared_graph: SharedGraph,
-    // out_key: NodeKey,
-    last_time: Instant,
-    took: Duration,
-}
-
 // type SharedGraph = Arc<Mutex<Graph>>;
 // type SharedChannels = Arc<Mutex<SlotMap<ChannelId, SharedGraph>>>;
 
-pub fn create_shared_graph(
-    audio_subsystem: &mut AudioSubsystem,
-) -> (SharedGraph, AudioDevice<OutCallbacker>) {
-    let shared_graph = Arc::new(Mutex::new(Graph::new()));
-    // let out_key = shared_graph.lock().unwrap().add(Box::new(retn));
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44_100),
-        channels: Some(1),   // mono
-        samples: Some(1000), // default sample size
-    };
-
-    let device = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| OutCallbacker {
-            spec,
-            shared_graph: shared_graph.clone(),
-            // out_key,
-            last_time: Instant::now(),
-            took: Duration::new(0, 0),
-        })
-        .unwrap();
-
-    device.resume();
-    (shared_graph, device)
+struct AudioOutput {
+    backend: Box<dyn AudioBackend>,
+    control: Arc<OutputControl>,
+    device: Option<String>,
+    devices: Vec<String>,
+    requested: StreamConfig,
 }
 
-impl AudioCallback for OutCallbacker {
-    type Channel = f32;
-
-    fn callback(&mut self, sdl_out: &mut [f32]) {
-        let _now = Instant::now();
-
-        let mut graph = self.shared_graph.lock().unwrap();
-        let out_key = graph.output_node.unwrap();
-        for i in 0..sdl_out.len() {
-            graph.step(self.spec.freq as f32);
-            let output = graph.get_node(out_key).get(0);
-            sdl_out[i] = 0.5 * output;
-        }
-
-        self.took = self.last_time.elapsed();
-        self.last_time = Instant::now();
-
-        if self.took.as_secs_f32() > 3.0 {
-            println!("Warning very slow Graph::step()");
-        }
+impl AudioOutput {
+    fn open(&mut self, shared_graph: &SharedGraph) -> anyhow::Result<StreamConfig> {
+        let renderer = GraphRenderer::new(shared_graph.clone(), self.control.clone(), 0.5);
+        self.backend
+            .open(self.device.as_deref(), &self.requested, Box::new(renderer))
     }
 }
 
-fn create_graph(audio_subsystem: &mut AudioSubsystem) -> (SharedGraph, AudioDevice<OutCallbacker>) {
-    let (shared_graph, device) = create_shared_graph(audio_subsystem);
-
-    (shared_graph, device)
+fn create_graph() -> (SharedGraph, AudioOutput) {
+    let shared_graph = Arc::new(Mutex::new(Graph::new()));
+    let backend = backend_from_env("sdl").unwrap_or_else(|error| {
+        println!("No audio backend, falling back to null: {:?}", error);
+        Box::new(NullBackend::default())
+    });
+    let mut output = AudioOutput {
+        backend,
+        control: Arc::new(OutputControl::default()),
+        device: device_from_env(),
+        devices: Vec::new(),
+        requested: StreamConfig::from_env(1),
+    };
+    if let Err(error) = output.backend.open_capture(None) {
+        println!("No audio capture device: {:?}", error);
+    }
+    if let Err(error) = output.open(&shared_graph) {
+        println!("No audio output on {}: {:?}", output.backend.name(), error);
+    }
+    (shared_graph, output)
 }
 
 pub struct GraphState {
@@ -139,32 +123,90 @@ pub struct GraphState {
     save_name: String,
     current_patch: Option<String>,
     last_reload_time: Option<Instant>,
-    patch_files: Vec<String>,
+    library: PatchLibrary,
+    search: String,
+    // Tags of the current patch as typed, separated by commas
+    tags_text: String,
+    confirm_overwrite: Option<String>,
+    confirm_delete: Option<String>,
+    // File being renamed and its new name
+    renaming: Option<(String, String)>,
+    sample_rate: f32,
+    // Audition render in progress, and a finished one that is waiting to be
+    // handed to the audio callback
+    preview_receiver: Option<mpsc::Receiver<Vec<f32>>>,
+    pending_preview: Option<Vec<f32>>,
+    node_palette: Option<NodePalette>,
+    keyboard: Keyboard,
+    spectrum: SpectrumAnalyzer,
+    sample_files: Vec<String>,
+    // Canvas view
+    pan: egui::Vec2,
+    zoom: f32,
+    node_sizes: HashMap<NodeKey, egui::Vec2>,
+    arrange_requested: bool,
+    // Screen position where a rubber band selection started
+    select_from: Option<egui::Pos2>,
+    // Keys of the nested subgraphs open in the editor
+    path: Vec<NodeKey>,
+    // Undo history of the open graph and of the other levels that were edited
+    history: History,
+    history_path: Vec<NodeKey>,
+    histories: HashMap<Vec<NodeKey>, History>,
+    last_link_check: Instant,
+    // Files of the linked subgraphs as of the last check, read again without
+    // holding the graph when they change
+    links: Vec<(String, Option<SystemTime>)>,
+    // Contents and modification time of the current patch file when it was
+    // last loaded or saved
+    loaded_patch: String,
+    current_patch_modified: Option<SystemTime>,
 }
 
 struct SynthGui2 {
     shared_graph: SharedGraph,
     graph_state: GraphState,
+    output: AudioOutput,
 }
 
 impl GraphState {
+    // Keys may be stale after the graph was changed from the history
+    fn clear_selection(&mut self) {
+        self.drag_from = None;
+        self.selected_connection = None;
+        self.selected_nodes.clear();
+    }
+
+    fn sync_history(&mut self) {
+        if self.history_path == self.path {
+            return;
+        }
+        let history = self.histories.remove(&self.path).unwrap_or_default();
+        let old_history = std::mem::replace(&mut self.history, history);
+        let old_path = std::mem::replace(&mut self.history_path, self.path.clone());
+        self.histories.insert(old_path, old_history);
+        self.clear_selection();
+        self.node_sizes.clear();
+        self.pan = egui::Vec2::ZERO;
+    }
+
     fn update_patches(&mut self) {
-        self.patch_files = glob("*.patch")
+        self.library.scan();
+        self.sample_files = glob("*.wav")
             .unwrap()
             .filter_map(Result::ok)
-            .filter_map(|path| {
-                path.file_name()
-                    .map(|name| name.to_string_lossy().to_string())
-            })
+            .map(|path| path.to_string_lossy().to_string())
             .collect();
         self.last_reload_time = Some(Instant::now());
     }
 }
 
 impl SynthGui2 {
-    fn new(shared_graph: SharedGraph) -> Self {
+    fn new(shared_graph: SharedGraph, output: AudioOutput) -> Self {
+        let sample_rate = output.backend.config().unwrap_or_default().sample_rate as f32;
         Self {
             shared_graph,
+            output,
             graph_state: GraphState {
                 // selected_input_port: None,
                 // selected_output_port: None,
@@ -173,7 +215,32 @@ impl SynthGui2 {
                 selected_nodes: Vec::new(),
                 save_name: "".to_string(),
                 last_reload_time: None,
-                patch_files: Vec::new(),
+                library: PatchLibrary::new(),
+                search: String::new(),
+                tags_text: String::new(),
+                confirm_overwrite: None,
+                confirm_delete: None,
+                renaming: None,
+                sample_rate,
+                preview_receiver: None,
+                pending_preview: None,
+                node_palette: None,
+                keyboard: Keyboard::default(),
+                spectrum: SpectrumAnalyzer::default(),
+                sample_files: Vec::new(),
+                pan: egui::Vec2::ZERO,
+                zoom: 1.0,
+                node_sizes: HashMap::new(),
+                arrange_requested: false,
+                select_from: None,
+                path: Vec::new(),
+                history: History::default(),
+                history_path: Vec::new(),
+                histories: HashMap::new(),
+                last_link_check: Instant::now(),
+                links: Vec::new(),
+                loaded_patch: String::new(),
+                current_patch_modified: None,
                 current_patch: None,
             },
         }
@@ -206,199 +273,1125 @@ impl eframe::App for SynthGui2 {
         let Self {
             ref mut graph_state,
             ref mut shared_graph,
-            ..
+            ref mut output,
         } = self;
+        // Reopening the output waits for the audio callback, which in turn
+        // waits for the graph, so this has to happen before the graph is
+        // locked.
+        render_output_menu(ctx, output, shared_graph, graph_state);
+        update_preview(graph_state, &output.control);
+        // Reads the output from a ring buffer, not the graph
+        graph_state.spectrum.render(ctx, graph_state.sample_rate);
+        // Changed patch files are read before the graph is locked
+        let mut reloads = None;
+        if graph_state.last_link_check.elapsed() > LINK_CHECK_INTERVAL {
+            reloads = Some((
+                read_current_patch(graph_state),
+                Subgraph::read_changed_links(&graph_state.links),
+            ));
+            graph_state.last_link_check = Instant::now();
+        }
         let mut graph = shared_graph.lock().unwrap();
-        if ctx.input(|i| i.key_pressed(egui::Key::R)) {
+        // Before any shortcuts, the played keys are taken out of the input
+        graph_state.keyboard.handle_qwerty(ctx);
+        // Shift keeps R free for the computer keyboard
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::SHIFT, egui::Key::R)) {
             ctx.set_pixels_per_point(2.0);
         }
-        if ctx.input(|i| i.key_pressed(egui::Key::D)) {
+        egui::gui_zoom::zoom_with_keyboard_shortcuts(ctx, _frame.info().native_pixels_per_point);
+        ctx.request_repaint_after(Duration::from_millis(1000 / 60));
+
+        // Patches are always loaded into and saved from the top level graph
+        render_patch_menu(ctx, &mut graph, graph_state);
+        render_profiler_menu(ctx, &mut graph);
+        if let Some((patch, linked_patches)) = reloads {
+            if let Some(patch) = patch {
+                swap_current_patch(&mut graph, graph_state, patch);
+            }
+            Subgraph::apply_links(&mut graph, &linked_patches);
+            graph_state.links = Subgraph::links(&graph);
+        }
+
+        // Notes are played on the whole patch, whichever subgraph is open
+        egui::TopBottomPanel::bottom("piano").show(ctx, |ui| {
+            graph_state.keyboard.render(ui);
+        });
+
+        let path_names = subgraph_path_names(&graph, &graph_state.path);
+        graph_state.path.truncate(path_names.len());
+        graph_state.sync_history();
+        let path = graph_state.path.clone();
+        with_graph_at(&mut graph, &path, |graph| {
+            render_editor(ctx, graph, graph_state, &path_names)
+        });
+        refresh_subgraphs(&mut graph, &path);
+        // Builds the plan once for all edits of the frame
+        graph.publish();
+    }
+}
+
+fn render_editor(
+    ctx: &egui::Context,
+    graph: &mut Graph,
+    graph_state: &mut GraphState,
+    path_names: &[String],
+) {
+    if ctx.input_mut(|i| {
+        i.consume_key(
+            egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+            egui::Key::Z,
+        )
+    }) {
+        if graph_state.history.redo(graph) {
+            graph_state.clear_selection();
+        }
+    }
+    if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::Z)) {
+        if graph_state.history.undo(graph) {
+            graph_state.clear_selection();
+        }
+    }
+    if !ctx.wants_keyboard_input() {
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::A)) {
+            graph_state.selected_nodes = graph.node_order().clone();
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::D)) {
+            let copied = graph.copy_nodes(&graph_state.selected_nodes);
+            paste_nodes(graph, graph_state, &copied, "duplicate", [20.0, 20.0]);
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::G)) {
+            group_selection(graph, graph_state);
+        }
+        // Not while typing, e.g. in the node palette search field. Shift
+        // keeps D free for the computer keyboard.
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::SHIFT, egui::Key::D)) {
             if let Some(edge) = graph_state.selected_connection.clone() {
-                graph.disconnect_edge(edge.clone());
+                graph_state.history.disconnect(graph, edge.clone());
                 // self.graph_state.selected_input_port = None;
                 graph_state.selected_connection = None;
                 graph_state.selected_nodes = vec![edge.from.node, edge.to.node];
             }
         }
         if ctx.input(|i| i.key_pressed(egui::Key::F)) {
-            graph_state.selected_nodes.iter().for_each(|node_idx| {
-                graph.remove(*node_idx);
-            })
+            let selected_nodes = std::mem::take(&mut graph_state.selected_nodes);
+            graph_state.history.remove_nodes(graph, &selected_nodes);
+            graph_state.selected_connection = None;
+        }
+    }
+    egui::SidePanel::left("new_node_menu").show(ctx, |ui| {
+        if ui.button("auto-arrange").clicked() {
+            graph_state.arrange_requested = true;
+        }
+        if ui.button("group into subgraph").clicked() {
+            group_selection(graph, graph_state);
+        }
+        ui.separator();
+        render_new_node_menu(ui, graph, graph_state);
+    });
+    egui::CentralPanel::default().show(ctx, |ui| {
+        let mut node_rects: HashMap<NodeKey, egui::Rect> = HashMap::new();
+        let mut node_inputs_pos: HashMap<Port, egui::Pos2> = HashMap::new();
+        let mut node_outputs_pos: HashMap<Port, egui::Pos2> = HashMap::new();
+        // graph.sort();
+
+        render_history_menu(ctx, graph, graph_state);
+        if !path_names.is_empty() {
+            render_breadcrumbs(ui, graph_state, path_names);
         }
-        egui::gui_zoom::zoom_with_keyboard_shortcuts(ctx, _frame.info().native_pixels_per_point);
-        egui::CentralPanel::default().show(ctx, |ui| {
-            let mut node_rects: HashMap<NodeKey, egui::Rect> = HashMap::new();
-            let mut node_inputs_pos: HashMap<Port, egui::Pos2> = HashMap::new();
-            let mut node_outputs_pos: HashMap<Port, egui::Pos2> = HashMap::new();
-            // graph.sort();
-
-            render_patch_menu(ctx, ui, &mut graph, graph_state);
-
-            ctx.request_repaint_after(Duration::from_millis(1000 / 60));
-            let node_depths = graph.node_depths().clone();
-            let node_groups = graph
-                .node_order()
-                .clone()
-                .into_iter()
-                .group_by(|node_key| node_depths[node_key]);
-            ui.columns(2, |cols| {
-                render_new_node_menu(&mut cols[0], &mut graph, graph_state);
-                egui::ScrollArea::both().show(&mut cols[1], |ui| {
-                    ui.vertical(|ui| {
-                        for (_, group) in &node_groups {
-                            ui.horizontal(|ui| {
-                                for node_idx in group {
-                                    render_node(
-                                        ui,
-                                        &mut graph,
-                                        graph_state,
-                                        &node_idx,
-                                        &mut node_inputs_pos,
-                                        &mut node_outputs_pos,
-                                        &mut node_rects,
-                                    );
-                                }
-                            });
-                        }
-                    });
-                    for node_idx in graph.node_order().clone() {
-                        render_node_connections(
-                            ui,
-                            &mut graph,
-                            &node_idx,
-                            graph_state,
-                            &mut node_inputs_pos,
-                            &mut node_outputs_pos,
-                        )
-                    }
 
-                    match &graph_state.drag_from {
-                        Some(
-                            p @ Port {
-                                kind: PortKind::Input,
-                                ..
-                            },
-                        ) => {
-                            draw_bezier(
-                                ui,
-                                node_inputs_pos[&p],
-                                ctx.pointer_latest_pos().unwrap_or_default(),
-                                egui::Color32::RED,
-                            );
-                        }
-                        Some(
-                            p @ Port {
-                                kind: PortKind::Output,
-                                ..
-                            },
-                        ) => {
-                            draw_bezier(
-                                ui,
-                                node_outputs_pos[&p],
-                                ctx.pointer_latest_pos().unwrap_or_default(),
-                                egui::Color32::RED,
-                            );
-                        }
-                        _ => {}
-                    }
-                    if ui.input(|input| input.key_pressed(egui::Key::Escape)) {
-                        graph_state.drag_from = None;
-                    }
-                    ui.input(|input| {
-                        for evt in &input.events {
-                            match evt {
-                                egui::Event::PointerButton {
-                                    button: egui::PointerButton::Primary,
-                                    pressed: false,
-                                    ..
-                                } => {
-                                    graph_state.drag_from = None;
-                                }
-                                _ => {}
-                            }
-                        }
-                    });
-                });
-            })
+        render_canvas(
+            ctx,
+            ui,
+            graph,
+            graph_state,
+            &mut node_inputs_pos,
+            &mut node_outputs_pos,
+            &mut node_rects,
+        );
+    });
+}
+
+fn group_selection(graph: &mut Graph, graph_state: &mut GraphState) {
+    let selected_nodes = std::mem::take(&mut graph_state.selected_nodes);
+    if let Some(node_key) = graph_state
+        .history
+        .group_into_subgraph(graph, &selected_nodes)
+    {
+        graph_state.selected_nodes = vec![node_key];
+    }
+    graph_state.selected_connection = None;
+}
+
+// Names of the subgraphs along `path`, stops at the first key that isn't a
+// subgraph in its parent
+fn subgraph_path_names(graph: &Graph, path: &[NodeKey]) -> Vec<String> {
+    let Some((node_key, rest)) = path.split_first() else {
+        return vec![];
+    };
+    if !graph.has_node(*node_key) {
+        return vec![];
+    }
+    let node = graph.get_node(*node_key);
+    let Some(subgraph) = node.as_any().downcast_ref::<Subgraph>() else {
+        return vec![];
+    };
+    let name = if subgraph.name.is_empty() {
+        String::from("Subgraph")
+    } else {
+        subgraph.name.clone()
+    };
+    let mut names = vec![name];
+    names.extend(subgraph_path_names(&subgraph.subgraph, rest));
+    names
+}
+
+// Runs `f` on the graph of the subgraph at `path`, which has to be valid
+fn with_graph_at<R>(graph: &mut Graph, path: &[NodeKey], f: impl FnOnce(&mut Graph) -> R) -> R {
+    let Some((node_key, rest)) = path.split_first() else {
+        return f(graph);
+    };
+    let mut node = graph.get_node_mut(*node_key);
+    let subgraph = node.as_any_mut().downcast_mut::<Subgraph>().unwrap();
+    with_graph_at(&mut subgraph.subgraph, rest, f)
+}
+
+// Updates the interfaces of the subgraphs along `path` after their inner
+// graphs were edited, innermost first
+fn refresh_subgraphs(graph: &mut Graph, path: &[NodeKey]) {
+    let Some((node_key, rest)) = path.split_first() else {
+        return;
+    };
+    {
+        let mut node = graph.get_node_mut(*node_key);
+        let subgraph = node.as_any_mut().downcast_mut::<Subgraph>().unwrap();
+        refresh_subgraphs(&mut subgraph.subgraph, rest);
+    }
+    Subgraph::refresh(graph, *node_key);
+}
+
+fn render_breadcrumbs(ui: &mut egui::Ui, graph_state: &mut GraphState, path_names: &[String]) {
+    ui.horizontal(|ui| {
+        if ui.button("patch").clicked() {
+            graph_state.path.clear();
+        }
+        for (idx, name) in path_names.iter().enumerate() {
+            ui.label(">");
+            if ui.button(name).clicked() {
+                graph_state.path.truncate(idx + 1);
+            }
+        }
+    });
+}
+
+// How often the current patch and linked subgraph files are checked for changes
+const LINK_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+const DEFAULT_NODE_SIZE: egui::Vec2 = egui::vec2(150.0, 80.0);
+const ARRANGE_MARGIN: egui::Vec2 = egui::vec2(20.0, 40.0);
+
+// Lays out nodes in rows by their depth in the graph
+fn auto_arrange(graph: &mut Graph, node_sizes: &HashMap<NodeKey, egui::Vec2>) {
+    let node_depths = graph.node_depths().clone();
+    let node_groups = graph
+        .node_order()
+        .clone()
+        .into_iter()
+        .group_by(|node_key| node_depths[node_key]);
+    let mut y = 0.0;
+    for (_, group) in &node_groups {
+        let mut x = 0.0;
+        let mut row_height: f32 = 0.0;
+        for node_key in group {
+            let size = node_sizes
+                .get(&node_key)
+                .copied()
+                .unwrap_or(DEFAULT_NODE_SIZE);
+            graph.positions.insert(node_key, [x, y]);
+            x += size.x + ARRANGE_MARGIN.x;
+            row_height = row_height.max(size.y);
+        }
+        y += row_height + ARRANGE_MARGIN.y;
+    }
+}
+
+fn canvas_to_screen(
+    canvas_rect: egui::Rect,
+    graph_state: &GraphState,
+    pos: [f32; 2],
+) -> egui::Pos2 {
+    canvas_rect.min + graph_state.pan + egui::Vec2::from(pos) * graph_state.zoom
+}
+
+fn screen_to_canvas(
+    canvas_rect: egui::Rect,
+    graph_state: &GraphState,
+    pos: egui::Pos2,
+) -> [f32; 2] {
+    let v = (pos - canvas_rect.min - graph_state.pan) / graph_state.zoom;
+    [v.x, v.y]
+}
+
+fn zoomed_style(style: &egui::Style, zoom: f32) -> egui::Style {
+    let mut style = style.clone();
+    for font_id in style.text_styles.values_mut() {
+        font_id.size *= zoom;
+    }
+    style.spacing.item_spacing *= zoom;
+    style.spacing.button_padding *= zoom;
+    style.spacing.interact_size *= zoom;
+    style.spacing.icon_width *= zoom;
+    style.spacing.icon_spacing *= zoom;
+    style.spacing.window_margin = egui::Margin::same(style.spacing.window_margin.left * zoom);
+    style
+}
+
+fn render_canvas(
+    ctx: &egui::Context,
+    ui: &mut egui::Ui,
+    graph: &mut Graph,
+    graph_state: &mut GraphState,
+    node_inputs_pos: &mut HashMap<Port, egui::Pos2>,
+    node_outputs_pos: &mut HashMap<Port, egui::Pos2>,
+    node_rects: &mut HashMap<NodeKey, egui::Rect>,
+) {
+    let canvas_rect = ui.available_rect_before_wrap();
+    let canvas_id = ui.make_persistent_id("canvas");
+
+    if graph.positions.is_empty() || graph_state.arrange_requested {
+        auto_arrange(graph, &graph_state.node_sizes);
+        graph_state.arrange_requested = false;
+    }
+    // Nodes added since the last frame are placed in the middle of the view
+    let view_center = screen_to_canvas(canvas_rect, graph_state, canvas_rect.center());
+    for node_key in graph.node_order().clone() {
+        graph.positions.entry(node_key).or_insert(view_center);
+    }
+
+    let mut canvas_ui = ui.child_ui(canvas_rect, egui::Layout::top_down(egui::Align::Min));
+    canvas_ui.set_clip_rect(canvas_rect);
+    let ui = &mut canvas_ui;
+    let node_style = zoomed_style(ui.style(), graph_state.zoom);
+    for node_idx in graph.node_order().clone() {
+        let node_pos = canvas_to_screen(canvas_rect, graph_state, graph.positions[&node_idx]);
+        let mut node_ui = ui.child_ui(
+            egui::Rect::from_min_size(node_pos, egui::Vec2::splat(f32::INFINITY)),
+            egui::Layout::top_down(egui::Align::Min),
+        );
+        node_ui.set_clip_rect(canvas_rect);
+        node_ui.set_style(node_style.clone());
+        render_node(
+            &mut node_ui,
+            graph,
+            graph_state,
+            &node_idx,
+            node_inputs_pos,
+            node_outputs_pos,
+            node_rects,
+        );
+    }
+    for (node_key, rect) in node_rects.iter() {
+        graph_state
+            .node_sizes
+            .insert(*node_key, rect.size() / graph_state.zoom);
+    }
+
+    for node_idx in graph.node_order().clone() {
+        render_node_connections(
+            ui,
+            graph,
+            &node_idx,
+            graph_state,
+            node_inputs_pos,
+            node_outputs_pos,
+        )
+    }
+
+    match &graph_state.drag_from {
+        Some(
+            p @ Port {
+                kind: PortKind::Input,
+                ..
+            },
+        ) => {
+            draw_bezier(
+                ui,
+                node_inputs_pos[&p],
+                ctx.pointer_latest_pos().unwrap_or_default(),
+                egui::Color32::RED,
+            );
+        }
+        Some(
+            p @ Port {
+                kind: PortKind::Output,
+                ..
+            },
+        ) => {
+            draw_bezier(
+                ui,
+                node_outputs_pos[&p],
+                ctx.pointer_latest_pos().unwrap_or_default(),
+                egui::Color32::RED,
+            );
+        }
+        _ => {}
+    }
+    if ui.input(|input| input.key_pressed(egui::Key::Escape)) {
+        graph_state.drag_from = None;
+    }
+    ui.input(|input| {
+        for evt in &input.events {
+            match evt {
+                egui::Event::PointerButton {
+                    button: egui::PointerButton::Primary,
+                    pressed: false,
+                    ..
+                } => {
+                    graph_state.drag_from = None;
+                }
+                _ => {}
+            }
+        }
+    });
+
+    // Registered after the nodes so that nodes get first pick of clicks and drags
+    let canvas_response = ui.interact(canvas_rect, canvas_id, egui::Sense::click_and_drag());
+    if canvas_response.dragged_by(egui::PointerButton::Middle) {
+        graph_state.pan += canvas_response.drag_delta();
+    }
+    let shift = ui.input(|i| i.modifiers.shift);
+    if canvas_response.clicked() && !shift {
+        graph_state.selected_nodes.clear();
+    }
+    if canvas_response.drag_started_by(egui::PointerButton::Primary) {
+        graph_state.select_from = canvas_response.interact_pointer_pos();
+    }
+    if let (Some(select_from), Some(pointer)) = (graph_state.select_from, ctx.pointer_latest_pos())
+    {
+        let select_rect = egui::Rect::from_two_pos(select_from, pointer);
+        ui.painter().rect(
+            select_rect,
+            0.0,
+            egui::Color32::WHITE.gamma_multiply(0.05),
+            egui::Stroke::new(1.0, egui::Color32::WHITE.gamma_multiply(0.5)),
+        );
+        if canvas_response.drag_released() {
+            if !shift {
+                graph_state.selected_nodes.clear();
+            }
+            for (node_key, rect) in node_rects.iter() {
+                if select_rect.intersects(*rect) && !graph_state.selected_nodes.contains(node_key) {
+                    graph_state.selected_nodes.push(*node_key);
+                }
+            }
+            graph_state.select_from = None;
+        }
+    }
+    handle_clipboard(ctx, graph, graph_state, canvas_rect);
+    // Shown from the frame after it opens, so the space that opened it isn't
+    // typed into the search field
+    render_node_palette(ctx, graph, graph_state, canvas_rect);
+    let open_palette = canvas_response.secondary_clicked()
+        || (!ctx.wants_keyboard_input()
+            && ui.input(|i| i.key_pressed(egui::Key::Space))
+            && ctx
+                .pointer_latest_pos()
+                .is_some_and(|pointer| canvas_rect.contains(pointer)));
+    if open_palette {
+        let pos = ctx.pointer_latest_pos().unwrap_or(canvas_rect.center());
+        graph_state.node_palette = Some(NodePalette {
+            pos,
+            query: String::new(),
         });
     }
+    if let Some(pointer) = ctx.pointer_latest_pos() {
+        if canvas_rect.contains(pointer) {
+            let over_node = node_rects.values().any(|rect| rect.contains(pointer));
+            let (zoom_delta, scroll_delta) = ui.input(|i| (i.zoom_delta(), i.scroll_delta));
+            if zoom_delta != 1.0 {
+                // Keep the canvas point under the pointer fixed while zooming
+                let anchor = screen_to_canvas(canvas_rect, graph_state, pointer);
+                graph_state.zoom = (graph_state.zoom * zoom_delta).clamp(0.25, 4.0);
+                graph_state.pan =
+                    pointer - canvas_rect.min - egui::Vec2::from(anchor) * graph_state.zoom;
+            } else if !over_node {
+                graph_state.pan += scroll_delta;
+            }
+        }
+    }
 }
 
-fn render_patch_menu(
+// Copy, cut and paste node groups as ron text, the same format as patch files
+fn handle_clipboard(
     ctx: &egui::Context,
-    _ui: &mut egui::Ui,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
+    canvas_rect: egui::Rect,
 ) {
+    if ctx.wants_keyboard_input() {
+        return;
+    }
+    let events = ctx.input(|i| i.events.clone());
+    for event in events {
+        match event {
+            egui::Event::Copy | egui::Event::Cut if !graph_state.selected_nodes.is_empty() => {
+                let copied = graph.copy_nodes(&graph_state.selected_nodes);
+                match ron::ser::to_string_pretty(&copied, ron::ser::PrettyConfig::default()) {
+                    Ok(text) => ctx.output_mut(|o| o.copied_text = text),
+                    Err(error) => println!("{:?}", error),
+                }
+                if event == egui::Event::Cut {
+                    let selected_nodes = std::mem::take(&mut graph_state.selected_nodes);
+                    graph_state.history.remove_nodes(graph, &selected_nodes);
+                }
+            }
+            egui::Event::Paste(text) => match ron::from_str::<Graph>(&text) {
+                Ok(pasted) => {
+                    // Place the top left corner of the group at the pointer
+                    let target = ctx
+                        .pointer_latest_pos()
+                        .filter(|pointer| canvas_rect.contains(*pointer))
+                        .unwrap_or(canvas_rect.center());
+                    let target = screen_to_canvas(canvas_rect, graph_state, target);
+                    let min = pasted
+                        .positions
+                        .values()
+                        .fold([f32::INFINITY; 2], |min, pos| {
+                            [min[0].min(pos[0]), min[1].min(pos[1])]
+                        });
+                    let offset = if min[0].is_finite() {
+                        [target[0] - min[0], target[1] - min[1]]
+                    } else {
+                        [0.0, 0.0]
+                    };
+                    paste_nodes(graph, graph_state, &pasted, "paste", offset);
+                }
+                Err(error) => println!("Clipboard is not a patch: {:?}", error),
+            },
+            _ => {}
+        }
+    }
+}
+
+fn paste_nodes(
+    graph: &mut Graph,
+    graph_state: &mut GraphState,
+    nodes: &Graph,
+    action: &str,
+    offset: [f32; 2],
+) {
+    if nodes.node_order().is_empty() {
+        return;
+    }
+    let label = format!("{} {} nodes", action, nodes.node_order().len());
+    let node_keys = graph_state.history.paste(graph, &label, nodes);
+    for node_key in &node_keys {
+        if let Some(pos) = graph.positions.get_mut(node_key) {
+            pos[0] += offset[0];
+            pos[1] += offset[1];
+        }
+    }
+    graph_state.selected_nodes = node_keys;
+    graph_state.selected_connection = None;
+}
+
+fn modified_time(filename: &str) -> Option<SystemTime> {
+    std::fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+// A newer version of the current patch, read from disk before the graph is
+// locked
+struct PatchReload {
+    filename: String,
+    file_contents: String,
+    graph: Graph,
+    unchanged: Vec<NodeKey>,
+}
+
+// Reads the current patch when its file was changed by something else. The
+// audio callback waits for the graph lock, so reading, parsing and comparing
+// to the loaded version happen before it is taken.
+fn read_current_patch(graph_state: &mut GraphState) -> Option<PatchReload> {
+    let filename = graph_state.current_patch.clone()?;
+    let modified = modified_time(&filename);
+    if modified.is_none() || modified == graph_state.current_patch_modified {
+        return None;
+    }
+    graph_state.current_patch_modified = modified;
+    let file_contents = std::fs::read_to_string(&filename).ok()?;
+    if file_contents == graph_state.loaded_patch {
+        return None;
+    }
+    let mut graph: Graph = match ron::from_str(&file_contents) {
+        Ok(graph) => graph,
+        Err(error) => {
+            println!("Couldn't reload {}: {:?}", filename, error);
+            return None;
+        }
+    };
+    // Compared as saved, before either is upgraded by sorting
+    let previous =
+        ron::from_str::<Graph>(&graph_state.loaded_patch).unwrap_or_else(|_| Graph::new());
+    let unchanged = graph.unchanged_nodes(&previous);
+    if graph.output_node.is_none() {
+        graph.output_node = graph.get_by_type_mut::<Out>().map(|(out_key, _)| out_key);
+    }
+    graph.sort();
+    Some(PatchReload {
+        filename,
+        file_contents,
+        graph,
+        unchanged,
+    })
+}
+
+// Replaces the running graph with the reloaded patch, as an edit that can be
+// undone. The audio callback holds the graph lock for a whole buffer, so the
+// new graph takes over at a buffer boundary.
+fn swap_current_patch(graph: &mut Graph, graph_state: &mut GraphState, reload: PatchReload) {
+    let PatchReload {
+        filename,
+        file_contents,
+        graph: mut new_graph,
+        unchanged,
+    } = reload;
+    let kept = new_graph.take_running_nodes(graph, &unchanged);
+    println!("Reloaded {}, kept the state of {} nodes", filename, kept);
+    graph_state.path.clear();
+    graph_state.sync_history();
+    graph_state
+        .history
+        .reload_patch(graph, &filename, new_graph);
+    graph_state.loaded_patch = file_contents;
+    graph_state.clear_selection();
+}
+
+enum PatchAction {
+    Load(PatchEntry),
+    Audition(String),
+    Rename(String, String),
+    Delete(String),
+}
+
+fn load_patch(graph: &mut Graph, graph_state: &mut GraphState, entry: &PatchEntry) {
+    let file_contents = match std::fs::read_to_string(&entry.file) {
+        Ok(file_contents) => file_contents,
+        Err(error) => {
+            println!("Couldn't read {}: {:?}", entry.file, error);
+            return;
+        }
+    };
+    let result_graph: Result<Graph, _> = ron::from_str(&file_contents);
+    match result_graph {
+        Ok(mut loaded_graph) => {
+            if loaded_graph.output_node.is_none() {
+                let mut out = None;
+                if let Some((out_key, _)) = loaded_graph.get_by_type_mut::<Out>() {
+                    out = Some(out_key);
+                }
+                loaded_graph.output_node = out;
+            }
+            loaded_graph.sort();
+            graph_state.path.clear();
+            graph_state.sync_history();
+            graph_state
+                .history
+                .load_patch(graph, &entry.name, loaded_graph);
+            graph_state.current_patch_modified = modified_time(&entry.file);
+            graph_state.loaded_patch = file_contents;
+            graph_state.clear_selection();
+            graph_state.current_patch = Some(entry.file.clone());
+            graph_state.save_name = entry.name.clone();
+            graph_state.keyboard.all_notes_off();
+        }
+        Err(error) => println!("{:?}", error),
+    }
+}
+
+fn save_patch(graph: &Graph, graph_state: &mut GraphState, file: String) {
+    // Saved without remapping keys, so the keys in the file match the running
+    // graph when it is hot reloaded
+    let serialized = ron::ser::to_string_pretty(graph, ron::ser::PrettyConfig::default()).unwrap();
+    if let Some(parent) = Path::new(&file).parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let result = File::create(&file).and_then(|mut f| f.write_all(serialized.as_bytes()));
+    if let Err(error) = result {
+        println!("Couldn't save {}: {:?}", file, error);
+        return;
+    }
+    graph_state.current_patch_modified = modified_time(&file);
+    graph_state.loaded_patch = serialized;
+    graph_state.current_patch = Some(file);
+    graph_state.last_reload_time = None;
+}
+
+fn audition_patch(graph_state: &mut GraphState, file: String) {
+    let (sender, receiver) = mpsc::channel();
+    let sample_rate = graph_state.sample_rate;
+    std::thread::spawn(move || match render_preview(&file, sample_rate) {
+        Ok(samples) => {
+            let _ = sender.send(samples);
+        }
+        Err(error) => println!("Couldn't audition {}: {:?}", file, error),
+    });
+    graph_state.preview_receiver = Some(receiver);
+}
+
+// Hands a finished audition render to the audio callback
+fn update_preview(graph_state: &mut GraphState, control: &OutputControl) {
+    if let Some(receiver) = &graph_state.preview_receiver {
+        match receiver.try_recv() {
+            Ok(samples) => {
+                graph_state.pending_preview = Some(samples);
+                graph_state.preview_receiver = None;
+            }
+            Err(mpsc::TryRecvError::Disconnected) => graph_state.preview_receiver = None,
+            Err(mpsc::TryRecvError::Empty) => {}
+        }
+    }
+    if let Some(samples) = graph_state.pending_preview.take() {
+        control.play_preview(samples);
+    }
+}
+
+fn parse_tags(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_string())
+        .collect()
+}
+
+fn render_patch_entry(
+    ui: &mut egui::Ui,
+    entry: &PatchEntry,
+    label: &str,
+    graph_state: &mut GraphState,
+) -> Option<PatchAction> {
+    let mut action = None;
+    ui.horizontal(|ui| {
+        if let Some((file, new_name)) = &mut graph_state.renaming {
+            if *file == entry.file {
+                ui.add(egui::TextEdit::singleline(new_name).desired_width(120.0));
+                if ui.small_button("ok").clicked() {
+                    action = Some(PatchAction::Rename(file.clone(), new_name.clone()));
+                }
+                if ui.small_button("cancel").clicked() {
+                    graph_state.renaming = None;
+                }
+                return;
+            }
+        }
+        if graph_state.confirm_delete.as_ref() == Some(&entry.file) {
+            ui.label(format!("delete {}?", entry.name));
+            if ui.small_button("yes").clicked() {
+                action = Some(PatchAction::Delete(entry.file.clone()));
+            }
+            if ui.small_button("no").clicked() {
+                graph_state.confirm_delete = None;
+            }
+            return;
+        }
+
+        let color = match &graph_state.current_patch {
+            Some(current_patch) if *current_patch == entry.file => {
+                egui::Color32::from_rgb(0, 128, 0)
+            }
+            _ => Default::default(),
+        };
+        let mut response = ui.add(egui::Button::new(label).fill(color));
+        if !entry.meta.description.is_empty() || !entry.meta.tags.is_empty() {
+            response = response.on_hover_text(format!(
+                "{}\n[{}]",
+                entry.meta.description,
+                entry.meta.tags.join(", ")
+            ));
+        }
+        if response.clicked() {
+            action = Some(PatchAction::Load(entry.clone()));
+        }
+        if ui.small_button("▶").on_hover_text("audition").clicked() {
+            action = Some(PatchAction::Audition(entry.file.clone()));
+        }
+        if ui.small_button("rename").clicked() {
+            graph_state.renaming = Some((entry.file.clone(), entry.name.clone()));
+        }
+        if ui.small_button("delete").clicked() {
+            graph_state.confirm_delete = Some(entry.file.clone());
+        }
+    });
+    action
+}
+
+fn render_patch_menu(ctx: &egui::Context, graph: &mut Graph, graph_state: &mut GraphState) {
     egui::Window::new("Patches").show(ctx, |ui| {
-        {
-            let _response =
-                ui.add(egui::TextEdit::singleline(&mut graph_state.save_name).desired_width(100.0));
-            if ui.add(egui::Button::new("save")).clicked() {
-                let graph_copy = graph.copy();
-                let serialized =
-                    ron::ser::to_string_pretty(&graph_copy, ron::ser::PrettyConfig::default())
-                        .unwrap();
-                let name = format!("{}.patch", graph_state.save_name);
-                let mut file = File::create(&name).unwrap();
-                file.write_all(serialized.as_bytes()).unwrap();
-                graph_state.current_patch = Some(name);
+        ui.horizontal(|ui| {
+            ui.label("library");
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut graph_state.library.root).desired_width(160.0),
+            );
+            if response.changed() {
                 graph_state.last_reload_time = None;
             }
-            if let Some(last_reload_time) = graph_state.last_reload_time {
-                if last_reload_time.elapsed().as_secs() > 2 {
-                    graph_state.update_patches();
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut graph_state.save_name)
+                    .hint_text("folder/name")
+                    .desired_width(120.0),
+            );
+            if ui.add(egui::Button::new("save")).clicked() && !graph_state.save_name.is_empty() {
+                let file = graph_state.library.file_for(&graph_state.save_name);
+                let is_current = graph_state.current_patch.as_ref() == Some(&file);
+                if !is_current && Path::new(&file).exists() {
+                    graph_state.confirm_overwrite = Some(file);
+                } else {
+                    save_patch(graph, graph_state, file);
                 }
-            } else {
+            }
+        });
+        if let Some(file) = graph_state.confirm_overwrite.clone() {
+            ui.horizontal(|ui| {
+                ui.label(format!("overwrite {}?", file));
+                if ui.small_button("yes").clicked() {
+                    save_patch(graph, graph_state, file);
+                    graph_state.confirm_overwrite = None;
+                }
+                if ui.small_button("no").clicked() {
+                    graph_state.confirm_overwrite = None;
+                }
+            });
+        }
+        egui::CollapsingHeader::new("description and tags").show(ui, |ui| {
+            ui.add(
+                egui::TextEdit::multiline(&mut graph.meta.description)
+                    .hint_text("description")
+                    .desired_rows(2),
+            );
+            // Keep the typed text while it parses to the same tags, so that a
+            // trailing comma isn't removed while typing
+            if parse_tags(&graph_state.tags_text) != graph.meta.tags {
+                graph_state.tags_text = graph.meta.tags.join(", ");
+            }
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut graph_state.tags_text)
+                    .hint_text("tags, comma separated"),
+            );
+            if response.changed() {
+                graph.meta.tags = parse_tags(&graph_state.tags_text);
+            }
+        });
+
+        if let Some(last_reload_time) = graph_state.last_reload_time {
+            if last_reload_time.elapsed().as_secs() > 2 {
                 graph_state.update_patches();
-                graph_state.last_reload_time = Some(Instant::now());
             }
+        } else {
+            graph_state.update_patches();
+            graph_state.last_reload_time = Some(Instant::now());
         }
-        for file in graph_state.patch_files.clone() {
-            if file.contains(&graph_state.save_name) || graph_state.save_name.is_empty() {
-                let patch_name = file.split(".patch").nth(0).unwrap();
-                let color = match &graph_state.current_patch {
-                    Some(current_patch) => match &file {
-                        path if path == current_patch => egui::Color32::from_rgb(0, 128, 0),
-                        _ => Default::default(),
-                    },
-                    _ => Default::default(),
-                };
-                if ui.add(egui::Button::new(patch_name).fill(color)).clicked() {
-                    let file_contents = std::fs::read_to_string(&file).unwrap();
-                    let result_graph: Result<Graph, _> = ron::from_str(&file_contents);
-                    match result_graph {
-                        Ok(loaded_graph) => {
-                            **graph = loaded_graph;
-                            if graph.output_node.is_none() {
-                                let mut out = None;
-                                if let Some((out_key, _)) = graph.get_by_type_mut::<Out>() {
-                                    out = Some(out_key);
+
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut graph_state.search)
+                    .hint_text("search")
+                    .desired_width(120.0),
+            );
+            if graph_state.preview_receiver.is_some() {
+                ui.spinner();
+            }
+            if ui
+                .small_button("stop")
+                .on_hover_text("stop audition")
+                .clicked()
+            {
+                graph_state.preview_receiver = None;
+                graph_state.pending_preview = Some(Vec::new());
+            }
+        });
+
+        let mut action = None;
+        let entries: Vec<PatchEntry> = graph_state
+            .library
+            .search(&graph_state.search)
+            .into_iter()
+            .cloned()
+            .collect();
+        egui::ScrollArea::vertical()
+            .max_height(300.0)
+            .show(ui, |ui| {
+                if graph_state.search.is_empty() {
+                    // Entries are sorted by folder, so each folder is one group
+                    for (folder, entries) in &entries.iter().group_by(|entry| entry.folder()) {
+                        let mut render_entries = |ui: &mut egui::Ui| {
+                            for entry in entries {
+                                if let Some(a) =
+                                    render_patch_entry(ui, entry, entry.short_name(), graph_state)
+                                {
+                                    action = Some(a);
                                 }
-                                graph.output_node = out;
                             }
-                            graph.sort();
-                            graph_state.current_patch = Some(file.clone());
-                            graph_state.save_name = patch_name[..].to_string();
+                        };
+                        if folder.is_empty() {
+                            render_entries(ui);
+                        } else {
+                            egui::CollapsingHeader::new(folder)
+                                .default_open(true)
+                                .show(ui, render_entries);
+                        }
+                    }
+                } else {
+                    for entry in &entries {
+                        if let Some(a) = render_patch_entry(ui, entry, &entry.name, graph_state) {
+                            action = Some(a);
+                        }
+                    }
+                }
+            });
+
+        match action {
+            Some(PatchAction::Load(entry)) => load_patch(graph, graph_state, &entry),
+            Some(PatchAction::Audition(file)) => audition_patch(graph_state, file),
+            Some(PatchAction::Rename(file, new_name)) => {
+                match graph_state.library.rename(&file, &new_name) {
+                    Ok(new_file) => {
+                        if graph_state.current_patch.as_ref() == Some(&file) {
+                            graph_state.current_patch_modified = modified_time(&new_file);
+                            graph_state.current_patch = Some(new_file);
+                            graph_state.save_name = new_name;
                         }
-                        Err(error) => println!("{:?}", error),
+                        graph_state.renaming = None;
+                    }
+                    Err(error) => println!("Couldn't rename {}: {:?}", file, error),
+                }
+            }
+            Some(PatchAction::Delete(file)) => {
+                if let Err(error) = graph_state.library.delete(&file) {
+                    println!("Couldn't delete {}: {:?}", file, error);
+                }
+                if graph_state.current_patch.as_ref() == Some(&file) {
+                    graph_state.current_patch = None;
+                    graph_state.current_patch_modified = None;
+                }
+                graph_state.confirm_delete = None;
+            }
+            None => {}
+        }
+    });
+}
+
+fn render_history_menu(ctx: &egui::Context, graph: &mut Graph, graph_state: &mut GraphState) {
+    egui::Window::new("History").show(ctx, |ui| {
+        let mut undo_steps = 0;
+        let mut redo_steps = 0;
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                let undo_entries = graph_state.history.undo_entries();
+                for (idx, entry) in undo_entries.iter().enumerate() {
+                    let is_current = idx + 1 == undo_entries.len();
+                    if ui.selectable_label(is_current, &entry.label).clicked() {
+                        undo_steps = undo_entries.len() - idx - 1;
+                    }
+                }
+                let redo_entries = graph_state.history.redo_entries();
+                for (idx, entry) in redo_entries.iter().enumerate().rev() {
+                    let label = egui::RichText::new(&entry.label).weak();
+                    if ui.selectable_label(false, label).clicked() {
+                        redo_steps = redo_entries.len() - idx;
+                    }
+                }
+            });
+        ui.horizontal(|ui| {
+            if ui.button("undo").clicked() {
+                undo_steps = 1;
+            }
+            if ui.button("redo").clicked() {
+                redo_steps = 1;
+            }
+        });
+        if undo_steps + redo_steps > 0 {
+            for _ in 0..undo_steps {
+                graph_state.history.undo(graph);
+            }
+            for _ in 0..redo_steps {
+                graph_state.history.redo(graph);
+            }
+            graph_state.clear_selection();
+        }
+    });
+}
+
+// Outlines a node in a color from blue to red by its share of the block time
+fn draw_node_heat(ui: &mut egui::Ui, rect: egui::Rect, profiler: &Profiler, node_key: NodeKey) {
+    let Some(timing) = profiler.node(node_key) else {
+        return;
+    };
+    let share = profiler.node_share(node_key);
+    let heat = share.sqrt();
+    let color = lerp_color(
+        egui::Color32::from_rgb(0x1E, 0x90, 0xFF),
+        egui::Color32::from_rgb(0xFF, 0x20, 0x00),
+        heat,
+    );
+    let painter = ui.painter();
+    painter.rect_stroke(rect, 4.0, egui::Stroke::new(1.0 + 3.0 * heat, color));
+    painter.text(
+        rect.right_top() + egui::vec2(-2.0, 2.0),
+        egui::Align2::RIGHT_TOP,
+        format!(
+            "{:.1}µs {:.0}%",
+            timing.average.as_secs_f32() * 1e6,
+            100.0 * share
+        ),
+        egui::FontId::monospace(9.0),
+        color,
+    );
+}
+
+// DSP load of the whole patch and, with profiling on, the most expensive nodes
+fn render_profiler_menu(ctx: &egui::Context, graph: &mut Graph) {
+    egui::Window::new("Profiler")
+        .default_open(false)
+        .show(ctx, |ui| {
+            let mut enabled = profiling();
+            if ui
+                .checkbox(&mut enabled, "profile nodes")
+                .on_hover_text("Times every node step, shows a heat map in the editor")
+                .changed()
+            {
+                set_profiling(enabled);
+                graph.profiler.reset();
+            }
+            let mut parallel_enabled = parallel();
+            if ui
+                .checkbox(&mut parallel_enabled, "parallel")
+                .on_hover_text(
+                    "Steps independent branches on all cores, not while profiling nodes. \
+                     Not real time safe, may cause dropouts.",
+                )
+                .changed()
+            {
+                set_parallel(parallel_enabled);
+            }
+            let profiler = &graph.profiler;
+            ui.add(
+                egui::ProgressBar::new(profiler.dsp_load.min(1.0))
+                    .text(format!("dsp load {:.1}%", 100.0 * profiler.dsp_load)),
+            );
+            ui.label(format!(
+                "peak {:.1}%, {} underruns in {} blocks",
+                100.0 * profiler.peak_load,
+                profiler.underruns,
+                profiler.blocks
+            ));
+            if ui.button("reset").clicked() {
+                graph.profiler.reset();
+            }
+            if enabled {
+                ui.separator();
+                let summary = graph.profile_summary();
+                egui::Grid::new("profile_nodes")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for node in summary.nodes.iter().take(12) {
+                            ui.label(&node.name);
+                            ui.label(format!("{:.1}µs", node.average.as_secs_f32() * 1e6));
+                            ui.label(format!("{:.0}%", 100.0 * node.share));
+                            ui.end_row();
+                        }
+                    });
+            }
+        });
+}
+
+fn render_output_menu(
+    ctx: &egui::Context,
+    output: &mut AudioOutput,
+    shared_graph: &SharedGraph,
+    graph_state: &mut GraphState,
+) {
+    egui::Window::new("Output").show(ctx, |ui| {
+        let config = output.backend.config();
+        match &config {
+            Some(config) => ui.label(format!(
+                "{} {} Hz, {} ch, {} frames",
+                output.backend.name(),
+                config.sample_rate,
+                config.channels,
+                config.buffer_size
+            )),
+            None => ui.label(format!("{} closed", output.backend.name())),
+        };
+        let mut reopen = false;
+        ui.horizontal(|ui| {
+            egui::ComboBox::new("output_device", "")
+                .selected_text(output.device.as_deref().unwrap_or("default"))
+                .show_ui(ui, |ui| {
+                    reopen |= ui
+                        .selectable_value(&mut output.device, None, "default")
+                        .changed();
+                    for device in &output.devices {
+                        reopen |= ui
+                            .selectable_value(&mut output.device, Some(device.clone()), device)
+                            .changed();
                     }
+                });
+            if ui.button("scan").clicked() {
+                output.devices = output.backend.devices().unwrap_or_else(|error| {
+                    println!("Failed to list audio devices: {:?}", error);
+                    Vec::new()
+                });
+            }
+            reopen |= ui.button("reopen").clicked();
+        });
+        // The patch keeps running, its nodes are prepared for the new rate
+        egui::ComboBox::new("output_rate", "sample rate")
+            .selected_text(format!("{}", output.requested.sample_rate))
+            .show_ui(ui, |ui| {
+                for rate in SAMPLE_RATES {
+                    reopen |= ui
+                        .selectable_value(
+                            &mut output.requested.sample_rate,
+                            rate,
+                            format!("{}", rate),
+                        )
+                        .changed();
                 }
+            });
+        if reopen {
+            match output.open(shared_graph) {
+                Ok(config) => graph_state.sample_rate = config.sample_rate as f32,
+                Err(error) => println!("Failed to open audio output: {:?}", error),
             }
         }
+        let control = &output.control;
+        let mut limiter_enabled = control.limiter_enabled.load(Ordering::Relaxed);
+        if ui
+            .checkbox(&mut limiter_enabled, "safety limiter")
+            .changed()
+        {
+            control
+                .limiter_enabled
+                .store(limiter_enabled, Ordering::Relaxed);
+        }
+        ui.label(format!("gain {:.2}", control.limiter_gain()));
     });
 }
 
 fn draw_sequencer(
     ui: &mut egui::Ui,
     node_key: NodeKey,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
 ) {
     let mut node = graph.get_node_mut(node_key);
     let v: &mut dyn Any = node.as_any_mut();
 
     let sequencer: &mut Sequencer = v.downcast_mut::<Sequencer>().unwrap();
+    let old_sequence = sequencer.sequence.clone();
+    let mut scrolled = false;
     let hover_column_id = ui.make_persistent_id(node_key);
     let hovered_column: Option<usize> = ui.memory(|mem| mem.data.get_temp(hover_column_id));
     enum Action {
@@ -506,8 +1499,10 @@ fn draw_sequencer(
                             ui.input(|input| {
                                 if input.scroll_delta.y > 0.0 {
                                     note.octave += 1;
+                                    scrolled = true;
                                 } else if input.scroll_delta.y < 0.0 {
                                     note.octave -= 1;
+                                    scrolled = true;
                                 }
                             });
                         }
@@ -516,6 +1511,18 @@ fn draw_sequencer(
             }
         });
     });
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut sequencer.follow_transport, "transport")
+            .on_hover_text("Step with the position of the JACK transport instead of trigger_in");
+        if sequencer.follow_transport {
+            ui.add(
+                egui::DragValue::new(&mut sequencer.steps_per_beat)
+                    .clamp_range(1.0..=16.0)
+                    .suffix(" steps/beat"),
+            );
+        }
+    });
+    let edited_columns = action.is_some();
     match action {
         Some(Action::AddColumn(col_idx)) => {
             sequencer.sequence.insert(
@@ -532,6 +1539,18 @@ fn draw_sequencer(
         }
         _ => {}
     };
+    if sequencer.sequence != old_sequence {
+        // One scroll gesture changes the octave over several frames
+        let merge = scrolled && !edited_columns;
+        graph_state.history.push(
+            "edit sequence",
+            Edit::SetSequence {
+                node: node_key,
+                sequence: old_sequence,
+            },
+            merge,
+        );
+    }
     for (color, rect) in leds {
         draw_led(ui, color, &rect);
     }
@@ -552,12 +1571,7 @@ fn draw_led(ui: &mut egui::Ui, color: Color32, rect: &egui::Rect) {
     );
 }
 
-fn draw_out(
-    ui: &mut egui::Ui,
-    node_key: NodeKey,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
-    graph_state: &mut GraphState,
-) {
+fn draw_out(ui: &mut egui::Ui, node_key: NodeKey, graph: &mut Graph, graph_state: &mut GraphState) {
     if let Some(ref buff) = graph.get_node(node_key).buff() {
         let points: PlotPoints = buff
             .iter()
@@ -573,42 +1587,264 @@ fn draw_out(
     }
 }
 
+// Scope trace colors and names, by input port
+const TRACE_COLORS: [egui::Color32; SCOPE_TRACES] = [
+    yellow_of_spacesuit,
+    blue_of_earth,
+    red_of_hal,
+    white_of_spaceship,
+];
+const TRACE_NAMES: [&str; SCOPE_TRACES] = ["a", "b", "c", "d"];
+
+fn draw_scope(
+    ui: &mut egui::Ui,
+    node_key: NodeKey,
+    graph: &mut Graph,
+    _graph_state: &mut GraphState,
+) {
+    let connected: Vec<usize> = graph.node_inputs()[&node_key]
+        .iter()
+        .map(|edge| edge.to.port)
+        .collect();
+    let mut node = graph.get_node_mut(node_key);
+    let Some(scope) = node.as_any_mut().downcast_mut::<Scope>() else {
+        return;
+    };
+    ui.horizontal(|ui| {
+        egui::ComboBox::new((node_key, "trigger_trace"), "")
+            .selected_text(format!("trig {}", TRACE_NAMES[scope.trigger_trace]))
+            .width(60.0)
+            .show_ui(ui, |ui| {
+                for (idx, name) in TRACE_NAMES.iter().enumerate() {
+                    ui.selectable_value(&mut scope.trigger_trace, idx, *name);
+                }
+            });
+        ui.add(
+            egui::DragValue::new(&mut scope.trigger_level)
+                .speed(0.01)
+                .prefix("level "),
+        );
+        let slope = if scope.trigger_rising {
+            "rising"
+        } else {
+            "falling"
+        };
+        if ui.button(slope).clicked() {
+            scope.trigger_rising = !scope.trigger_rising;
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::DragValue::new(&mut scope.timebase)
+                .speed(0.1)
+                .clamp_range(0.1..=500.0)
+                .suffix(" ms"),
+        )
+        .on_hover_text("timebase");
+        ui.add(
+            egui::DragValue::new(&mut scope.hold_off)
+                .speed(0.1)
+                .clamp_range(0.0..=1000.0)
+                .prefix("hold-off ")
+                .suffix(" ms"),
+        );
+        ui.checkbox(&mut scope.free_run, "free run");
+    });
+
+    let sweep = scope.find_sweep();
+    let sweep_len = scope.sweep_len();
+    let ms_per_sample = 1000.0 / scope.tap.0.sample_rate() as f64;
+    let zoom = ui.style().spacing.interact_size.y / 18.0;
+    Plot::new((node_key, "scope"))
+        .height(100.0 * zoom)
+        .width(200.0 * zoom)
+        .include_y(-1.0)
+        .include_y(1.0)
+        .include_x(0.0)
+        .include_x(sweep_len as f64 * ms_per_sample)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .show(ui, |plot_ui| {
+            let Some(start) = sweep else {
+                return;
+            };
+            for (idx, trace) in scope.tap.0.traces.iter().enumerate() {
+                if !connected.contains(&idx) {
+                    continue;
+                }
+                let points: PlotPoints = (0..sweep_len)
+                    .map(|i| [i as f64 * ms_per_sample, trace.read(start + i) as f64])
+                    .collect();
+                plot_ui.line(
+                    Line::new(points)
+                        .color(TRACE_COLORS[idx])
+                        .name(TRACE_NAMES[idx]),
+                );
+            }
+        });
+}
+
 fn draw_subgraph(
     ui: &mut egui::Ui,
     node_key: NodeKey,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
 ) {
-    let mut to_load_filename: Option<String> = None;
-    let combo = egui::ComboBox::new(node_key, "Select patch").show_ui(ui, |ui| {
-        for patch_filename in graph_state.patch_files.clone() {
-            let mut v: i32 = 0;
-            if ui
-                .selectable_value(&mut v, 1, patch_filename.split(".patch").nth(0).unwrap())
-                .clicked()
-            {
-                to_load_filename = Some(patch_filename);
-            }
+    let mut to_load_filename: Option<(String, bool)> = None;
+    ui.horizontal(|ui| {
+        for (label, link) in [("embed", false), ("link", true)] {
+            egui::ComboBox::new((node_key, label), "")
+                .selected_text(label)
+                .width(60.0)
+                .show_ui(ui, |ui| {
+                    for entry in &graph_state.library.entries {
+                        let mut v: i32 = 0;
+                        if ui.selectable_value(&mut v, 1, &entry.name).clicked() {
+                            to_load_filename = Some((entry.file.clone(), link));
+                        }
+                    }
+                });
         }
     });
 
-    if let Some(filename) = to_load_filename {
-        graph.disconnect_node(node_key);
-        let mut node = graph.get_node_mut(node_key);
-        let v: &mut dyn Any = node.as_any_mut();
+    if let Some((filename, link)) = to_load_filename {
+        {
+            let mut node = graph.get_node_mut(node_key);
+            let v: &mut dyn Any = node.as_any_mut();
 
-        let subgraph: &mut Subgraph = v.downcast_mut::<Subgraph>().unwrap();
-        subgraph.load(filename);
+            let subgraph: &mut Subgraph = v.downcast_mut::<Subgraph>().unwrap();
+            if link {
+                subgraph.link(filename);
+            } else {
+                subgraph.load(filename);
+            }
+        }
+        // Keeps the connections to ports that exist in the new patch
+        Subgraph::refresh(graph, node_key);
+    }
+    let mut node = graph.get_node_mut(node_key);
+    let subgraph: &mut Subgraph = node.as_any_mut().downcast_mut::<Subgraph>().unwrap();
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut subgraph.name).desired_width(80.0));
+        if ui.button("open").clicked() {
+            graph_state.path.push(node_key);
+        }
+        let mut oversample = subgraph.oversample;
+        egui::ComboBox::new((node_key, "oversample"), "")
+            .selected_text(format!("{}x", oversample))
+            .width(40.0)
+            .show_ui(ui, |ui| {
+                for factor in OVERSAMPLE_FACTORS {
+                    ui.selectable_value(&mut oversample, factor, format!("{}x", factor));
+                }
+            })
+            .response
+            .on_hover_text("Run the subgraph at a multiple of the rate, against aliasing");
+        if oversample != subgraph.oversample {
+            subgraph.set_oversample(oversample);
+        }
+    });
+    if let Some(link) = subgraph.link.clone() {
+        ui.horizontal(|ui| {
+            ui.label(format!("linked to {}", link));
+            if ui.button("make local copy").clicked() {
+                subgraph.make_local();
+            }
+            // Writes edits made in place back to the shared patch
+            if ui.button("save").clicked() {
+                if let Err(error) = subgraph.save_link() {
+                    println!("Couldn't save {}: {:?}", link, error);
+                }
+            }
+        });
     }
     // if ui.button("load").clicked() {
     // subgraph.load("bladesmall.patch".to_string());
     // }
 }
 
+fn draw_sampler(
+    ui: &mut egui::Ui,
+    node_key: NodeKey,
+    graph: &mut Graph,
+    graph_state: &mut GraphState,
+) {
+    let mut node = graph.get_node_mut(node_key);
+    let v: &mut dyn Any = node.as_any_mut();
+
+    let sampler: &mut Sampler = v.downcast_mut::<Sampler>().unwrap();
+    let mut to_load_filename: Option<String> = None;
+    let selected_text = if sampler.sample.path.is_empty() {
+        "Select sample".to_string()
+    } else {
+        sampler.sample.path.clone()
+    };
+    egui::ComboBox::new(node_key, "")
+        .selected_text(selected_text)
+        .show_ui(ui, |ui| {
+            for sample_filename in &graph_state.sample_files {
+                if ui
+                    .selectable_label(sampler.sample.path == *sample_filename, sample_filename)
+                    .clicked()
+                {
+                    to_load_filename = Some(sample_filename.clone());
+                }
+            }
+        });
+    if let Some(filename) = to_load_filename {
+        sampler.load(&filename);
+    }
+    let data = &sampler.sample.data;
+    if !data.is_empty() {
+        ui.label(format!(
+            "{} ch, {:.2} s @ {} Hz",
+            data.channels.len(),
+            data.len() as f32 / data.sample_rate,
+            data.sample_rate
+        ));
+    }
+}
+
+fn draw_audio_in(
+    ui: &mut egui::Ui,
+    node_key: NodeKey,
+    graph: &mut Graph,
+    graph_state: &mut GraphState,
+) {
+    let mut node = graph.get_node_mut(node_key);
+    let v: &mut dyn Any = node.as_any_mut();
+
+    let audio_in: &mut AudioIn = v.downcast_mut::<AudioIn>().unwrap();
+    let selected_text = if audio_in.file.path.is_empty() {
+        "device".to_string()
+    } else {
+        audio_in.file.path.clone()
+    };
+    egui::ComboBox::new(node_key, "")
+        .selected_text(selected_text)
+        .show_ui(ui, |ui| {
+            if ui
+                .selectable_label(audio_in.file.path.is_empty(), "device")
+                .clicked()
+            {
+                audio_in.use_device();
+            }
+            for sample_filename in &graph_state.sample_files {
+                if ui
+                    .selectable_label(audio_in.file.path == *sample_filename, sample_filename)
+                    .clicked()
+                {
+                    audio_in.load(sample_filename);
+                }
+            }
+        });
+}
+
 fn draw_scale(
     ui: &mut egui::Ui,
     node_key: NodeKey,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
 ) {
     let mut node = graph.get_node_mut(node_key);
@@ -642,69 +1878,187 @@ fn draw_scale(
     // }
 }
 
+fn draw_subgraph_port(ui: &mut egui::Ui, node_key: NodeKey, graph: &mut Graph) {
+    let mut node = graph.get_node_mut(node_key);
+    let v: &mut dyn Any = node.as_any_mut();
+
+    let name = if let Some(input) = v.downcast_mut::<SubgraphInput>() {
+        &mut input.name
+    } else if let Some(output) = v.downcast_mut::<SubgraphOutput>() {
+        &mut output.name
+    } else {
+        return;
+    };
+    ui.add(
+        egui::TextEdit::singleline(name)
+            .hint_text("name")
+            .desired_width(80.0),
+    );
+}
+
+fn draw_lfo(ui: &mut egui::Ui, node_key: NodeKey, graph: &mut Graph) {
+    let mut node = graph.get_node_mut(node_key);
+    let v: &mut dyn Any = node.as_any_mut();
+
+    let lfo: &mut Lfo = v.downcast_mut::<Lfo>().unwrap();
+    ui.horizontal(|ui| {
+        egui::ComboBox::new(ui.make_persistent_id((node_key, "shape")), "")
+            .selected_text(format!("{:?}", lfo.shape))
+            .width(80.0)
+            .show_ui(ui, |ui| {
+                for shape in LFO_SHAPES {
+                    ui.selectable_value(&mut lfo.shape, shape, format!("{:?}", shape));
+                }
+            });
+        ui.checkbox(&mut lfo.tempo_sync, "sync");
+    });
+    if lfo.tempo_sync {
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut lfo.bpm)
+                    .clamp_range(20.0..=300.0)
+                    .suffix(" bpm"),
+            );
+            egui::ComboBox::new(ui.make_persistent_id((node_key, "division")), "")
+                .selected_text(LFO_DIVISIONS[lfo.division.min(LFO_DIVISIONS.len() - 1)].0)
+                .width(60.0)
+                .show_ui(ui, |ui| {
+                    for (idx, (label, _)) in LFO_DIVISIONS.iter().enumerate() {
+                        ui.selectable_value(&mut lfo.division, idx, *label);
+                    }
+                });
+        });
+    }
+}
+
 fn render_node_custom(
     ui: &mut egui::Ui,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
     node_key: NodeKey,
 ) {
-    let type_name = graph.get_node(node_key).typetag_name().clone();
-    match type_name {
-        "Out" => {
-            draw_out(ui, node_key, graph, graph_state);
-        }
-        "Sequencer" => {
-            draw_sequencer(ui, node_key, graph, graph_state);
-        }
-        "Subgraph" => {
-            draw_subgraph(ui, node_key, graph, graph_state);
-        }
-        "Scale" => {
-            draw_scale(ui, node_key, graph, graph_state);
-        }
-        _ => {}
+    let type_name = graph.get_node(node_key).typetag_name();
+    if let Some(drawer) = registry().get(type_name).and_then(|info| info.drawer) {
+        drawer(ui, node_key, graph, graph_state);
     }
 }
 
-static NODE_TYPES: OnceLock<Mutex<Vec<Box<dyn Node>>>> = OnceLock::new();
+fn render_new_node_menu(ui: &mut egui::Ui, graph: &mut Graph, graph_state: &mut GraphState) {
+    let registry = registry();
+    for category in registry.categories() {
+        egui::CollapsingHeader::new(category)
+            .default_open(true)
+            .show(ui, |ui| {
+                for node_type in &registry.node_types {
+                    if node_type.category != category || !node_type.can_create_in_ui {
+                        continue;
+                    }
+                    let res = ui
+                        .button(node_type.name)
+                        .on_hover_text(node_type.description);
+                    if res.clicked() {
+                        graph_state
+                            .history
+                            .add_node(graph, (node_type.default_fn)(), None);
+                    }
+                }
+            });
+    }
+}
 
-fn render_new_node_menu(
-    ui: &mut egui::Ui,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
-    _graph_state: &mut GraphState,
+/// "Add node" popup, opened at the pointer with a right click or space
+pub struct NodePalette {
+    // Screen position of the popup, new nodes are placed there
+    pos: egui::Pos2,
+    query: String,
+}
+
+fn render_node_palette(
+    ctx: &egui::Context,
+    graph: &mut Graph,
+    graph_state: &mut GraphState,
+    canvas_rect: egui::Rect,
 ) {
-    let node_types = NODE_TYPES
-        .get_or_init(|| {
-            Mutex::new(vec![
-                Box::new(Add::default()),
-                Box::new(SineOsc::default()),
-                Box::new(SawOsc::default()),
-                Box::new(Scale::default()),
-                Box::new(Bias::default()),
-                Box::new(Reverb::default()),
-                Box::new(Lowpass::default()),
-                Box::new(Envelope::default()),
-                Box::new(Sequencer::default()),
-                Box::new(Subgraph::default()),
-                Box::new(PhaseGen::default()),
-            ])
+    let Some(palette) = &mut graph_state.node_palette else {
+        return;
+    };
+    let pos = palette.pos;
+    let mut chosen: Option<&NodeTypeInfo> = None;
+    let mut close = false;
+    let response = egui::Area::new("node_palette")
+        .order(egui::Order::Foreground)
+        .fixed_pos(pos)
+        .show(ctx, |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.set_width(200.0);
+                let query_response = ui.add(
+                    egui::TextEdit::singleline(&mut palette.query)
+                        .hint_text("add node")
+                        .desired_width(f32::INFINITY),
+                );
+                query_response.request_focus();
+                let registry = registry();
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        if palette.query.is_empty() {
+                            for category in registry.categories() {
+                                ui.label(egui::RichText::new(category).weak());
+                                for node_type in &registry.node_types {
+                                    if node_type.category == category
+                                        && node_type.can_create_in_ui
+                                        && ui
+                                            .selectable_label(false, node_type.name)
+                                            .on_hover_text(node_type.description)
+                                            .clicked()
+                                    {
+                                        chosen = Some(node_type);
+                                    }
+                                }
+                            }
+                        } else {
+                            let matches = registry.search(&palette.query);
+                            for (idx, node_type) in matches.iter().enumerate() {
+                                // Enter picks the best match
+                                let label = format!("{}  ({})", node_type.name, node_type.category);
+                                if ui
+                                    .selectable_label(idx == 0, label)
+                                    .on_hover_text(node_type.description)
+                                    .clicked()
+                                {
+                                    chosen = Some(node_type);
+                                }
+                            }
+                            if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                                chosen = matches.first().copied();
+                                close = true;
+                            }
+                        }
+                    });
+                if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                    close = true;
+                }
+            });
         })
-        .lock()
-        .unwrap();
-
-    ui.vertical(|ui| {
-        for node in &*node_types {
-            let res = ui.button(node.typetag_name());
-            if res.clicked() {
-                graph.add(node.copy());
-            }
-        }
-    });
+        .response;
+    if response.clicked_elsewhere() {
+        close = true;
+    }
+    if let Some(node_type) = chosen {
+        let position = screen_to_canvas(canvas_rect, graph_state, pos);
+        graph_state
+            .history
+            .add_node(graph, (node_type.default_fn)(), Some(position));
+        close = true;
+    }
+    if close {
+        graph_state.node_palette = None;
+    }
 }
 
 fn render_node(
     ui: &mut egui::Ui,
-    mut graph: &mut std::sync::MutexGuard<'_, Graph>,
+    mut graph: &mut Graph,
     graph_state: &mut GraphState,
     node_idx: &NodeKey,
     node_inputs_pos: &mut HashMap<Port, eframe::epaint::Pos2>,
@@ -731,15 +2085,115 @@ fn render_node(
             );
         });
     });
-    if r.response.interact(egui::Sense::click()).clicked() {
-        graph_state.selected_nodes = vec![*node_idx];
+    if profiling() {
+        draw_node_heat(ui, r.response.rect, &graph.profiler, *node_idx);
+    }
+    let response = r.response.interact(egui::Sense::click_and_drag());
+    if response.double_clicked() && graph.get_node(*node_idx).as_any().is::<Subgraph>() {
+        graph_state.path.push(*node_idx);
+    }
+    if response.clicked() {
+        if ui.input(|i| i.modifiers.shift) {
+            if let Some(idx) = graph_state
+                .selected_nodes
+                .iter()
+                .position(|node_key| node_key == node_idx)
+            {
+                graph_state.selected_nodes.remove(idx);
+            } else {
+                graph_state.selected_nodes.push(*node_idx);
+            }
+        } else {
+            graph_state.selected_nodes = vec![*node_idx];
+        }
+    }
+    if response.dragged_by(egui::PointerButton::Primary) {
+        let delta = response.drag_delta() / graph_state.zoom;
+        // Dragging a selected node moves the whole selection
+        let moved = if graph_state.selected_nodes.contains(node_idx) {
+            graph_state.selected_nodes.clone()
+        } else {
+            vec![*node_idx]
+        };
+        for node_key in moved {
+            if let Some(pos) = graph.positions.get_mut(&node_key) {
+                pos[0] += delta.x;
+                pos[1] += delta.y;
+            }
+        }
     }
     node_rects.insert(*node_idx, r.response.rect);
 }
 
+// Blocks a cable flashes for after a gate onset
+const GATE_FLASH_BLOCKS: u32 = 4;
+
+// Cables get brighter with the RMS level of their signal and flash white when
+// a gate opens
+fn cable_color(activity: Option<&PortActivity>) -> egui::Color32 {
+    let silent = egui::Color32::from_rgba_premultiplied(96, 0, 0, 96);
+    let Some(activity) = activity else {
+        return silent;
+    };
+    let loud = egui::Color32::from_rgba_premultiplied(255, 40, 40, 255);
+    let mut color = lerp_color(silent, loud, PortActivity::normalized(activity.rms));
+    if activity.gate_age < GATE_FLASH_BLOCKS {
+        let flash = 1.0 - activity.gate_age as f32 / GATE_FLASH_BLOCKS as f32;
+        color = lerp_color(color, egui::Color32::WHITE, flash);
+    }
+    color
+}
+
+fn lerp_color(a: egui::Color32, b: egui::Color32, t: f32) -> egui::Color32 {
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    egui::Color32::from_rgba_premultiplied(
+        lerp(a.r(), b.r()),
+        lerp(a.g(), b.g()),
+        lerp(a.b(), b.b()),
+        lerp(a.a(), b.a()),
+    )
+}
+
+// A ring around an output port lit by the peak level, and a sparkline of the
+// recent peaks below it
+fn draw_port_activity(ui: &mut egui::Ui, rect: egui::Rect, activity: &PortActivity) {
+    let painter = ui.painter();
+    let peak = PortActivity::normalized(activity.peak);
+    let ring_color = if activity.gate_age < GATE_FLASH_BLOCKS {
+        egui::Color32::WHITE
+    } else {
+        egui::Color32::GREEN.gamma_multiply(peak)
+    };
+    painter.circle_stroke(
+        rect.center(),
+        rect.width() / 2.0,
+        egui::Stroke::new(1.5, ring_color),
+    );
+
+    let spark_rect = egui::Rect::from_min_size(
+        rect.left_bottom(),
+        egui::vec2(rect.width(), rect.height() * 0.3),
+    );
+    let n = activity.history.len().max(2) - 1;
+    let points: Vec<egui::Pos2> = activity
+        .history
+        .iter()
+        .enumerate()
+        .map(|(idx, level)| {
+            let x = spark_rect.left() + spark_rect.width() * idx as f32 / n as f32;
+            let y = spark_rect.bottom() - spark_rect.height() * PortActivity::normalized(*level);
+            egui::pos2(x, y)
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        points,
+        egui::Stroke::new(1.0, egui::Color32::GREEN.gamma_multiply(0.6)),
+    ));
+}
+
 fn render_node_connections(
     ui: &mut egui::Ui,
-    mut graph: &mut std::sync::MutexGuard<'_, Graph>,
+    mut graph: &mut Graph,
     node_idx: &NodeKey,
     graph_state: &mut GraphState,
     node_inputs_pos: &mut HashMap<Port, eframe::epaint::Pos2>,
@@ -752,7 +2206,7 @@ fn render_node_connections(
             let color = if graph_state.selected_connection == Some(edge.clone()) {
                 egui::Color32::from_rgba_premultiplied(0, 255, 0, 128)
             } else {
-                egui::Color32::from_rgba_premultiplied(255, 0, 0, 128)
+                cable_color(graph.activity.get(&edge.from))
             };
             draw_bezier(
                 ui,
@@ -765,7 +2219,7 @@ fn render_node_connections(
 }
 fn render_core_node(
     ui: &mut egui::Ui,
-    mut graph: &mut std::sync::MutexGuard<'_, Graph>,
+    mut graph: &mut Graph,
     node_idx: &NodeKey,
     graph_state: &mut GraphState,
     node_inputs_pos: &mut HashMap<Port, eframe::epaint::Pos2>,
@@ -815,7 +2269,7 @@ fn render_output_port(
     node_outputs_pos: &mut HashMap<Port, eframe::epaint::Pos2>,
     node_idx: &NodeKey,
     output_idx: usize,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
 ) {
     // let rect = draw_circle(ui, egui::Color32::GOLD);
@@ -827,8 +2281,13 @@ fn render_output_port(
         kind: PortKind::Output,
     };
 
+    let res = ui.allocate_response(
+        egui::Vec2::splat(20.0 * graph_state.zoom),
+        egui::Sense::click_and_drag(),
+    );
     let res = ui.add(
         Knob::new(&mut v, ui.auto_id_with(node_idx))
+            .response(res)
             .with_type(KnobType::Output)
             .color(egui::Color32::GREEN)
             .speed(10.0 / 300.0)
@@ -850,9 +2309,12 @@ fn render_output_port(
     //         _ => {}
     //     }
     if res.dragged_by(egui::PointerButton::Primary) {
-        graph_state.drag_from = Some(port);
+        graph_state.drag_from = Some(port.clone());
     }
     // res.drag_released
+    if let Some(activity) = graph.activity.get(&port) {
+        draw_port_activity(ui, res.rect, activity);
+    }
     node_outputs_pos.insert(
         Port {
             node: *node_idx,
@@ -871,7 +2333,7 @@ fn render_output_port(
 }
 
 fn render_input_port(
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
+    graph: &mut Graph,
     graph_state: &mut GraphState,
     node_idx: &NodeKey,
     input_idx: usize,
@@ -889,13 +2351,18 @@ fn render_input_port(
     // } else {
     // false
     // };
-    let mut val = graph.get_node_mut(*node_idx).get_input(input_idx);
-    let res = ui.allocate_response([20.0, 20.0].into(), egui::Sense::click_and_drag());
+    let old_val = graph.get_node_mut(*node_idx).get_input(input_idx);
+    let range = graph.get_node(*node_idx).input_range(input_idx);
+    let mut val = old_val;
+    let res = ui.allocate_response(
+        egui::Vec2::splat(20.0 * graph_state.zoom),
+        egui::Sense::click_and_drag(),
+    );
     Knob::new(&mut val, ui.auto_id_with(node_idx))
         .with_type(KnobType::Input)
-        .speed(10.0 / 300.0)
+        .speed((range.end() - range.start()) / 60.0)
         .color(egui::Color32::RED)
-        .clamp_range(0.0..=2.0) // .with_id(_node_id),
+        .clamp_range(range.clone()) // .with_id(_node_id),
         .selected(
             res.rect
                 .contains(ui.ctx().pointer_latest_pos().unwrap_or_default())
@@ -905,7 +2372,15 @@ fn render_input_port(
         .response(res.clone())
         .ui(ui);
 
-    graph.get_node_mut(*node_idx).set(input_idx, val);
+    // The knob clamps values it didn't change itself, those are not edits
+    if val != old_val.clamp(*range.start(), *range.end()) {
+        let merge = res.dragged() && !res.drag_started();
+        graph_state
+            .history
+            .set_input(graph, *node_idx, input_idx, val, merge);
+    } else {
+        graph.get_node_mut(*node_idx).set(input_idx, val);
+    }
     // if res.clicked() {
     //     match graph_state.drag_from.clone() {
     //         Some(
@@ -943,7 +2418,7 @@ fn render_input_port(
     }
     if res.dragged_by(egui::PointerButton::Primary) {
         if let Some(edge) = graph.get_edge(port) {
-            graph.disconnect_edge(edge.clone());
+            graph_state.history.disconnect(graph, edge.clone());
             graph_state.drag_from = Some(edge.from);
         }
         // if graph_state.drag_from.is_none()
@@ -965,12 +2440,7 @@ fn render_input_port(
     );
 }
 
-fn maybe_create_connection(
-    graph_state: &mut GraphState,
-    graph: &mut std::sync::MutexGuard<'_, Graph>,
-    from: Port,
-    to: Port,
-) {
+fn maybe_create_connection(graph_state: &mut GraphState, graph: &mut Graph, from: Port, to: Port) {
     // let from_depth = graph.node_depths()[&from.node];
     // let to_depth = graph.node_depths()[&to.node];
     // let input_depth = if from.kind == PortKind::Input {
@@ -991,7 +2461,7 @@ fn maybe_create_connection(
         println!("Must connect forward in graph!");
     } */
     else {
-        graph.connect(from.clone(), to.clone());
+        graph_state.history.connect(graph, from.clone(), to.clone());
         graph_state.drag_from = None;
         graph_state.selected_connection = Some(Edge {
             from: from.clone(),
@@ -1005,10 +2475,7 @@ fn main() {
     // Log to stdout (if you run with `RUST_LOG=debug`).
     // tracing_subscriber::fmt::init();
 
-    let sdl_context = sdl2::init().unwrap();
-    let mut audio_subsystem = sdl_context.audio().unwrap();
-
-    let (shared_graph, _device) = create_graph(&mut audio_subsystem);
+    let (shared_graph, output) = create_graph();
 
     // let file_contents = std::fs::read_to_string("synth3.patch").unwrap();
     // let graph: Graph = serde_json::from_str(&file_contents).unwrap();
@@ -1022,7 +2489,7 @@ fn main() {
     eframe::run_native(
         "synthotron",
         options,
-        Box::new(move |_cc| Box::new(SynthGui2::new(shared_graph))),
+        Box::new(move |_cc| Box::new(SynthGui2::new(shared_graph, output))),
     )
     .unwrap();
 }